@@ -1,7 +1,46 @@
-pub(crate) fn build_aggregate_prompt(user_prompt: &str, chunk_outputs: &[String]) -> String {
-    format!(
-        "User request:\n{}\n\nCombine the following per-chunk outputs into a single coherent final answer:\n\n{}",
-        user_prompt,
-        chunk_outputs.join("\n\n")
-    )
+pub(crate) const DEFAULT_AGGREGATE_PROMPT_TEMPLATE: &str = "User request:\n{{user_prompt}}\n\nCombine the following per-chunk outputs into a single coherent final answer:\n\n{{chunk_outputs}}";
+
+pub(crate) fn render_aggregate_prompt(
+    template: &str,
+    user_prompt: &str,
+    chunk_outputs: &[String],
+) -> String {
+    template
+        .replace("{{user_prompt}}", user_prompt)
+        .replace("{{chunk_outputs}}", &chunk_outputs.join("\n\n"))
+}
+
+/// Split `chunk_outputs` into runs whose rendered aggregate prompt fits in
+/// `max_chars`. A batch holds at least two outputs even when they overflow
+/// the budget, so every round of batching shrinks the list; only the last
+/// batch can be a single output, which needs no combining.
+pub(crate) fn batch_outputs(
+    template: &str,
+    user_prompt: &str,
+    chunk_outputs: Vec<String>,
+    max_chars: usize,
+) -> Vec<Vec<String>> {
+    let overhead = render_aggregate_prompt(template, user_prompt, &[])
+        .chars()
+        .count();
+    let mut batches: Vec<Vec<String>> = Vec::new();
+    let mut batch: Vec<String> = Vec::new();
+    let mut batch_chars = overhead;
+    for output in chunk_outputs {
+        let output_chars = output.chars().count();
+        // Outputs are joined by a blank line
+        if batch.len() >= 2 && batch_chars + 2 + output_chars > max_chars {
+            batches.push(std::mem::take(&mut batch));
+            batch_chars = overhead;
+        }
+        if !batch.is_empty() {
+            batch_chars += 2;
+        }
+        batch_chars += output_chars;
+        batch.push(output);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
 }
@@ -0,0 +1,426 @@
+use crate::{AppConfig, TokenizerKind};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// Bytes at the start of stdin that identify it when a job is resumed.
+const STDIN_FINGERPRINT_BYTES: usize = 64 * 1024;
+
+/// What a chunked job reads, recorded so a resumed job can check that its
+/// input has not changed.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum InputIdentity {
+    Files(Vec<FileIdentity>),
+    Stdin,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub(crate) struct FileIdentity {
+    path: PathBuf,
+    bytes: u64,
+    modified_ms: u64,
+}
+
+impl InputIdentity {
+    pub(crate) fn of(files: &[PathBuf]) -> Result<Self> {
+        if files.is_empty() {
+            return Ok(InputIdentity::Stdin);
+        }
+        files
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path)
+                    .with_context(|| format!("Failed to read file metadata: {}", path.display()))?;
+                let modified_ms = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+                Ok(FileIdentity {
+                    path: fs::canonicalize(path).unwrap_or_else(|_| path.clone()),
+                    bytes: metadata.len(),
+                    modified_ms,
+                })
+            })
+            .collect::<Result<_>>()
+            .map(InputIdentity::Files)
+    }
+}
+
+/// One line of a checkpoint file.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum CheckpointRecord {
+    Start {
+        version: u32,
+        settings_hash: String,
+        input: InputIdentity,
+    },
+    /// A chunk whose answer has been received.
+    Chunk {
+        chunk_index: usize,
+        /// Index of the input file being read (0 for stdin).
+        input_index: usize,
+        /// Byte offset in that input where the next chunk starts.
+        offset: u64,
+        rolling_summary: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aggregate_input: Option<String>,
+        /// SHA-256 of the start of stdin, up to the offset.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stdin_sha256: Option<String>,
+    },
+    /// Every chunk is done; only aggregation is left.
+    ChunksDone,
+}
+
+/// Where a resumed job picks up.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ResumePoint {
+    pub(crate) input_index: usize,
+    pub(crate) offset: u64,
+    pub(crate) chunk_index: usize,
+    pub(crate) rolling_summary: String,
+    pub(crate) aggregate_inputs: Vec<String>,
+    pub(crate) chunks_done: bool,
+    stdin_sha256: Option<String>,
+}
+
+/// Progress of a chunked job, appended to a JSON Lines file after every
+/// chunk so that `--resume` can skip the chunks already answered.
+///
+/// Writing checkpoints never fails a job: errors are logged and the job
+/// continues without them.
+pub(crate) struct Checkpoint {
+    path: PathBuf,
+    file: Option<File>,
+    input_index: usize,
+    /// The first bytes read from stdin, for its fingerprint.
+    stdin_head: Option<Vec<u8>>,
+}
+
+impl Checkpoint {
+    /// Start a new checkpoint in `path`, replacing any earlier one.
+    pub(crate) fn create(path: PathBuf, settings_hash: &str, input: &InputIdentity) -> Self {
+        let mut checkpoint = Self {
+            path,
+            file: None,
+            input_index: 0,
+            stdin_head: stdin_head(input),
+        };
+        match checkpoint.open(false) {
+            Ok(file) => checkpoint.file = Some(file),
+            Err(e) => warn!("Not writing checkpoints: {e:#}"),
+        }
+        checkpoint.append(&CheckpointRecord::Start {
+            version: CHECKPOINT_VERSION,
+            settings_hash: settings_hash.to_string(),
+            input: input.clone(),
+        });
+        checkpoint
+    }
+
+    /// Continue the job recorded in `path`, which must have been started
+    /// with the same settings and input.
+    pub(crate) fn resume(
+        path: &Path,
+        settings_hash: &str,
+        input: &InputIdentity,
+    ) -> Result<(Self, ResumePoint)> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open checkpoint: {}", path.display()))?;
+        let resume_point = read_checkpoint(BufReader::new(file), settings_hash, input)
+            .with_context(|| format!("Cannot resume from {}", path.display()))?;
+        info!(
+            "Resuming after chunk {} from {}",
+            resume_point.chunk_index,
+            path.display()
+        );
+
+        let mut checkpoint = Self {
+            path: path.to_path_buf(),
+            file: None,
+            input_index: resume_point.input_index,
+            stdin_head: stdin_head(input),
+        };
+        match checkpoint.open(true) {
+            Ok(file) => checkpoint.file = Some(file),
+            Err(e) => warn!("Not writing checkpoints: {e:#}"),
+        }
+        Ok((checkpoint, resume_point))
+    }
+
+    fn open(&self, append: bool) -> Result<File> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).with_context(|| {
+                format!("Failed to create checkpoint directory: {}", dir.display())
+            })?;
+        }
+        let mut options = OpenOptions::new();
+        options
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append);
+        // Checkpoints hold parts of the input and answers
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&self.path)
+            .with_context(|| format!("Failed to write checkpoint: {}", self.path.display()))
+    }
+
+    /// The checkpoint file, if checkpoints are being written.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|_| self.path.as_path())
+    }
+
+    /// Note that the following chunks come from input number `input_index`.
+    pub(crate) fn start_input(&mut self, input_index: usize) {
+        self.input_index = input_index;
+    }
+
+    /// Note text read from the input, to fingerprint stdin.
+    pub(crate) fn record_read(&mut self, text: &str) {
+        self.extend_stdin_head(text.as_bytes());
+    }
+
+    fn extend_stdin_head(&mut self, bytes: &[u8]) {
+        if let Some(head) = self.stdin_head.as_mut() {
+            let wanted = STDIN_FINGERPRINT_BYTES.saturating_sub(head.len());
+            head.extend_from_slice(&bytes[..wanted.min(bytes.len())]);
+        }
+    }
+
+    /// Read and discard the `offset` bytes of stdin that the resumed job
+    /// already processed, checking that they start as they did before.
+    pub(crate) fn skip_stdin<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        resume_point: &ResumePoint,
+    ) -> Result<()> {
+        let mut remaining = resume_point.offset;
+        while remaining > 0 {
+            let buffer = reader.fill_buf().context("Failed to read stdin")?;
+            if buffer.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "stdin ended before the {} bytes processed by the checkpointed job",
+                    resume_point.offset
+                ));
+            }
+            let take = buffer.len().min(remaining as usize);
+            let skipped = buffer[..take].to_vec();
+            self.extend_stdin_head(&skipped);
+            reader.consume(take);
+            remaining -= take as u64;
+        }
+        if resume_point.stdin_sha256.is_some()
+            && self.stdin_sha256(resume_point.offset) != resume_point.stdin_sha256
+        {
+            return Err(anyhow::anyhow!(
+                "stdin differs from the input of the checkpointed job"
+            ));
+        }
+        Ok(())
+    }
+
+    fn stdin_sha256(&self, offset: u64) -> Option<String> {
+        let head = self.stdin_head.as_ref()?;
+        let end = head.len().min(offset as usize);
+        Some(hex_digest(&head[..end]))
+    }
+
+    /// Record that chunk `chunk_index` was answered and the next chunk
+    /// starts at `offset` in the current input.
+    pub(crate) fn save_chunk(
+        &mut self,
+        chunk_index: usize,
+        offset: u64,
+        rolling_summary: &str,
+        aggregate_input: Option<&str>,
+    ) {
+        self.append(&CheckpointRecord::Chunk {
+            chunk_index,
+            input_index: self.input_index,
+            offset,
+            rolling_summary: rolling_summary.to_string(),
+            aggregate_input: aggregate_input.map(str::to_string),
+            stdin_sha256: self.stdin_sha256(offset),
+        });
+    }
+
+    pub(crate) fn save_chunks_done(&mut self) {
+        self.append(&CheckpointRecord::ChunksDone);
+    }
+
+    fn append(&mut self, record: &CheckpointRecord) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let result = serde_json::to_string(record)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(file.write_all(format!("{line}\n").as_bytes())?));
+        if let Err(e) = result {
+            warn!(
+                "Failed to write checkpoint {}, no longer writing checkpoints: {e:#}",
+                self.path.display()
+            );
+            self.file = None;
+        }
+    }
+
+    /// Delete the checkpoint of a job that has finished.
+    pub(crate) fn remove(mut self) {
+        if self.file.take().is_some() {
+            debug!("Removing checkpoint {}", self.path.display());
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+fn stdin_head(input: &InputIdentity) -> Option<Vec<u8>> {
+    (*input == InputIdentity::Stdin).then(Vec::new)
+}
+
+// Replay the records of a checkpoint file into the point to resume from
+fn read_checkpoint(
+    reader: impl BufRead,
+    settings_hash: &str,
+    input: &InputIdentity,
+) -> Result<ResumePoint> {
+    let mut lines = reader.lines();
+    let first_line = lines
+        .next()
+        .transpose()?
+        .ok_or_else(|| anyhow::anyhow!("the checkpoint is empty"))?;
+    match serde_json::from_str(&first_line).context("not a checkpoint file")? {
+        CheckpointRecord::Start {
+            version,
+            settings_hash: saved_hash,
+            input: saved_input,
+        } => {
+            if version != CHECKPOINT_VERSION {
+                return Err(anyhow::anyhow!("unsupported checkpoint version {version}"));
+            }
+            if saved_hash != settings_hash {
+                return Err(anyhow::anyhow!(
+                    "the prompt, model or chunk settings changed since the checkpoint was written"
+                ));
+            }
+            if saved_input != *input {
+                return Err(anyhow::anyhow!(
+                    "the input files differ from those of the checkpointed job"
+                ));
+            }
+        }
+        _ => return Err(anyhow::anyhow!("not a checkpoint file")),
+    }
+
+    let mut resume_point = ResumePoint::default();
+    let mut lines = lines.peekable();
+    while let Some(line) = lines.next() {
+        let line = line?;
+        let record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            // The job may have stopped while writing the last line
+            Err(e) if lines.peek().is_none() => {
+                debug!("Ignoring incomplete last checkpoint record: {e}");
+                break;
+            }
+            Err(e) => return Err(e).context("corrupt checkpoint record"),
+        };
+        match record {
+            CheckpointRecord::Chunk {
+                chunk_index,
+                input_index,
+                offset,
+                rolling_summary,
+                aggregate_input,
+                stdin_sha256,
+            } => {
+                resume_point.chunk_index = chunk_index;
+                resume_point.input_index = input_index;
+                resume_point.offset = offset;
+                resume_point.rolling_summary = rolling_summary;
+                resume_point.aggregate_inputs.extend(aggregate_input);
+                resume_point.stdin_sha256 = stdin_sha256;
+            }
+            CheckpointRecord::ChunksDone => resume_point.chunks_done = true,
+            CheckpointRecord::Start { .. } => {
+                return Err(anyhow::anyhow!("unexpected start record"));
+            }
+        }
+    }
+    Ok(resume_point)
+}
+
+/// Hash of everything that decides how the input is split and what each
+/// chunk is asked. Aggregation settings are left out so they can change
+/// before a job is resumed.
+pub(crate) fn settings_hash(
+    config: &AppConfig,
+    user_prompt: &str,
+    chunk_template: &str,
+) -> Result<String> {
+    let tokenizer = config.chunk_size_tokens.map(|_| config.tokenizer);
+    let settings = serde_json::json!({
+        "model": config.model,
+        "temperature": config.temperature,
+        "user_prompt": user_prompt,
+        "chunk_template": chunk_template,
+        "chunk_size_chars": config.chunk_size_chars,
+        "chunk_overlap_chars": config.chunk_overlap_chars,
+        "chunk_size_tokens": config.chunk_size_tokens,
+        "chunk_overlap_tokens": config.chunk_overlap_tokens,
+        "tokenizer": tokenizer.unwrap_or(TokenizerKind::Heuristic),
+        "chunk_boundary": config.chunk_boundary,
+        "chunk_boundary_tolerance": config.chunk_boundary_tolerance,
+        "rolling_summary_chars": config.rolling_summary_chars,
+        "summarize_chunk_outputs": config.summarize_chunk_outputs,
+        "parallel_chunks": config.parallel_chunks,
+        "aggregate_chunks": config.aggregate_chunks,
+    });
+    Ok(hex_digest(&serde_json::to_vec(&settings)?))
+}
+
+fn hex_digest(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Checkpoints live next to the response cache, named after the job so a
+/// rerun of the same job writes the same file. Jobs reading stdin cannot be
+/// told apart that way, so their names also carry the process ID and start
+/// time to keep concurrent jobs from replacing each other's checkpoint.
+pub(crate) fn default_path(settings_hash: &str, input: &InputIdentity) -> Result<PathBuf> {
+    let dir = match dirs::cache_dir() {
+        Some(cache_dir) => cache_dir.join("ai-cli").join("checkpoints"),
+        None => crate::get_config_dir()?.join("checkpoints"),
+    };
+    let job = hex_digest(format!("{settings_hash}{}", serde_json::to_string(input)?).as_bytes());
+    let name = match input {
+        InputIdentity::Files(_) => job[..16].to_string(),
+        InputIdentity::Stdin => {
+            let started_ms = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since_epoch| since_epoch.as_millis());
+            format!("{}-{}-{started_ms}", &job[..16], std::process::id())
+        }
+    };
+    Ok(dir.join(format!("{name}.jsonl")))
+}
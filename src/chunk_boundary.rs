@@ -0,0 +1,173 @@
+use crate::source_language::SourceLanguage;
+use serde::{Deserialize, Serialize};
+
+/// Where a chunk may end when it has to be cut.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, clap::ValueEnum, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum ChunkBoundary {
+    /// `code-block` for source files in a known language, `exact` otherwise.
+    #[default]
+    Auto,
+    /// Cut at exactly the chunk size.
+    Exact,
+    /// End chunks at line breaks.
+    Line,
+    /// End chunks at blank lines between paragraphs.
+    Paragraph,
+    /// Start chunks at Markdown headings.
+    MarkdownHeading,
+    /// End chunks after a full stop, question or exclamation mark.
+    Sentence,
+    /// Start chunks at top-level definitions in source files, or at
+    /// unindented code after a blank line, and never inside ``` fences.
+    CodeBlock,
+}
+
+/// A kind of break, from the most to the least structural.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Break {
+    Definition(SourceLanguage),
+    Heading,
+    CodeItem,
+    Paragraph,
+    Sentence,
+    Line,
+    Word,
+}
+
+impl ChunkBoundary {
+    // Breaks to look for, most preferred first. Weaker breaks are fallbacks
+    // for windows that contain none of the preferred kind.
+    fn preferences(self, language: Option<SourceLanguage>) -> Vec<Break> {
+        match (self, language) {
+            (ChunkBoundary::Auto, None) | (ChunkBoundary::Exact, _) => vec![],
+            (ChunkBoundary::Auto | ChunkBoundary::CodeBlock, Some(language)) => vec![
+                Break::Definition(language),
+                Break::CodeItem,
+                Break::Paragraph,
+                Break::Line,
+                Break::Word,
+            ],
+            (ChunkBoundary::CodeBlock, None) => {
+                vec![Break::CodeItem, Break::Paragraph, Break::Line, Break::Word]
+            }
+            (ChunkBoundary::Line, _) => vec![Break::Line, Break::Word],
+            (ChunkBoundary::Paragraph, _) => vec![Break::Paragraph, Break::Line, Break::Word],
+            (ChunkBoundary::MarkdownHeading, _) => {
+                vec![Break::Heading, Break::Paragraph, Break::Line, Break::Word]
+            }
+            (ChunkBoundary::Sentence, _) => vec![Break::Sentence, Break::Line, Break::Word],
+        }
+    }
+}
+
+/// Find where to cut `text` so that the chunk ends between `window_start`
+/// and `end` (byte offsets), preferring the strongest break `boundary`
+/// allows and, among equally strong ones, the latest. `language` is the
+/// language of `text` when it is source code. `is_candidate` limits the cut
+/// to offsets the caller can use, such as token starts.
+pub(crate) fn find_break(
+    boundary: ChunkBoundary,
+    language: Option<SourceLanguage>,
+    text: &str,
+    window_start: usize,
+    end: usize,
+    is_candidate: impl Fn(usize) -> bool,
+) -> Option<usize> {
+    let preferences = boundary.preferences(language);
+    if preferences.is_empty() || window_start > end {
+        return None;
+    }
+
+    // Whether each line start in the window is inside a ``` fence depends on
+    // the fence lines before it
+    let mut in_fence = std::iter::once(0)
+        .chain(
+            text[..window_start]
+                .match_indices('\n')
+                .map(|(index, _)| index + 1),
+        )
+        .filter(|&line_start| line_start < window_start && is_fence_line(&text[line_start..]))
+        .count()
+        % 2
+        == 1;
+
+    let mut best: Vec<Option<usize>> = vec![None; preferences.len()];
+    let positions = text[window_start..end]
+        .char_indices()
+        .map(|(offset, _)| window_start + offset)
+        .chain(std::iter::once(end));
+    for pos in positions {
+        let at_line_start = pos == 0 || text.as_bytes()[pos - 1] == b'\n';
+        if pos > 0 && is_candidate(pos) {
+            for (level, kind) in preferences.iter().enumerate() {
+                if is_break(*kind, text, pos, at_line_start, in_fence) {
+                    best[level] = Some(pos);
+                }
+            }
+        }
+        if at_line_start && is_fence_line(&text[pos..]) {
+            in_fence = !in_fence;
+        }
+    }
+    best.into_iter().flatten().next()
+}
+
+fn is_break(kind: Break, text: &str, pos: usize, at_line_start: bool, in_fence: bool) -> bool {
+    let before = &text[..pos];
+    let after = &text[pos..];
+    match kind {
+        Break::Definition(language) => {
+            at_line_start
+                && language.starts_definition(
+                    after.lines().next().unwrap_or_default(),
+                    before[..pos - 1].rsplit('\n').next().unwrap_or_default(),
+                )
+        }
+        Break::Line => at_line_start,
+        Break::Paragraph => at_line_start && !in_fence && follows_blank_line(before),
+        Break::Heading => at_line_start && !in_fence && is_heading(after),
+        Break::CodeItem => {
+            at_line_start
+                && !in_fence
+                && follows_blank_line(before)
+                && after
+                    .chars()
+                    .next()
+                    .is_some_and(|c| !c.is_whitespace() && !matches!(c, '}' | ')' | ']'))
+        }
+        Break::Sentence => {
+            (at_line_start && follows_blank_line(before)) || ends_sentence(before, after)
+        }
+        Break::Word => {
+            before.ends_with(char::is_whitespace) || after.starts_with(char::is_whitespace)
+        }
+    }
+}
+
+// `before` ends with a newline that closes a line of only whitespace
+fn follows_blank_line(before: &str) -> bool {
+    before
+        .strip_suffix('\n')
+        .map(|rest| rest.trim_end_matches([' ', '\t', '\r']))
+        .is_some_and(|rest| rest.ends_with('\n'))
+}
+
+fn is_heading(line: &str) -> bool {
+    let hashes = line.chars().take_while(|&c| c == '#').count();
+    (1..=6).contains(&hashes) && line[hashes..].starts_with([' ', '\t'])
+}
+
+fn is_fence_line(line: &str) -> bool {
+    let line = line.trim_start_matches(' ');
+    line.starts_with("```") || line.starts_with("~~~")
+}
+
+fn ends_sentence(before: &str, after: &str) -> bool {
+    let before = before.trim_end_matches(['"', '\'', ')', ']', '”', '’']);
+    if before.ends_with(['。', '！', '？']) {
+        return true;
+    }
+    // Requiring a space after the mark skips decimals such as 3.5
+    before.ends_with(['.', '!', '?', '…']) && after.starts_with(char::is_whitespace)
+}
@@ -1,22 +1,50 @@
-use crate::aggregation::build_aggregate_prompt;
-use crate::input_stream::InputChunker;
+use crate::aggregation::{
+    batch_outputs, render_aggregate_prompt, DEFAULT_AGGREGATE_PROMPT_TEMPLATE,
+};
+use crate::checkpoint::{self, Checkpoint, InputIdentity, ResumePoint};
+use crate::input_stream::{InputChunker, TextReader};
+use crate::rate_limiter::{estimate_tokens, RateLimiter};
+use crate::recording::ExchangeLog;
+use crate::response_cache::ResponseCache;
+use crate::source_language::SourceLanguage;
+use crate::spinner::{Progress, Spinner};
+use crate::tokenizer;
 use crate::{
-    stream_response, stream_response_collect, AppConfig, Args, ChatCompletionRequest, ChatMessage,
-    InputMode,
+    stream_response_collect, AppConfig, Args, ChatCompletionRequest, ChatMessage, InputMode,
 };
 use anyhow::{Context, Result};
+use futures::{
+    stream::{FuturesOrdered, StreamExt},
+    FutureExt,
+};
 use log::debug;
 use reqwest::Client;
 use std::{
     fs,
     fs::File,
-    io::{self, BufRead, BufReader, IsTerminal},
+    future::Future,
+    io::{self, BufRead, BufReader, IsTerminal, Seek, SeekFrom, Write},
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
     time::Duration,
 };
+use tokio::task::JoinHandle;
+
+/// Tokens kept free for where the chunk text joins the template, since the
+/// tokenizer may split the combined prompt slightly differently.
+const TEMPLATE_JOIN_MARGIN_TOKENS: usize = 2;
+
+/// Prompt for `summarize_chunk_outputs`, which condenses a long chunk answer
+/// before it is passed on.
+const SUMMARY_PROMPT_TEMPLATE: &str = "Summarize the following answer in at most {{max_chars}} characters. Keep the facts, names, numbers and open questions needed to continue this task, and reply with the summary only.\n\nTask:\n{{user_prompt}}\n\nAnswer:\n{{chunk_output}}";
 
 pub(crate) const DEFAULT_CHUNK_PROMPT_TEMPLATE: &str = "You are processing part {{chunk_index}}.\nFollow the user request:\n{{user_prompt}}\n\nPrevious summary:\n{{rolling_summary}}\n\nCurrent chunk:\n{{chunk_text}}";
 
 pub(crate) fn should_use_chunked_mode(args: &Args, config: &AppConfig) -> Result<bool> {
+    // Only chunked runs write the checkpoints --resume reads
+    if args.resume.is_some() {
+        return Ok(true);
+    }
     match config.input_mode {
         InputMode::Off => Ok(false),
         InputMode::Chunked => Ok(true),
@@ -40,35 +68,93 @@ pub(crate) fn should_use_chunked_mode(args: &Args, config: &AppConfig) -> Result
     }
 }
 
+/// Where a chunk was read from, for the chunk prompt template.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ChunkPosition<'a> {
+    /// Number of the chunk across all inputs, from 1.
+    pub(crate) index: usize,
+    /// Estimated number of chunks, when the input length is known.
+    pub(crate) count_estimate: Option<usize>,
+    /// Path of the file the chunk was read from, or `<stdin>`.
+    pub(crate) file_name: &'a str,
+    /// Offset of the chunk's first byte in that file.
+    pub(crate) byte_offset: u64,
+}
+
 pub(crate) fn render_chunk_prompt(
     template: &str,
     user_prompt: &str,
     rolling_summary: &str,
     chunk_text: &str,
-    chunk_index: usize,
+    position: &ChunkPosition,
 ) -> String {
+    let count_estimate = position
+        .count_estimate
+        .map_or_else(|| "unknown".to_string(), |count| count.to_string());
     template
-        .replace("{{chunk_index}}", &chunk_index.to_string())
+        .replace("{{chunk_index}}", &position.index.to_string())
+        .replace("{{chunk_count_estimate}}", &count_estimate)
+        .replace("{{file_name}}", position.file_name)
+        .replace("{{byte_offset}}", &position.byte_offset.to_string())
         .replace("{{user_prompt}}", user_prompt)
         .replace("{{rolling_summary}}", rolling_summary)
         .replace("{{chunk_text}}", chunk_text)
 }
 
-fn truncate_chars(input: &str, max_chars: usize) -> String {
+pub(crate) fn truncate_chars(input: &str, max_chars: usize) -> String {
     input.chars().take(max_chars).collect()
 }
 
+// Length of stdin when it is redirected from a file rather than a pipe
+#[cfg(unix)]
+fn stdin_len() -> Option<u64> {
+    use std::os::fd::AsFd;
+    let stdin = File::from(io::stdin().as_fd().try_clone_to_owned().ok()?);
+    let metadata = stdin.metadata().ok()?;
+    metadata.is_file().then_some(metadata.len())
+}
+
+#[cfg(not(unix))]
+fn stdin_len() -> Option<u64> {
+    None
+}
+
+/// Process the input in chunks, printing the answers as they arrive. With
+/// `keep_answer`, the printed answer is also returned: the combined answer
+/// when the chunk outputs were aggregated, otherwise all of them in order.
+/// Every request waits for `rate_limiter`, which callers running several
+/// inputs share between them.
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn process_large_input(
     args: &Args,
     config: &AppConfig,
     client: &Client,
     show_progress: bool,
-) -> Result<()> {
-    log::info!(
-        "Processing large input in chunked mode with chunk_size={} and overlap={}",
-        config.chunk_size_chars,
-        config.chunk_overlap_chars
-    );
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
+    rate_limiter: &mut RateLimiter,
+    keep_answer: bool,
+) -> Result<Option<String>> {
+    match config.chunk_size_tokens {
+        Some(chunk_size_tokens) => log::info!(
+            "Processing large input in chunked mode with chunk_size={} tokens ({:?} tokenizer) and overlap={} tokens",
+            chunk_size_tokens,
+            config.tokenizer,
+            config.chunk_overlap_tokens.unwrap_or(0)
+        ),
+        None => log::info!(
+            "Processing large input in chunked mode with chunk_size={} and overlap={}",
+            config.chunk_size_chars,
+            config.chunk_overlap_chars
+        ),
+    }
+
+    if config.parallel_chunks > 1 {
+        log::info!(
+            "Sending up to {} chunk requests at once; chunks are processed without a rolling summary",
+            config.parallel_chunks
+        );
+    }
 
     let user_prompt = args
         .prompt
@@ -87,192 +173,620 @@ pub(crate) async fn process_large_input(
         DEFAULT_CHUNK_PROMPT_TEMPLATE.to_string()
     };
 
-    let mut chunker = InputChunker::new(config.chunk_size_chars, config.chunk_overlap_chars);
-    let mut rolling_summary = String::new();
-    let mut chunk_index = 0usize;
-    let mut aggregate_inputs: Vec<String> = Vec::new();
+    let mut chunker = match config.chunk_size_tokens {
+        Some(chunk_size_tokens) => InputChunker::with_tokens(
+            tokenizer::from_config(config)?,
+            chunk_size_tokens,
+            config.chunk_overlap_tokens.unwrap_or(0),
+        ),
+        None => InputChunker::new(config.chunk_size_chars, config.chunk_overlap_chars),
+    }
+    .with_boundary(config.chunk_boundary, config.chunk_boundary_tolerance);
+    let settings_hash = checkpoint::settings_hash(config, &user_prompt, &chunk_template)?;
+    let input = InputIdentity::of(&args.files)?;
+    let (mut checkpoint, resume_point) = match &args.resume {
+        Some(path) => Checkpoint::resume(path, &settings_hash, &input)?,
+        None => (
+            Checkpoint::create(
+                checkpoint::default_path(&settings_hash, &input)?,
+                &settings_hash,
+                &input,
+            ),
+            ResumePoint::default(),
+        ),
+    };
+
+    let chunk_count_estimate = estimate_chunk_count(
+        args,
+        config,
+        &mut chunker,
+        &chunk_template,
+        &user_prompt,
+        &resume_point,
+    );
+    match chunk_count_estimate {
+        Some(count) => debug!("Input is expected to take about {count} chunks"),
+        None => debug!("Input length is unknown, so the number of chunks is too"),
+    }
+    let progress = Progress::new(resume_point.chunk_index, chunk_count_estimate);
+
+    let mut rolling_summary = resume_point.rolling_summary.clone();
+    let mut chunk_index = resume_point.chunk_index;
+    let mut aggregate_inputs = resume_point.aggregate_inputs.clone();
+    let mut answer = keep_answer.then(String::new);
 
-    if !args.files.is_empty() {
-        for file_path in &args.files {
-            let file = File::open(file_path)
-                .with_context(|| format!("Failed to open file: {}", file_path.display()))?;
-            let mut reader = BufReader::new(file);
+    let chunks_result = async {
+        if resume_point.chunks_done {
+            return Ok(());
+        }
+        if args.files.is_empty() {
+            let stdin = io::stdin();
+            let mut reader = BufReader::new(stdin.lock());
+            checkpoint.skip_stdin(&mut reader, &resume_point)?;
+            chunker.start_stream(resume_point.offset);
             process_reader_chunks(
                 &mut reader,
+                "<stdin>",
                 &mut chunker,
-                false,
                 &chunk_template,
                 &user_prompt,
                 &mut rolling_summary,
                 &mut chunk_index,
                 &mut aggregate_inputs,
+                &mut answer,
+                &mut checkpoint,
+                progress,
+                rate_limiter,
                 config,
                 client,
                 show_progress,
+                cache,
+                exchange_log,
             )
             .await?;
+        } else {
+            for (input_index, file_path) in
+                args.files.iter().enumerate().skip(resume_point.input_index)
+            {
+                let mut file = File::open(file_path)
+                    .with_context(|| format!("Failed to open file: {}", file_path.display()))?;
+                // A resumed job continues where its last answered chunk ended
+                let offset = if input_index == resume_point.input_index {
+                    resume_point.offset
+                } else {
+                    0
+                };
+                file.seek(SeekFrom::Start(offset))
+                    .with_context(|| format!("Failed to read file: {}", file_path.display()))?;
+                let mut reader = BufReader::new(file);
+                let language = SourceLanguage::from_path(file_path);
+                if let Some(language) = language {
+                    debug!("Chunking {} as {language:?} source", file_path.display());
+                }
+                chunker.set_language(language);
+                chunker.start_stream(offset);
+                checkpoint.start_input(input_index);
+                process_reader_chunks(
+                    &mut reader,
+                    &file_path.display().to_string(),
+                    &mut chunker,
+                    &chunk_template,
+                    &user_prompt,
+                    &mut rolling_summary,
+                    &mut chunk_index,
+                    &mut aggregate_inputs,
+                    &mut answer,
+                    &mut checkpoint,
+                    progress,
+                    rate_limiter,
+                    config,
+                    client,
+                    show_progress,
+                    cache,
+                    exchange_log,
+                )
+                .await?;
+            }
         }
-    } else {
-        let stdin = io::stdin();
-        let mut reader = BufReader::new(stdin.lock());
-        process_reader_chunks(
-            &mut reader,
-            &mut chunker,
-            false,
-            &chunk_template,
-            &user_prompt,
-            &mut rolling_summary,
-            &mut chunk_index,
-            &mut aggregate_inputs,
-            config,
-            client,
-            show_progress,
-        )
-        .await?;
+        checkpoint.save_chunks_done();
+        Ok(())
+    }
+    .await;
+    if let Err(e) = chunks_result {
+        return Err(with_resume_hint(e, &checkpoint));
     }
-
-    process_reader_chunks(
-        &mut BufReader::new(io::empty()),
-        &mut chunker,
-        true,
-        &chunk_template,
-        &user_prompt,
-        &mut rolling_summary,
-        &mut chunk_index,
-        &mut aggregate_inputs,
-        config,
-        client,
-        show_progress,
-    )
-    .await?;
 
     if chunk_index == 0 {
+        checkpoint.remove();
         return Err(anyhow::anyhow!(
             "No input data was provided for chunked processing"
         ));
     }
 
-    if config.aggregate_chunks && aggregate_inputs.len() > 1 {
-        if args.verbose > 0 {
-            println!("\n[aggregate] Generating final combined answer...\n");
-        }
+    let aggregate_result = async {
+        if config.aggregate_chunks && aggregate_inputs.len() > 1 {
+            let aggregate_template = if let Some(template_file) = &config.aggregate_prompt_file {
+                fs::read_to_string(template_file).with_context(|| {
+                    format!(
+                        "Failed to read aggregate prompt template file: {}",
+                        template_file.display()
+                    )
+                })?
+            } else {
+                DEFAULT_AGGREGATE_PROMPT_TEMPLATE.to_string()
+            };
+            let aggregate_inputs = reduce_aggregate_inputs(
+                args,
+                &aggregate_template,
+                &user_prompt,
+                aggregate_inputs,
+                rate_limiter,
+                config,
+                client,
+                show_progress,
+                cache,
+                exchange_log,
+            )
+            .await?;
 
-        let aggregate_prompt = build_aggregate_prompt(&user_prompt, &aggregate_inputs);
+            if args.verbose > 0 {
+                println!("\n[aggregate] Generating final combined answer...\n");
+            }
 
-        let request = ChatCompletionRequest {
-            model: config.model.clone(),
-            messages: vec![ChatMessage {
-                role: "user".to_string(),
-                content: aggregate_prompt,
-            }],
-            stream: true,
-            temperature: config.temperature,
-        };
+            let aggregate_prompt =
+                render_aggregate_prompt(&aggregate_template, &user_prompt, &aggregate_inputs);
 
-        stream_response(
-            client,
-            config.base_url.as_str(),
-            config.api_key.as_ref(),
-            request,
-            config.timeout_secs,
-            show_progress,
-        )
-        .await?;
+            let request = ChatCompletionRequest {
+                model: config.model.clone(),
+                messages: vec![ChatMessage {
+                    role: "user".to_string(),
+                    content: aggregate_prompt,
+                }],
+                stream: true,
+                temperature: config.temperature,
+            };
+
+            let aggregate_answer = stream_response_with_retries(
+                client,
+                config.base_url.as_str(),
+                config.api_key.as_ref(),
+                request,
+                config.timeout_secs,
+                "Aggregate request".to_string(),
+                true,
+                show_progress,
+                None,
+                rate_limiter,
+                cache,
+                exchange_log,
+            )
+            .await?;
+            if let Some(answer) = answer.as_mut() {
+                *answer = aggregate_answer;
+            }
+        }
+        Ok(())
     }
+    .await;
+    if let Err(e) = aggregate_result {
+        return Err(with_resume_hint(e, &checkpoint));
+    }
+    checkpoint.remove();
 
     println!();
-    Ok(())
+    Ok(answer)
+}
+
+/// Estimated number of chunks in the whole input, or `None` when its length
+/// is unknown. The prompt template's tokens are reserved first, so the
+/// estimate uses the chunk size that the first chunk really gets.
+pub(crate) fn estimate_chunk_count(
+    args: &Args,
+    config: &AppConfig,
+    chunker: &mut InputChunker,
+    chunk_template: &str,
+    user_prompt: &str,
+    resume_point: &ResumePoint,
+) -> Option<usize> {
+    let file_name = args
+        .files
+        .get(resume_point.input_index)
+        .map_or_else(|| "<stdin>".to_string(), |path| path.display().to_string());
+    let first_chunk = ChunkPosition {
+        index: resume_point.chunk_index + 1,
+        count_estimate: None,
+        file_name: &file_name,
+        byte_offset: resume_point.offset,
+    };
+    // A template too long for the chunk size fails once chunks are read
+    if let Err(e) = reserve_prompt_tokens(
+        chunker,
+        config,
+        chunk_template,
+        user_prompt,
+        &resume_point.rolling_summary,
+        &first_chunk,
+    ) {
+        debug!("Not estimating the number of chunks: {e:#}");
+        return None;
+    }
+
+    // Chunks never span files, so each file is estimated on its own
+    let input_lengths = if args.files.is_empty() {
+        stdin_len().map(|len| vec![len])
+    } else {
+        args.files
+            .iter()
+            .map(|path| fs::metadata(path).ok().map(|metadata| metadata.len()))
+            .collect()
+    };
+    input_lengths.map(|lengths: Vec<u64>| {
+        let count: usize = lengths
+            .iter()
+            .map(|&len| chunker.estimate_chunk_count(len))
+            .sum();
+        match config.max_chunks {
+            0 => count,
+            max_chunks => count.min(max_chunks),
+        }
+    })
+}
+
+// Point the user at the checkpoint of a job that failed part way
+fn with_resume_hint(error: anyhow::Error, checkpoint: &Checkpoint) -> anyhow::Error {
+    match checkpoint.path() {
+        Some(path) => error.context(format!(
+            "Chunked processing stopped; continue it with --resume {}",
+            path.display()
+        )),
+        None => error,
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
 async fn process_reader_chunks<R: BufRead>(
     reader: &mut R,
+    file_name: &str,
     chunker: &mut InputChunker,
-    flush_only: bool,
     chunk_template: &str,
     user_prompt: &str,
     rolling_summary: &mut String,
     chunk_index: &mut usize,
     aggregate_inputs: &mut Vec<String>,
+    answer: &mut Option<String>,
+    checkpoint: &mut Checkpoint,
+    progress: Progress,
+    rate_limiter: &mut RateLimiter,
     config: &AppConfig,
     client: &Client,
     show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
 ) -> Result<()> {
-    if !flush_only {
-        let mut line = String::new();
-        loop {
-            line.clear();
-            let read_bytes = reader.read_line(&mut line)?;
-            if read_bytes == 0 {
-                break;
+    let position = |index, byte_offset| ChunkPosition {
+        index,
+        count_estimate: progress.total(),
+        file_name,
+        byte_offset,
+    };
+    reserve_prompt_tokens(
+        chunker,
+        config,
+        chunk_template,
+        user_prompt,
+        rolling_summary,
+        &position(*chunk_index + 1, chunker.offset()),
+    )?;
+
+    // Requests for independent chunks that are still running, oldest first.
+    // Their answers are only printed once every earlier chunk is done.
+    let mut in_flight = FuturesOrdered::new();
+    let mut oldest_in_flight = *chunk_index + 1;
+    let mut reader = TextReader::new(reader);
+    let mut piece = String::new();
+    let mut flush = false;
+    let mut reached_max_chunks = false;
+    loop {
+        let byte_offset = chunker.offset();
+        let chunk_text = match chunker.next_chunk(flush) {
+            Some(chunk_text) => chunk_text,
+            None if flush => break,
+            None => {
+                if reader.read_piece(&mut piece)? {
+                    checkpoint.record_read(&piece);
+                    chunker.push_str(&piece);
+                } else {
+                    flush = true;
+                }
+                continue;
             }
+        };
 
-            chunker.push_str(&line);
-            while let Some(chunk_text) = chunker.next_chunk(false) {
-                *chunk_index += 1;
-                process_single_chunk(
-                    *chunk_index,
-                    &chunk_text,
-                    chunk_template,
+        *chunk_index += 1;
+        // Where the chunk after this one starts, for the checkpoint
+        let next_offset = chunker.offset();
+        let request = chunk_request(
+            &position(*chunk_index, byte_offset),
+            &chunk_text,
+            chunk_template,
+            user_prompt,
+            rolling_summary,
+            config,
+        );
+        if config.parallel_chunks > 1 {
+            if in_flight.len() >= config.parallel_chunks {
+                let (chunk_output, done_index, done_offset) =
+                    next_in_order(&mut in_flight, progress.at(oldest_in_flight), show_progress)
+                        .await?;
+                oldest_in_flight = done_index + 1;
+                record_chunk_output(
+                    &chunk_output,
+                    done_index,
+                    done_offset,
                     user_prompt,
                     rolling_summary,
                     aggregate_inputs,
+                    answer,
+                    checkpoint,
+                    rate_limiter,
                     config,
                     client,
                     show_progress,
+                    cache,
+                    exchange_log,
                 )
                 .await?;
-                if config.max_chunks > 0 && *chunk_index >= config.max_chunks {
-                    return Err(anyhow::anyhow!(
-                        "Reached max_chunks limit ({})",
-                        config.max_chunks
-                    ));
-                }
             }
+            let submitted_index = *chunk_index;
+            let request = RequestTask::spawn(
+                client,
+                config,
+                request,
+                format!("Chunk {submitted_index}"),
+                rate_limiter,
+                cache,
+                exchange_log,
+            );
+            in_flight.push_back(request.map(move |result| {
+                result.map(|chunk_output| (chunk_output, submitted_index, next_offset))
+            }));
+        } else {
+            let chunk_output = stream_response_with_retries(
+                client,
+                config.base_url.as_str(),
+                config.api_key.as_ref(),
+                request,
+                config.timeout_secs,
+                format!("Chunk {chunk_index}"),
+                true,
+                show_progress,
+                Some(progress.at(*chunk_index)),
+                rate_limiter,
+                cache,
+                exchange_log,
+            )
+            .await?;
+            record_chunk_output(
+                &chunk_output,
+                *chunk_index,
+                next_offset,
+                user_prompt,
+                rolling_summary,
+                aggregate_inputs,
+                answer,
+                checkpoint,
+                rate_limiter,
+                config,
+                client,
+                show_progress,
+                cache,
+                exchange_log,
+            )
+            .await?;
         }
-    }
 
-    while let Some(chunk_text) = chunker.next_chunk(true) {
-        *chunk_index += 1;
-        process_single_chunk(
-            *chunk_index,
-            &chunk_text,
+        if config.max_chunks > 0 && *chunk_index >= config.max_chunks {
+            reached_max_chunks = true;
+            break;
+        }
+        reserve_prompt_tokens(
+            chunker,
+            config,
             chunk_template,
             user_prompt,
             rolling_summary,
+            &position(*chunk_index + 1, chunker.offset()),
+        )?;
+    }
+
+    while !in_flight.is_empty() {
+        let (chunk_output, done_index, done_offset) =
+            next_in_order(&mut in_flight, progress.at(oldest_in_flight), show_progress).await?;
+        oldest_in_flight = done_index + 1;
+        record_chunk_output(
+            &chunk_output,
+            done_index,
+            done_offset,
+            user_prompt,
+            rolling_summary,
             aggregate_inputs,
+            answer,
+            checkpoint,
+            rate_limiter,
             config,
             client,
             show_progress,
+            cache,
+            exchange_log,
         )
         .await?;
-        if config.max_chunks > 0 && *chunk_index >= config.max_chunks {
-            return Err(anyhow::anyhow!(
-                "Reached max_chunks limit ({})",
-                config.max_chunks
-            ));
-        }
+    }
+    if reached_max_chunks {
+        return Err(anyhow::anyhow!(
+            "Reached max_chunks limit ({})",
+            config.max_chunks
+        ));
     }
 
     Ok(())
 }
 
+// Wait for the oldest running chunk request, at `progress`, and print its
+// answer. Requests yield the answer with the chunk's index and the offset of
+// the next chunk.
+async fn next_in_order<F>(
+    in_flight: &mut FuturesOrdered<F>,
+    progress: Progress,
+    show_progress: bool,
+) -> Result<(String, usize, u64)>
+where
+    F: Future<Output = Result<(String, usize, u64)>>,
+{
+    let mut spinner = Spinner::with_progress(progress, show_progress);
+    let done = in_flight
+        .next()
+        .await
+        .context("No chunk request is running")??;
+    spinner.finish_and_clear();
+    print!("{}", done.0);
+    io::stdout().flush()?;
+    Ok(done)
+}
+
+// Combine chunk outputs batch by batch, level by level, until a single
+// aggregate prompt can hold all that is left
 #[allow(clippy::too_many_arguments)]
-async fn process_single_chunk(
-    chunk_index: usize,
-    chunk_text: &str,
-    chunk_template: &str,
+pub(crate) async fn reduce_aggregate_inputs(
+    args: &Args,
+    aggregate_template: &str,
     user_prompt: &str,
-    rolling_summary: &mut String,
-    aggregate_inputs: &mut Vec<String>,
+    mut aggregate_inputs: Vec<String>,
+    rate_limiter: &mut RateLimiter,
     config: &AppConfig,
     client: &Client,
     show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
+) -> Result<Vec<String>> {
+    let mut level = 0;
+    while aggregate_inputs.len() > 1
+        && render_aggregate_prompt(aggregate_template, user_prompt, &aggregate_inputs)
+            .chars()
+            .count()
+            > config.aggregate_batch_chars
+    {
+        let input_count = aggregate_inputs.len();
+        let mut batches = batch_outputs(
+            aggregate_template,
+            user_prompt,
+            aggregate_inputs,
+            config.aggregate_batch_chars,
+        );
+        if batches.len() == 1 {
+            // Outputs too long to fit even in pairs go to the final prompt
+            // as they are rather than through a pointless extra level
+            aggregate_inputs = batches.remove(0);
+            break;
+        }
+        level += 1;
+        if args.verbose > 0 {
+            println!(
+                "\n[aggregate] Level {level}: combining {input_count} outputs in {} batches...\n",
+                batches.len()
+            );
+        }
+
+        let mut combined = Vec::with_capacity(batches.len());
+        for (batch_index, batch) in batches.into_iter().enumerate() {
+            if batch.len() == 1 {
+                combined.extend(batch);
+                continue;
+            }
+            let request = ChatCompletionRequest {
+                model: config.model.clone(),
+                messages: vec![ChatMessage {
+                    role: "user".to_string(),
+                    content: render_aggregate_prompt(aggregate_template, user_prompt, &batch),
+                }],
+                stream: true,
+                temperature: config.temperature,
+            };
+            let batch_output = stream_response_with_retries(
+                client,
+                config.base_url.as_str(),
+                config.api_key.as_ref(),
+                request,
+                config.timeout_secs,
+                format!("Aggregate batch {level}.{}", batch_index + 1),
+                false,
+                show_progress,
+                None,
+                rate_limiter,
+                cache,
+                exchange_log,
+            )
+            .await?;
+            combined.push(truncate_chars(&batch_output, config.aggregate_input_chars));
+        }
+        debug!(
+            "Aggregation level {level} reduced {input_count} outputs to {}",
+            combined.len()
+        );
+        aggregate_inputs = combined;
+    }
+    Ok(aggregate_inputs)
+}
+
+// When chunking by tokens, shrink the next chunk so that it fits in
+// chunk_size_tokens together with the prompt template rendered around it
+pub(crate) fn reserve_prompt_tokens(
+    chunker: &mut InputChunker,
+    config: &AppConfig,
+    chunk_template: &str,
+    user_prompt: &str,
+    rolling_summary: &str,
+    next_chunk: &ChunkPosition,
 ) -> Result<()> {
+    let (Some(chunk_size_tokens), Some(tokenizer)) =
+        (config.chunk_size_tokens, chunker.tokenizer())
+    else {
+        return Ok(());
+    };
+    let template_tokens = tokenizer.count_tokens(&render_chunk_prompt(
+        chunk_template,
+        user_prompt,
+        rolling_summary,
+        "",
+        next_chunk,
+    )) + TEMPLATE_JOIN_MARGIN_TOKENS;
+    let next_chunk_index = next_chunk.index;
+    let overlap = config.chunk_overlap_tokens.unwrap_or(0);
+
+    match chunk_size_tokens.checked_sub(template_tokens) {
+        Some(budget) if budget > overlap => {
+            debug!("Chunk {next_chunk_index} may use {budget} of {chunk_size_tokens} tokens");
+            chunker.set_chunk_size(budget);
+            Ok(())
+        }
+        _ => Err(anyhow::anyhow!(
+            "The prompt template for chunk {next_chunk_index} needs about {template_tokens} tokens, leaving no room for chunk text within chunk_size_tokens ({chunk_size_tokens}) and chunk_overlap_tokens ({overlap}); raise chunk_size_tokens or shorten the prompt"
+        )),
+    }
+}
+
+// The request for one chunk
+fn chunk_request(
+    position: &ChunkPosition<'_>,
+    chunk_text: &str,
+    chunk_template: &str,
+    user_prompt: &str,
+    rolling_summary: &str,
+    config: &AppConfig,
+) -> ChatCompletionRequest {
     if log::log_enabled!(log::Level::Debug) {
         debug!(
             "Processing chunk {} ({} chars)",
-            chunk_index,
+            position.index,
             chunk_text.chars().count()
         );
     }
@@ -282,10 +796,10 @@ async fn process_single_chunk(
         user_prompt,
         rolling_summary,
         chunk_text,
-        chunk_index,
+        position,
     );
 
-    let request = ChatCompletionRequest {
+    ChatCompletionRequest {
         model: config.model.clone(),
         messages: vec![ChatMessage {
             role: "user".to_string(),
@@ -293,40 +807,237 @@ async fn process_single_chunk(
         }],
         stream: true,
         temperature: config.temperature,
+    }
+}
+
+// Keep what later chunks, the aggregation step and the caller need from an
+// answer, and checkpoint it together with the offset of the next chunk.
+// Answers over a length limit are cut to it, or summarized first with
+// summarize_chunk_outputs.
+#[allow(clippy::too_many_arguments)]
+async fn record_chunk_output(
+    chunk_output: &str,
+    chunk_index: usize,
+    next_offset: u64,
+    user_prompt: &str,
+    rolling_summary: &mut String,
+    aggregate_inputs: &mut Vec<String>,
+    answer: &mut Option<String>,
+    checkpoint: &mut Checkpoint,
+    rate_limiter: &mut RateLimiter,
+    config: &AppConfig,
+    client: &Client,
+    show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
+) -> Result<()> {
+    if let Some(answer) = answer.as_mut() {
+        answer.push_str(chunk_output);
+    }
+    // Independent chunks never see the answer to the chunk before
+    let rolling_limit = (config.parallel_chunks <= 1).then_some(config.rolling_summary_chars);
+    let aggregate_limit = config
+        .aggregate_chunks
+        .then_some(config.aggregate_input_chars);
+    let output_chars = chunk_output.chars().count();
+
+    // One summary, short enough for the tightest limit the answer exceeds
+    let summary_chars = [rolling_limit, aggregate_limit]
+        .into_iter()
+        .flatten()
+        .filter(|&limit| limit > 0 && output_chars > limit)
+        .min();
+    let summary = match summary_chars {
+        Some(max_chars) if config.summarize_chunk_outputs => Some(
+            summarize_chunk_output(
+                chunk_output,
+                chunk_index,
+                max_chars,
+                user_prompt,
+                rate_limiter,
+                config,
+                client,
+                show_progress,
+                cache,
+                exchange_log,
+            )
+            .await?,
+        ),
+        _ => None,
+    };
+    let condense = |max_chars: usize| {
+        if output_chars <= max_chars {
+            chunk_output.to_string()
+        } else {
+            truncate_chars(summary.as_deref().unwrap_or(chunk_output), max_chars)
+        }
     };
 
-    let chunk_output = stream_response_with_retries(
+    if let Some(max_chars) = rolling_limit {
+        *rolling_summary = condense(max_chars);
+    }
+    let aggregate_input = aggregate_limit.map(condense);
+    checkpoint.save_chunk(
+        chunk_index,
+        next_offset,
+        rolling_summary,
+        aggregate_input.as_deref(),
+    );
+    aggregate_inputs.extend(aggregate_input);
+    Ok(())
+}
+
+// Ask the model for a summary of a chunk's answer that fits in `max_chars`
+#[allow(clippy::too_many_arguments)]
+async fn summarize_chunk_output(
+    chunk_output: &str,
+    chunk_index: usize,
+    max_chars: usize,
+    user_prompt: &str,
+    rate_limiter: &mut RateLimiter,
+    config: &AppConfig,
+    client: &Client,
+    show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
+) -> Result<String> {
+    let prompt = SUMMARY_PROMPT_TEMPLATE
+        .replace("{{max_chars}}", &max_chars.to_string())
+        .replace("{{user_prompt}}", user_prompt)
+        .replace("{{chunk_output}}", chunk_output);
+    let request = ChatCompletionRequest {
+        model: config.model.clone(),
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: prompt,
+        }],
+        stream: true,
+        temperature: config.temperature,
+    };
+    let summary = stream_response_with_retries(
         client,
         config.base_url.as_str(),
         config.api_key.as_ref(),
         request,
         config.timeout_secs,
-        chunk_index,
+        format!("Summary of chunk {chunk_index}"),
+        false,
         show_progress,
+        None,
+        rate_limiter,
+        cache,
+        exchange_log,
     )
     .await?;
+    debug!(
+        "Summarized the answer to chunk {chunk_index} from {} to {} chars",
+        chunk_output.chars().count(),
+        summary.chars().count()
+    );
+    Ok(summary)
+}
+
+/// A request sent with retries on a task of its own, so it keeps streaming
+/// while the caller reads input or waits for earlier answers. Dropping it
+/// cancels the request.
+pub(crate) struct RequestTask(JoinHandle<Result<String>>);
 
-    *rolling_summary = truncate_chars(&chunk_output, 2000);
-    if config.aggregate_chunks {
-        aggregate_inputs.push(truncate_chars(&chunk_output, 1500));
+impl RequestTask {
+    /// Start `request` without printing its answer. The task shares the
+    /// caller's rate limits and acquires them right before each attempt.
+    pub(crate) fn spawn(
+        client: &Client,
+        config: &AppConfig,
+        request: ChatCompletionRequest,
+        label: String,
+        rate_limiter: &RateLimiter,
+        cache: Option<&ResponseCache>,
+        exchange_log: Option<&ExchangeLog>,
+    ) -> Self {
+        let client = client.clone();
+        let base_url = config.base_url.clone();
+        let api_key = config.api_key.clone();
+        let timeout_secs = config.timeout_secs;
+        let mut rate_limiter = rate_limiter.clone();
+        let cache = cache.cloned();
+        let exchange_log = exchange_log.cloned();
+        Self(tokio::spawn(async move {
+            stream_response_with_retries(
+                &client,
+                &base_url,
+                api_key.as_ref(),
+                request,
+                timeout_secs,
+                label,
+                false,
+                false,
+                None,
+                &mut rate_limiter,
+                cache.as_ref(),
+                exchange_log.as_ref(),
+            )
+            .await
+        }))
     }
+}
 
-    Ok(())
+impl Future for RequestTask {
+    type Output = Result<String>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|joined| match joined {
+            Ok(result) => result,
+            Err(e) => Err(anyhow::anyhow!("Request task failed: {e}")),
+        })
+    }
 }
 
-async fn stream_response_with_retries(
+impl Drop for RequestTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Send `request`, retrying failed attempts with backoff. Every attempt,
+/// retries included, first waits for the rate limiter unless the answer is
+/// in the response cache.
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn stream_response_with_retries(
     client: &Client,
     base_url: &str,
     api_key: Option<&String>,
     request: ChatCompletionRequest,
     first_chunk_timeout_secs: u64,
-    chunk_index: usize,
+    label: String,
+    print_output: bool,
     show_progress: bool,
+    progress: Option<Progress>,
+    rate_limiter: &mut RateLimiter,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
 ) -> Result<String> {
     let max_retries = 2;
     let mut backoff_secs = 1u64;
+    let estimated_tokens = request
+        .messages
+        .iter()
+        .map(|message| estimate_tokens(&message.content))
+        .sum();
+
+    let cache_key = match cache {
+        Some(_) => Some(ResponseCache::key(base_url, &request)?),
+        None => None,
+    };
 
     for attempt in 0..=max_retries {
+        // A cached answer is replayed without contacting the endpoint, so
+        // it does not count against the rate limits
+        let cached = cache
+            .zip(cache_key.as_deref())
+            .is_some_and(|(cache, key)| cache.contains(key));
+        if !cached {
+            rate_limiter.acquire(estimated_tokens, show_progress).await;
+        }
         let request_for_attempt = ChatCompletionRequest {
             model: request.model.clone(),
             messages: request.messages.clone(),
@@ -340,8 +1051,11 @@ async fn stream_response_with_retries(
             api_key,
             request_for_attempt,
             first_chunk_timeout_secs,
-            true,
+            print_output,
             show_progress,
+            progress,
+            cache,
+            exchange_log,
         )
         .await
         {
@@ -349,15 +1063,15 @@ async fn stream_response_with_retries(
             Err(err) => {
                 if attempt == max_retries {
                     return Err(anyhow::anyhow!(
-                        "Chunk {} failed after {} attempt(s): {}",
-                        chunk_index,
+                        "{} failed after {} attempt(s): {}",
+                        label,
                         max_retries + 1,
                         err
                     ));
                 }
                 debug!(
-                    "Chunk {} attempt {} failed, retrying in {}s: {}",
-                    chunk_index,
+                    "{} attempt {} failed, retrying in {}s: {}",
+                    label,
                     attempt + 1,
                     backoff_secs,
                     err
@@ -368,8 +1082,5 @@ async fn stream_response_with_retries(
         }
     }
 
-    Err(anyhow::anyhow!(
-        "Unexpected retry loop exit for chunk {}",
-        chunk_index
-    ))
+    Err(anyhow::anyhow!("Unexpected retry loop exit for {}", label))
 }
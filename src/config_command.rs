@@ -0,0 +1,734 @@
+use crate::model_settings::MODEL_SETTINGS_KEYS;
+use crate::{
+    config_from_layers, config_layers_for_args, config_migration, create_default_config,
+    escapes_project, find_project_config, read_config_table, requested_profile, resolve_config,
+    resolve_config_path, resolve_relative_paths, strip_project_excluded_keys, unknown_config_keys,
+    unknown_key_message, validate_chunk_settings, validate_temperature, AppConfig, Args,
+    ConfigLayer, CONFIG_KEYS, CONFIG_PATH_KEYS, PROFILE_EXCLUDED_KEYS, PROJECT_CONFIG_FILE,
+    PROJECT_EXCLUDED_KEYS,
+};
+use anyhow::{Context, Result};
+use clap::Subcommand;
+use log::debug;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml_edit::DocumentMut;
+
+/// Keys whose values are floats even when written without a fraction.
+const FLOAT_KEYS: &[&str] = &["temperature", "chunk_boundary_tolerance"];
+
+/// Keys whose values are always written as strings by `config set`.
+const STRING_KEYS: &[&str] = &[
+    "model",
+    "base_url",
+    "api_key",
+    "api_key_command",
+    "api_key_file",
+    "api_key_env",
+    "default_prompt",
+    "system_prompt",
+    "input_mode",
+    "chunk_prompt_file",
+    "aggregate_prompt_file",
+    "chunk_boundary",
+    "tokenizer",
+    "tokenizer_file",
+    "cache_dir",
+    "default_profile",
+];
+
+/// Manage the configuration file
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct ConfigArgs {
+    #[command(subcommand)]
+    pub(crate) command: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub(crate) enum ConfigCommand {
+    /// Write a default config file (to --config or the default location)
+    Init {
+        /// Overwrite an existing config file
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print the effective configuration and where each value comes from
+    Show,
+    /// Print one effective configuration value
+    Get {
+        /// Config key, e.g. `model`
+        key: String,
+        /// Print secrets such as `api_key` in full instead of masked
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Set a value in the config file
+    Set {
+        /// Config key, e.g. `model` or `profiles.work.model`
+        key: String,
+        /// New value; strings need no quotes
+        value: String,
+        /// Write to the project config file instead of the user config file
+        #[arg(long)]
+        project: bool,
+    },
+    /// Remove a value from the config file
+    Unset {
+        /// Config key, e.g. `model` or `profiles.work.model`
+        key: String,
+        /// Edit the project config file instead of the user config file
+        #[arg(long)]
+        project: bool,
+    },
+    /// Check the config files, environment and flags for problems
+    Validate,
+    /// Open the config file in $VISUAL or $EDITOR and validate it afterwards
+    Edit {
+        /// Edit the project config file instead of the user config file
+        #[arg(long)]
+        project: bool,
+    },
+}
+
+pub(crate) async fn run(args: &Args, config_args: &ConfigArgs) -> Result<()> {
+    match &config_args.command {
+        ConfigCommand::Init { force } => {
+            if args.no_config {
+                return Err(anyhow::anyhow!(
+                    "--no-config cannot be used with `config init`"
+                ));
+            }
+            let config_path = resolve_config_path(args)?;
+            create_default_config(&config_path, *force)?;
+            println!("Created {}", config_path.display());
+            Ok(())
+        }
+        ConfigCommand::Show => {
+            let layers = config_layers_for_args(args)?;
+            let config = resolve_config(args, &layers)?;
+            let sources = config_sources(
+                args,
+                &layers
+                    .iter()
+                    .map(|layer| (layer.path.as_path(), &layer.table))
+                    .collect::<Vec<_>>(),
+                &config,
+                &|name| std::env::var(name).ok(),
+            );
+            print!("{}", format_config(&config, &sources)?);
+            Ok(())
+        }
+        ConfigCommand::Get { key, reveal } => {
+            let path_parts = check_key(key)?;
+            let layers = config_layers_for_args(args)?;
+            let value = if path_parts[0] == "profiles" {
+                profile_value(&layers, &path_parts).cloned()
+            } else {
+                let config = resolve_config(args, &layers)?;
+                let table = toml::Value::Table(toml::Table::try_from(&config)?);
+                path_parts
+                    .iter()
+                    .try_fold(&table, |value, part| value.get(part))
+                    .cloned()
+            };
+            match value {
+                Some(value) => println!("{}", format_value(&path_parts, &value, *reveal)),
+                None => return Err(anyhow::anyhow!("'{key}' is not set")),
+            }
+            Ok(())
+        }
+        ConfigCommand::Set {
+            key,
+            value,
+            project,
+        } => {
+            let path = target_path(args, *project, "set")?;
+            let path_parts = check_key(key)?;
+            if *project && is_project_excluded(&path_parts) {
+                return Err(anyhow::anyhow!(
+                    "{key} cannot be set in a project config file; set it in the user config file instead"
+                ));
+            }
+            let value = parse_config_value(&path_parts, value)?;
+            if *project
+                && path_parts
+                    .last()
+                    .is_some_and(|key| CONFIG_PATH_KEYS.contains(key))
+                && value.as_str().is_some_and(escapes_project)
+            {
+                return Err(anyhow::anyhow!(
+                    "{key} in a project config file must be a path inside the project, without '..'"
+                ));
+            }
+            update_config_file(args, &path, |document| {
+                set_document_value(document, &path_parts, value)
+            })?;
+            println!("Set {key} in {}", path.display());
+            Ok(())
+        }
+        ConfigCommand::Unset { key, project } => {
+            let path = target_path(args, *project, "unset")?;
+            let path_parts = check_key(key)?;
+            if !path.exists() {
+                return Err(anyhow::anyhow!("Config file not found: {}", path.display()));
+            }
+            let mut removed = false;
+            update_config_file(args, &path, |document| {
+                removed = remove_document_value(document, &path_parts);
+                Ok(())
+            })?;
+            if removed {
+                println!("Removed {key} from {}", path.display());
+            } else {
+                println!("{key} is not set in {}", path.display());
+            }
+            Ok(())
+        }
+        ConfigCommand::Validate => {
+            let problems = validation_problems(args)?;
+            report_problems(&problems)?;
+            println!("Configuration is valid");
+            Ok(())
+        }
+        ConfigCommand::Edit { project } => {
+            let path = target_path(args, *project, "edit")?;
+            if !path.exists() {
+                if *project {
+                    fs::write(&path, "").with_context(|| {
+                        format!("Failed to create config file: {}", path.display())
+                    })?;
+                } else {
+                    create_default_config(&path, false)?;
+                }
+            }
+            open_editor(&path)?;
+
+            let table = read_config_table(&path)?;
+            report_problems(&candidate_problems(args, &path, &table)?)?;
+            println!("Configuration is valid");
+            Ok(())
+        }
+    }
+}
+
+/// The value at `path` under `profiles`, from the last config file that
+/// sets it. The resolved configuration cannot answer this, because only the
+/// active profile is merged into it and the `profiles` table is dropped.
+pub(crate) fn profile_value<'a>(
+    layers: &'a [ConfigLayer],
+    path: &[&str],
+) -> Option<&'a toml::Value> {
+    layers.iter().rev().find_map(|layer| {
+        path[1..]
+            .iter()
+            .try_fold(layer.table.get(path[0])?, |value, part| value.get(part))
+    })
+}
+
+// Whether the key at `path` is one a project config file may not set
+fn is_project_excluded(path: &[&str]) -> bool {
+    let is_alias = path.len() >= 2 && path[path.len() - 2] == "aliases";
+    !is_alias
+        && path
+            .last()
+            .is_some_and(|key| PROJECT_EXCLUDED_KEYS.contains(key))
+}
+
+fn ignored_key_message(path: &Path, key: &str) -> String {
+    format!(
+        "{}: {key} is ignored in a project config file; set it in the user config file instead",
+        path.display()
+    )
+}
+
+// The file `config set/unset/edit` changes: the project config file (found
+// from the working directory, or created there) or the user config file
+fn target_path(args: &Args, project: bool, command: &str) -> Result<PathBuf> {
+    if project {
+        let cwd = std::env::current_dir().context("Failed to get current directory")?;
+        return Ok(find_project_config(&cwd).unwrap_or_else(|| cwd.join(PROJECT_CONFIG_FILE)));
+    }
+    if args.no_config {
+        return Err(anyhow::anyhow!(
+            "--no-config cannot be used with `config {command}`"
+        ));
+    }
+    resolve_config_path(args)
+}
+
+// Split a dotted key into its parts. Accepts `<key>`, `aliases.<alias>` and
+// `models.<model>.<setting>`, each optionally under `profiles.<name>.`; model
+// names may themselves contain dots
+pub(crate) fn check_key(key: &str) -> Result<Vec<&str>> {
+    let mut parts = Vec::new();
+    let mut rest = key;
+    if let Some((name, profile_key)) = key
+        .strip_prefix("profiles.")
+        .and_then(|profile| profile.split_once('.'))
+    {
+        parts.extend(["profiles", name]);
+        rest = profile_key;
+    }
+    let in_profile = !parts.is_empty();
+
+    let known = if let Some(alias) = rest.strip_prefix("aliases.") {
+        parts.extend(["aliases", alias]);
+        !alias.is_empty()
+    } else if let Some(model_key) = rest.strip_prefix("models.") {
+        match model_key.rsplit_once('.') {
+            Some((model, setting)) if !model.is_empty() => {
+                parts.extend(["models", model, setting]);
+                MODEL_SETTINGS_KEYS.contains(&setting)
+            }
+            _ => false,
+        }
+    } else {
+        parts.push(rest);
+        CONFIG_KEYS.contains(&rest) && !(in_profile && PROFILE_EXCLUDED_KEYS.contains(&rest))
+    };
+    if !known || parts.iter().any(|part| part.is_empty()) {
+        return Err(anyhow::anyhow!(
+            "Unknown config key '{key}' (known keys: {}, aliases.<alias>, models.<model>.<{}>)",
+            CONFIG_KEYS.join(", "),
+            MODEL_SETTINGS_KEYS.join("|")
+        ));
+    }
+    Ok(parts)
+}
+
+// Parse a command line value for the key at `path`: string keys and alias
+// targets are taken literally, everything else is read as a TOML value
+pub(crate) fn parse_config_value(path: &[&str], value: &str) -> Result<toml_edit::Value> {
+    let key = path.last().copied().unwrap_or_default();
+    let is_alias = path.len() >= 2 && path[path.len() - 2] == "aliases";
+    if is_alias || STRING_KEYS.contains(&key) {
+        return Ok(value.into());
+    }
+    if FLOAT_KEYS.contains(&key) {
+        let number: f64 = value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid value '{value}' for {key}: {e}"))?;
+        return Ok(number.into());
+    }
+    value
+        .parse::<toml_edit::Value>()
+        .map_err(|e| anyhow::anyhow!("Invalid value '{value}' for {key}: {e}"))
+}
+
+pub(crate) fn set_document_value(
+    document: &mut DocumentMut,
+    path: &[&str],
+    value: toml_edit::Value,
+) -> Result<()> {
+    let Some((key, tables)) = path.split_last() else {
+        return Err(anyhow::anyhow!("Empty config key"));
+    };
+    let mut table = document.as_table_mut();
+    for name in tables {
+        let mut new_table = toml_edit::Table::new();
+        new_table.set_implicit(true);
+        table = table
+            .entry(name)
+            .or_insert(toml_edit::Item::Table(new_table))
+            .as_table_mut()
+            .ok_or_else(|| anyhow::anyhow!("'{name}' is not a table"))?;
+    }
+    // Replace existing values in place so their comments survive
+    match table.get_mut(key).and_then(|item| item.as_value_mut()) {
+        Some(existing) => {
+            let decor = existing.decor().clone();
+            *existing = value;
+            *existing.decor_mut() = decor;
+        }
+        None => {
+            table.insert(key, toml_edit::value(value));
+        }
+    }
+    Ok(())
+}
+
+// Remove a value, returning whether it was present
+pub(crate) fn remove_document_value(document: &mut DocumentMut, path: &[&str]) -> bool {
+    let Some((key, tables)) = path.split_last() else {
+        return false;
+    };
+    let mut table = document.as_table_mut();
+    for name in tables {
+        match table.get_mut(name).and_then(|item| item.as_table_mut()) {
+            Some(inner) => table = inner,
+            None => return false,
+        }
+    }
+    table.remove(key).is_some()
+}
+
+// Apply `edit` to the config file at `path`, keeping its comments and layout,
+// and only write the result if the configuration is still valid
+fn update_config_file(
+    args: &Args,
+    path: &Path,
+    edit: impl FnOnce(&mut DocumentMut) -> Result<()>,
+) -> Result<()> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to read config file: {}", path.display()))
+        }
+    };
+    let mut document: DocumentMut = contents
+        .parse()
+        .with_context(|| format!("Invalid config file: {}", path.display()))?;
+    config_migration::migrate_document(&mut document, path);
+    edit(&mut document)?;
+
+    let updated = document.to_string();
+    let table: toml::Table = toml::from_str(&updated).context("Failed to parse updated config")?;
+    let problems = candidate_problems(args, path, &table)?;
+    if !problems.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Not writing {}: {}",
+            path.display(),
+            problems.join("; ")
+        ));
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Failed to create config directory")?;
+    }
+    fs::write(path, updated)
+        .with_context(|| format!("Failed to write config file: {}", path.display()))?;
+    debug!("Updated config file: {}", path.display());
+    Ok(())
+}
+
+// Problems with the whole configuration as the next run would see it
+fn validation_problems(args: &Args) -> Result<Vec<String>> {
+    let mut problems = Vec::new();
+    let layers = config_layers_for_args(args)?;
+    for layer in &layers {
+        problems.extend(
+            unknown_config_keys(&layer.table)
+                .into_iter()
+                .map(|key| unknown_key_message(&layer.path, &key)),
+        );
+        problems.extend(
+            layer
+                .ignored_keys
+                .iter()
+                .map(|key| ignored_key_message(&layer.path, key)),
+        );
+    }
+    match resolve_config(args, &layers) {
+        Ok(config) => problems.extend(value_problems(&config)),
+        Err(e) => problems.push(format!("{e:#}")),
+    }
+    Ok(problems)
+}
+
+// Problems with the config files if `path` held `table`. Every profile in the
+// file is checked, not just the one that would be selected
+fn candidate_problems(args: &Args, path: &Path, table: &toml::Table) -> Result<Vec<String>> {
+    let mut problems: Vec<String> = unknown_config_keys(table)
+        .into_iter()
+        .map(|key| unknown_key_message(path, &key))
+        .collect();
+
+    let user_path = if args.no_config {
+        None
+    } else {
+        Some(resolve_config_path(args)?)
+    };
+    let project_path = std::env::current_dir()
+        .ok()
+        .and_then(|dir| find_project_config(&dir));
+
+    let mut tables = Vec::new();
+    let mut included_target = false;
+    for (layer_path, is_project) in [(user_path, false), (project_path, true)] {
+        let Some(layer_path) = layer_path else {
+            continue;
+        };
+        let mut layer = if layer_path == path {
+            included_target = true;
+            table.clone()
+        } else if layer_path.exists() {
+            read_config_table(&layer_path)?
+        } else {
+            continue;
+        };
+        if is_project {
+            let ignored_keys = strip_project_excluded_keys(&mut layer);
+            if layer_path == path {
+                problems.extend(
+                    ignored_keys
+                        .iter()
+                        .map(|key| ignored_key_message(path, key)),
+                );
+            }
+            if let Some(dir) = layer_path.parent() {
+                resolve_relative_paths(&mut layer, dir);
+            }
+        }
+        tables.push(layer);
+    }
+    if !included_target {
+        // A new project config file that is not discovered yet
+        let mut layer = table.clone();
+        problems.extend(
+            strip_project_excluded_keys(&mut layer)
+                .iter()
+                .map(|key| ignored_key_message(path, key)),
+        );
+        tables.push(layer);
+    }
+
+    let mut profiles: Vec<Option<String>> = vec![requested_profile(args)];
+    if let Some(toml::Value::Table(defined)) = table.get("profiles") {
+        for name in defined.keys() {
+            if !profiles
+                .iter()
+                .any(|profile| profile.as_deref() == Some(name))
+            {
+                profiles.push(Some(name.clone()));
+            }
+        }
+    }
+    for profile in profiles {
+        match config_from_layers(tables.clone(), profile.as_deref()) {
+            Ok(config) => {
+                problems.extend(
+                    value_problems(&config)
+                        .into_iter()
+                        .map(|problem| match &profile {
+                            Some(profile) => format!("profile '{profile}': {problem}"),
+                            None => problem,
+                        }),
+                )
+            }
+            Err(e) => problems.push(format!("{e:#}")),
+        }
+    }
+    problems.dedup();
+    Ok(problems)
+}
+
+// Range checks that run before every request
+fn value_problems(config: &AppConfig) -> Vec<String> {
+    let mut problems = Vec::new();
+    if let Some(temperature) = config.temperature {
+        if let Err(e) = validate_temperature(temperature) {
+            problems.push(e.to_string());
+        }
+    }
+    if let Err(e) = validate_chunk_settings(config) {
+        problems.push(e.to_string());
+    }
+    problems
+}
+
+fn report_problems(problems: &[String]) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for problem in problems {
+        eprintln!("- {problem}");
+    }
+    Err(anyhow::anyhow!(
+        "Found {} configuration problem(s)",
+        problems.len()
+    ))
+}
+
+/// Where each value of the effective `config` comes from, in precedence
+/// order: built-in default, config file, profile, `[models.<name>]` entry,
+/// environment variable, command line flag.
+pub(crate) fn config_sources(
+    args: &Args,
+    layers: &[(&Path, &toml::Table)],
+    config: &AppConfig,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> BTreeMap<&'static str, String> {
+    let mut sources = BTreeMap::new();
+    for key in CONFIG_KEYS {
+        sources.insert(*key, "default".to_string());
+    }
+
+    for (path, table) in layers {
+        for key in CONFIG_KEYS.iter().filter(|key| table.contains_key(**key)) {
+            sources.insert(*key, path.display().to_string());
+        }
+    }
+
+    if let Some(profile) = config.active_profile.as_deref() {
+        for (path, table) in layers {
+            let Some(toml::Value::Table(profile_table)) = table
+                .get("profiles")
+                .and_then(|profiles| profiles.get(profile))
+            else {
+                continue;
+            };
+            for key in CONFIG_KEYS
+                .iter()
+                .filter(|key| profile_table.contains_key(**key))
+            {
+                sources.insert(*key, format!("profile '{profile}' in {}", path.display()));
+            }
+        }
+    }
+
+    if let Some(settings) = config.models.get(&config.model) {
+        // The last file that has settings for the model, at the top level
+        // or in the active profile
+        let defined_in = layers.iter().rev().find_map(|(path, table)| {
+            let in_profile = config
+                .active_profile
+                .as_deref()
+                .and_then(|profile| table.get("profiles")?.get(profile));
+            [Some(*table), in_profile.and_then(toml::Value::as_table)]
+                .into_iter()
+                .flatten()
+                .any(|table| {
+                    table
+                        .get("models")
+                        .and_then(|models| models.get(&config.model))
+                        .is_some()
+                })
+                .then_some(path)
+        });
+        let source = match defined_in {
+            Some(path) => format!("model '{}' in {}", config.model, path.display()),
+            None => format!("model '{}'", config.model),
+        };
+        let mut keys = Vec::new();
+        if settings.temperature.is_some() {
+            keys.push("temperature");
+        }
+        // A size derived from the context window never replaces one from a file
+        let size_from_files = sources["chunk_size_chars"] != "default";
+        if settings.chunk_size_chars.is_some()
+            || (settings.context_window.is_some() && !size_from_files)
+        {
+            keys.push("chunk_size_chars");
+        }
+        if settings.base_url.is_some() {
+            keys.push("base_url");
+        }
+        if settings.api_key_env.is_some() {
+            keys.extend(["api_key", "api_key_command", "api_key_file", "api_key_env"]);
+        }
+        if settings.tokenizer.is_some() {
+            keys.push("tokenizer");
+        }
+        for key in keys {
+            sources.insert(key, source.clone());
+        }
+    }
+
+    for key in CONFIG_KEYS.iter().filter(|key| {
+        !matches!(
+            **key,
+            "default_profile" | "aliases" | "models" | "redact_patterns"
+        )
+    }) {
+        let mut names = Vec::new();
+        if matches!(*key, "base_url" | "api_key") {
+            names.push(format!("OPENAI_{}", key.to_ascii_uppercase()));
+        }
+        names.push(format!("AI_CLI_{}", key.to_ascii_uppercase()));
+        for name in names {
+            if env(&name).is_some_and(|value| !value.is_empty()) {
+                sources.insert(*key, format!("env {name}"));
+            }
+        }
+    }
+
+    let flags = [
+        ("model", args.model.is_some(), "--model"),
+        ("base_url", args.base_url.is_some(), "--base-url"),
+        ("api_key", args.api_key.is_some(), "--api-key"),
+        ("temperature", args.temperature.is_some(), "--temperature"),
+        ("timeout_secs", args.timeout.is_some(), "--timeout"),
+        ("input_mode", args.input_mode.is_some(), "--input-mode"),
+        ("no_progress", args.no_progress, "--no-progress"),
+        ("cache_enabled", args.no_cache, "--no-cache"),
+    ];
+    for (key, set, flag) in flags {
+        if set {
+            sources.insert(key, format!("flag {flag}"));
+        }
+    }
+    sources
+}
+
+/// A value as `config get` prints it: strings without quotes, and secrets
+/// masked unless `reveal` is set.
+pub(crate) fn format_value(path: &[&str], value: &toml::Value, reveal: bool) -> String {
+    match value {
+        toml::Value::String(secret) if path.last() == Some(&"api_key") && !reveal => {
+            mask_secret(secret)
+        }
+        toml::Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+// Show only enough of a secret to tell keys apart
+pub(crate) fn mask_secret(secret: &str) -> String {
+    let chars: Vec<char> = secret.chars().collect();
+    if chars.len() <= 8 {
+        return "***".to_string();
+    }
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("***{tail}")
+}
+
+/// Render the effective config as TOML with the source of each value.
+pub(crate) fn format_config(
+    config: &AppConfig,
+    sources: &BTreeMap<&'static str, String>,
+) -> Result<String> {
+    let table = toml::Table::try_from(config).context("Failed to serialize config")?;
+    let mut output = String::new();
+    if let Some(profile) = &config.active_profile {
+        output.push_str(&format!("# profile: {profile}\n"));
+    }
+    for key in CONFIG_KEYS {
+        let source = sources.get(key).map(String::as_str).unwrap_or("default");
+        match table.get(*key) {
+            Some(toml::Value::String(api_key)) if *key == "api_key" => {
+                let masked = toml::Value::String(mask_secret(api_key));
+                output.push_str(&format!("{key} = {masked}  # {source}\n"));
+            }
+            Some(value) => output.push_str(&format!("{key} = {value}  # {source}\n")),
+            None => output.push_str(&format!("# {key} is not set\n")),
+        }
+    }
+    Ok(output)
+}
+
+// Run $VISUAL, then $EDITOR, then vi on `path` and wait for it to exit
+fn open_editor(path: &Path) -> Result<()> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().unwrap_or("vi");
+    debug!("Opening {} with {editor}", path.display());
+
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()
+        .with_context(|| format!("Failed to start editor '{editor}'"))?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("Editor '{editor}' exited with {status}"));
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+use log::{debug, info, warn};
+use std::path::Path;
+
+/// Version of the config file format written by this build.
+pub(crate) const CONFIG_VERSION: u32 = 1;
+
+/// Changes needed to bring a config file up to `version`.
+pub(crate) struct Migration {
+    pub(crate) version: u32,
+    /// Keys renamed in this version, as `(old, new)`.
+    pub(crate) renamed_keys: &'static [(&'static str, &'static str)],
+}
+
+/// Every format change, oldest first. Files without `config_version` are
+/// version 0.
+const MIGRATIONS: &[Migration] = &[
+    // Version 1 introduced `config_version` itself; older files only need
+    // the version stamp.
+    Migration {
+        version: 1,
+        renamed_keys: &[],
+    },
+];
+
+fn file_version(version: Option<i64>, path: &Path) -> u32 {
+    match version {
+        None => 0,
+        Some(version) if version > i64::from(CONFIG_VERSION) => {
+            warn!(
+                "{} has config_version {version}, newer than this ai-cli supports ({CONFIG_VERSION}); some settings may be ignored",
+                path.display()
+            );
+            u32::try_from(version).unwrap_or(u32::MAX)
+        }
+        Some(version) => u32::try_from(version).unwrap_or(0),
+    }
+}
+
+/// Upgrade a parsed config file in memory, including keys inside
+/// `[profiles.<name>]` sections.
+pub(crate) fn migrate_table(table: &mut toml::Table, path: &Path) {
+    migrate_table_with(table, path, MIGRATIONS);
+}
+
+pub(crate) fn migrate_table_with(table: &mut toml::Table, path: &Path, migrations: &[Migration]) {
+    let version = file_version(
+        table
+            .get("config_version")
+            .and_then(toml::Value::as_integer),
+        path,
+    );
+    if version < CONFIG_VERSION {
+        debug!(
+            "Migrating {} from config_version {version} to {CONFIG_VERSION}",
+            path.display()
+        );
+    }
+
+    for migration in migrations.iter().filter(|m| m.version > version) {
+        for (old, new) in migration.renamed_keys {
+            rename_key(table, old, new, path);
+            if let Some(toml::Value::Table(profiles)) = table.get_mut("profiles") {
+                for (_, profile) in profiles.iter_mut() {
+                    if let toml::Value::Table(profile) = profile {
+                        rename_key(profile, old, new, path);
+                    }
+                }
+            }
+        }
+    }
+    if version < CONFIG_VERSION {
+        table.insert(
+            "config_version".to_string(),
+            toml::Value::Integer(i64::from(CONFIG_VERSION)),
+        );
+    }
+}
+
+fn rename_key(table: &mut toml::Table, old: &str, new: &str, path: &Path) {
+    if let Some(value) = table.remove(old) {
+        info!(
+            "{}: '{old}' is now called '{new}'; run `ai-cli config set` or edit the file to update it",
+            path.display()
+        );
+        table.entry(new.to_string()).or_insert(value);
+    }
+}
+
+/// Upgrade a config document before `ai-cli config` writes it back, keeping
+/// comments and layout.
+pub(crate) fn migrate_document(document: &mut toml_edit::DocumentMut, path: &Path) {
+    migrate_document_with(document, path, MIGRATIONS);
+}
+
+pub(crate) fn migrate_document_with(
+    document: &mut toml_edit::DocumentMut,
+    path: &Path,
+    migrations: &[Migration],
+) {
+    let version = file_version(
+        document
+            .get("config_version")
+            .and_then(|item| item.as_integer()),
+        path,
+    );
+
+    for migration in migrations.iter().filter(|m| m.version > version) {
+        for (old, new) in migration.renamed_keys {
+            rename_document_key(document.as_table_mut(), old, new);
+            if let Some(profiles) = document
+                .get_mut("profiles")
+                .and_then(|item| item.as_table_mut())
+            {
+                for (_, profile) in profiles.iter_mut() {
+                    if let Some(profile) = profile.as_table_mut() {
+                        rename_document_key(profile, old, new);
+                    }
+                }
+            }
+        }
+    }
+    if version < CONFIG_VERSION {
+        document.insert(
+            "config_version",
+            toml_edit::value(i64::from(CONFIG_VERSION)),
+        );
+    }
+}
+
+fn rename_document_key(table: &mut toml_edit::Table, old: &str, new: &str) {
+    if let Some((old_key, item)) = table.remove_entry(old) {
+        if !table.contains_key(new) {
+            // Keep any comment written above the old key
+            let mut new_key = toml_edit::Key::new(new);
+            *new_key.leaf_decor_mut() = old_key.leaf_decor().clone();
+            table.insert_formatted(&new_key, item);
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use crate::models_command::{check_model, fetch_models};
+use crate::sse::SseLines;
+use crate::{
+    api_url, config_layers_for_args, get_final_config, secrets, unknown_config_keys,
+    unknown_key_message, with_auth, AppConfig, Args, ChatCompletionRequest, ChatCompletionResponse,
+    ChatMessage,
+};
+use anyhow::{Context, Result};
+use reqwest::{Client, Url};
+use std::time::{Duration, Instant};
+use tokio_stream::StreamExt;
+
+/// Longest wait for DNS and TCP connection checks.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prompt for the test request; short so the check costs next to nothing.
+const PROBE_PROMPT: &str = "Reply with the single word OK.";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// The outcome of one diagnostic step.
+#[derive(Debug)]
+pub(crate) struct Check {
+    pub(crate) status: Status,
+    pub(crate) name: &'static str,
+    pub(crate) detail: String,
+}
+
+impl Check {
+    fn new(status: Status, name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            status,
+            name,
+            detail: detail.into(),
+        }
+    }
+
+    fn print(&self) {
+        let label = match self.status {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        println!("[{label:>4}] {}: {}", self.name, self.detail);
+    }
+}
+
+/// Run every check in order, printing each result as soon as it is known so
+/// a step that stalls is easy to spot.
+pub(crate) async fn run(args: &Args) -> Result<()> {
+    let mut checks = Vec::new();
+    let mut report = |check: Check| {
+        check.print();
+        checks.push(check);
+    };
+
+    let Some(config) = config_checks(args, &mut report).await else {
+        return Err(anyhow::anyhow!("Configuration could not be loaded"));
+    };
+    let client = Client::builder().build()?;
+    for check in connectivity_checks(&config, &client, &|name| std::env::var(name).ok()).await {
+        report(check);
+    }
+
+    let failures = checks
+        .iter()
+        .filter(|check| check.status == Status::Fail)
+        .count();
+    let warnings = checks
+        .iter()
+        .filter(|check| check.status == Status::Warn)
+        .count();
+    if failures > 0 {
+        return Err(anyhow::anyhow!(
+            "{failures} check(s) failed, {warnings} warning(s)"
+        ));
+    }
+    if warnings > 0 {
+        println!("No failures, {warnings} warning(s)");
+    } else {
+        println!("All checks passed");
+    }
+    Ok(())
+}
+
+// Load the effective config and look at the files it came from. Returns
+// `None` when the config cannot be loaded at all.
+async fn config_checks(args: &Args, report: &mut impl FnMut(Check)) -> Option<AppConfig> {
+    let layers = match config_layers_for_args(args) {
+        Ok(layers) => layers,
+        Err(e) => {
+            report(Check::new(Status::Fail, "config files", format!("{e:#}")));
+            return None;
+        }
+    };
+    if layers.is_empty() {
+        report(Check::new(
+            Status::Ok,
+            "config files",
+            "none found, using built-in defaults",
+        ));
+    }
+    for layer in &layers {
+        report(Check::new(
+            Status::Ok,
+            "config file",
+            layer.path.display().to_string(),
+        ));
+        for key in unknown_config_keys(&layer.table) {
+            report(Check::new(
+                Status::Warn,
+                "config file",
+                unknown_key_message(&layer.path, &key),
+            ));
+        }
+        if secrets::table_has_api_key(&layer.table) {
+            report(if secrets::is_world_readable(&layer.path) {
+                Check::new(
+                    Status::Warn,
+                    "permissions",
+                    format!(
+                        "{} contains api_key and is readable by other users; run `chmod 600 {}`",
+                        layer.path.display(),
+                        layer.path.display()
+                    ),
+                )
+            } else {
+                Check::new(
+                    Status::Ok,
+                    "permissions",
+                    format!("{} is private", layer.path.display()),
+                )
+            });
+        }
+    }
+
+    let config = match get_final_config(args).await {
+        Ok(config) => config,
+        Err(e) => {
+            report(Check::new(Status::Fail, "configuration", format!("{e:#}")));
+            return None;
+        }
+    };
+    report(Check::new(
+        Status::Ok,
+        "configuration",
+        format!(
+            "model {}, base_url {}, timeout {}s{}",
+            config.model,
+            config.base_url,
+            config.timeout_secs,
+            config
+                .active_profile
+                .as_ref()
+                .map(|profile| format!(", profile {profile}"))
+                .unwrap_or_default()
+        ),
+    ));
+    if let Some(path) = &config.api_key_file {
+        if secrets::is_world_readable(path) {
+            report(Check::new(
+                Status::Warn,
+                "permissions",
+                format!("api_key_file {} is readable by other users", path.display()),
+            ));
+        }
+    }
+    report(match &config.api_key {
+        Some(_) => Check::new(Status::Ok, "api key", "configured"),
+        None => Check::new(
+            Status::Warn,
+            "api key",
+            "not configured (fine for local servers such as Ollama)",
+        ),
+    });
+    Some(config)
+}
+
+/// Network checks against the configured endpoint, stopping at the first
+/// step that fails since later ones depend on it. `env` supplies the proxy
+/// variables the HTTP client reads.
+pub(crate) async fn connectivity_checks(
+    config: &AppConfig,
+    client: &Client,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    let url = match Url::parse(&config.base_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => url,
+        Ok(url) => {
+            checks.push(Check::new(
+                Status::Fail,
+                "base url",
+                format!("{url} must be an http:// or https:// URL with a host"),
+            ));
+            return checks;
+        }
+        Err(e) => {
+            checks.push(Check::new(
+                Status::Fail,
+                "base url",
+                format!("'{}' is not a valid URL: {e}", config.base_url),
+            ));
+            return checks;
+        }
+    };
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']')
+        .to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    checks.push(Check::new(
+        Status::Ok,
+        "base url",
+        format!("{} (host {host}, port {port})", config.base_url),
+    ));
+
+    let proxy = proxy_for(&url, env);
+    if let Some(proxy) = &proxy {
+        // The proxy resolves and connects to the host, so looking it up
+        // here would only test this machine's DNS
+        checks.push(Check::new(
+            Status::Ok,
+            "proxy",
+            format!("requests go through {proxy}"),
+        ));
+    } else {
+        let started = Instant::now();
+        let lookup = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            tokio::net::lookup_host((host.as_str(), port)),
+        )
+        .await;
+        let addrs: Vec<_> = match lookup {
+            Ok(Ok(addrs)) => addrs.collect(),
+            Ok(Err(e)) => {
+                checks.push(Check::new(
+                    Status::Fail,
+                    "dns",
+                    format!("could not resolve {host}: {e}"),
+                ));
+                return checks;
+            }
+            Err(_) => {
+                checks.push(Check::new(
+                    Status::Fail,
+                    "dns",
+                    format!("resolving {host} timed out after {CONNECT_TIMEOUT:?}"),
+                ));
+                return checks;
+            }
+        };
+        if addrs.is_empty() {
+            checks.push(Check::new(
+                Status::Fail,
+                "dns",
+                format!("{host} has no addresses"),
+            ));
+            return checks;
+        }
+        checks.push(Check::new(
+            Status::Ok,
+            "dns",
+            format!(
+                "{host} resolves to {} in {}",
+                addrs
+                    .iter()
+                    .map(|addr| addr.ip().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                format_duration(started.elapsed())
+            ),
+        ));
+    }
+
+    // Connect with the same client the requests use, so any proxy is
+    // honoured. Any HTTP response at all means the connection (and for
+    // https the TLS handshake) succeeded.
+    let started = Instant::now();
+    let via = proxy
+        .as_deref()
+        .map(|proxy| format!(" via {proxy}"))
+        .unwrap_or_default();
+    match client
+        .head(url.as_str())
+        .timeout(CONNECT_TIMEOUT)
+        .send()
+        .await
+    {
+        Ok(_) => {
+            let handshake = if url.scheme() == "https" {
+                " and completed the TLS handshake"
+            } else {
+                ""
+            };
+            checks.push(Check::new(
+                Status::Ok,
+                "connect",
+                format!(
+                    "reached {host}:{port}{via}{handshake} in {}",
+                    format_duration(started.elapsed())
+                ),
+            ));
+        }
+        Err(e) if e.is_timeout() => {
+            checks.push(Check::new(
+                Status::Fail,
+                "connect",
+                format!(
+                    "reaching {host}:{port}{via} timed out after {CONNECT_TIMEOUT:?} (firewall or proxy?)"
+                ),
+            ));
+            return checks;
+        }
+        Err(e) => {
+            let hint = if e.is_connect() && proxy.is_none() {
+                " (is the server running?)"
+            } else {
+                ""
+            };
+            checks.push(Check::new(
+                Status::Fail,
+                "connect",
+                format!(
+                    "could not reach {host}:{port}{via}: {:#}{hint}",
+                    anyhow::Error::from(e)
+                ),
+            ));
+            return checks;
+        }
+    }
+
+    // GET /models
+    let started = Instant::now();
+    match fetch_models(client, config).await {
+        Ok(models) => {
+            let elapsed = format_duration(started.elapsed());
+            checks.push(match check_model(&models, config) {
+                Ok(()) => Check::new(
+                    Status::Ok,
+                    "models",
+                    format!(
+                        "{} model(s) listed in {elapsed}, including {}",
+                        models.len(),
+                        config.model
+                    ),
+                ),
+                Err(e) => Check::new(Status::Warn, "models", format!("{e:#}")),
+            });
+        }
+        Err(e) => checks.push(Check::new(
+            Status::Warn,
+            "models",
+            format!("{e:#} (some servers do not implement /models)"),
+        )),
+    }
+
+    checks.push(probe_streaming(config, client).await);
+    checks
+}
+
+/// What a streamed test response looked like.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SseReport {
+    pub(crate) events: usize,
+    pub(crate) content_chars: usize,
+    pub(crate) done: bool,
+    pub(crate) anomalies: Vec<String>,
+    /// Received bytes not yet ending in a newline, split into lines the
+    /// same way a real request splits them.
+    pending: SseLines,
+}
+
+impl SseReport {
+    /// Inspect one network chunk of an SSE body.
+    pub(crate) fn chunk(&mut self, bytes: &[u8]) {
+        self.pending.push(bytes);
+        while let Some(line) = self.pending.next_line() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    let line = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                    self.anomaly(format!("invalid UTF-8 in line {}", preview(&line)));
+                    line
+                }
+            };
+            self.line(&line);
+        }
+    }
+
+    /// Problems visible once the last network chunk has been inspected.
+    pub(crate) fn end(&mut self) {
+        let trailing = String::from_utf8_lossy(&self.pending.take_rest()).into_owned();
+        self.finish(&trailing);
+    }
+
+    /// Inspect one complete line of an SSE body.
+    pub(crate) fn line(&mut self, line: &str) {
+        let line = line.trim_end_matches('\r');
+        if line.is_empty() || line.starts_with(':') {
+            return;
+        }
+        let Some(data) = line.strip_prefix("data:") else {
+            if !["event:", "id:", "retry:"]
+                .iter()
+                .any(|field| line.starts_with(field))
+            {
+                self.anomaly(format!("unexpected line {}", preview(line)));
+            }
+            return;
+        };
+        let data = data.trim_start();
+        if data == "[DONE]" {
+            self.done = true;
+            return;
+        }
+        self.events += 1;
+        if self.done {
+            self.anomaly("data after [DONE]".to_string());
+        }
+        match serde_json::from_str::<ChatCompletionResponse>(data) {
+            Ok(response) => {
+                self.content_chars += response
+                    .choices
+                    .iter()
+                    .filter_map(|choice| choice.delta.content.as_ref())
+                    .map(|content| content.chars().count())
+                    .sum::<usize>();
+            }
+            Err(e) => self.anomaly(format!("unparseable event {}: {e}", preview(data))),
+        }
+    }
+
+    /// Problems visible only once the stream has ended.
+    pub(crate) fn finish(&mut self, trailing: &str) {
+        if !trailing.trim().is_empty() {
+            self.anomaly(format!("unterminated last line {}", preview(trailing)));
+        }
+        if !self.done {
+            self.anomaly("stream ended without data: [DONE]".to_string());
+        }
+        if self.content_chars == 0 {
+            self.anomaly("no content was streamed".to_string());
+        }
+    }
+
+    fn anomaly(&mut self, anomaly: String) {
+        // A broken server tends to repeat itself; a few examples are enough
+        if self.anomalies.len() < 5 {
+            self.anomalies.push(anomaly);
+        }
+    }
+}
+
+fn preview(text: &str) -> String {
+    let short: String = text.chars().take(60).collect();
+    if short.len() < text.len() {
+        format!("'{short}...'")
+    } else {
+        format!("'{short}'")
+    }
+}
+
+// Send a tiny streaming request and time it the way a real run would see it
+async fn probe_streaming(config: &AppConfig, client: &Client) -> Check {
+    match measure_stream(config, client).await {
+        Ok((timings, report)) => {
+            let detail = format!(
+                "response headers after {}, first token after {}, finished after {} ({} event(s))",
+                format_duration(timings.headers),
+                timings
+                    .first_token
+                    .map(format_duration)
+                    .unwrap_or_else(|| "never".to_string()),
+                format_duration(timings.total),
+                report.events
+            );
+            if report.anomalies.is_empty() {
+                Check::new(Status::Ok, "streaming", detail)
+            } else {
+                Check::new(
+                    Status::Warn,
+                    "streaming",
+                    format!("{detail}; anomalies: {}", report.anomalies.join("; ")),
+                )
+            }
+        }
+        Err(e) => Check::new(Status::Fail, "streaming", format!("{e:#}")),
+    }
+}
+
+struct StreamTimings {
+    headers: Duration,
+    first_token: Option<Duration>,
+    total: Duration,
+}
+
+async fn measure_stream(config: &AppConfig, client: &Client) -> Result<(StreamTimings, SseReport)> {
+    let request = ChatCompletionRequest {
+        model: config.model.clone(),
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: PROBE_PROMPT.to_string(),
+        }],
+        stream: true,
+        temperature: config.temperature,
+    };
+    let url = api_url(&config.base_url, "chat/completions");
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let started = Instant::now();
+
+    let response = tokio::time::timeout(
+        timeout,
+        with_auth(client.post(&url).json(&request), config.api_key.as_ref()).send(),
+    )
+    .await
+    .map_err(|_| {
+        anyhow::anyhow!(
+            "no response from {url} within timeout_secs ({}s); this is what a hanging run looks like",
+            config.timeout_secs
+        )
+    })?
+    .with_context(|| format!("Failed to send request to {url}"))?;
+    let headers = started.elapsed();
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_body = response.text().await.unwrap_or_default();
+        return Err(anyhow::anyhow!(
+            "API request failed with status {}: {}",
+            status.as_u16(),
+            error_body
+        ));
+    }
+
+    let mut report = SseReport::default();
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    if !content_type.starts_with("text/event-stream") {
+        report.anomaly(format!(
+            "Content-Type is '{content_type}', expected text/event-stream"
+        ));
+    }
+
+    let mut first_token = None;
+    let mut stream = response.bytes_stream();
+    loop {
+        let chunk = match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(chunk)) => chunk.context("Failed to read response chunk")?,
+            Ok(None) => break,
+            Err(_) => {
+                report.anomaly(format!(
+                    "stream stalled for more than {}s",
+                    config.timeout_secs
+                ));
+                break;
+            }
+        };
+        let before = report.content_chars;
+        report.chunk(&chunk);
+        if first_token.is_none() && report.content_chars > before {
+            first_token = Some(started.elapsed());
+        }
+    }
+    report.end();
+
+    Ok((
+        StreamTimings {
+            headers,
+            first_token,
+            total: started.elapsed(),
+        },
+        report,
+    ))
+}
+
+/// The proxy the HTTP client sends requests for `url` through, if any,
+/// following the same variables it does: `HTTPS_PROXY` or `HTTP_PROXY` for
+/// the URL's scheme, then `ALL_PROXY`, unless the host is in `NO_PROXY`.
+pub(crate) fn proxy_for(url: &Url, env: &dyn Fn(&str) -> Option<String>) -> Option<String> {
+    let var = |name: &str| {
+        env(name)
+            .or_else(|| env(&name.to_ascii_lowercase()))
+            .filter(|value| !value.trim().is_empty())
+    };
+    let scheme_var = if url.scheme() == "https" {
+        "HTTPS_PROXY"
+    } else {
+        "HTTP_PROXY"
+    };
+    let proxy = var(scheme_var).or_else(|| var("ALL_PROXY"))?;
+
+    let host = url
+        .host_str()
+        .unwrap_or_default()
+        .trim_matches(|c| c == '[' || c == ']');
+    let bypassed = var("NO_PROXY").is_some_and(|no_proxy| {
+        no_proxy.split(',').map(str::trim).any(|entry| {
+            let domain = entry.trim_start_matches('.');
+            entry == "*"
+                || (!domain.is_empty() && (host == domain || host.ends_with(&format!(".{domain}"))))
+        })
+    });
+    (!bypassed).then_some(proxy)
+}
+
+fn format_duration(duration: Duration) -> String {
+    if duration.as_secs() >= 1 {
+        format!("{:.1}s", duration.as_secs_f64())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
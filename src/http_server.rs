@@ -0,0 +1,183 @@
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+/// Largest request body accepted by the built-in servers.
+const MAX_BODY_BYTES: usize = 64 * 1024 * 1024;
+const MAX_HEADER_LINES: usize = 100;
+
+/// A parsed HTTP/1.1 request.
+#[derive(Debug)]
+pub(crate) struct HttpRequest {
+    pub(crate) method: String,
+    pub(crate) path: String,
+    pub(crate) headers: Vec<(String, String)>,
+    pub(crate) body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Case-insensitive header lookup.
+    pub(crate) fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Request path without any query string. Absolute-form targets, as
+    /// sent to a proxy (`http://host/v1/models`), are reduced to the path.
+    pub(crate) fn route(&self) -> &str {
+        let path = self.path.split('?').next().unwrap_or_default();
+        match path
+            .strip_prefix("http://")
+            .or_else(|| path.strip_prefix("https://"))
+        {
+            Some(rest) => rest.find('/').map_or("/", |start| &rest[start..]),
+            None => path,
+        }
+    }
+}
+
+/// Read one request from `stream`. Returns `None` if the client closed the
+/// connection before sending anything.
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<Option<HttpRequest>> {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Malformed HTTP request line"))?
+        .to_string();
+    let path = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Malformed HTTP request line"))?
+        .to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() >= MAX_HEADER_LINES {
+            return Err(anyhow::anyhow!("Too many HTTP headers"));
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let mut request = HttpRequest {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    };
+
+    let content_length = match request.header("Content-Length") {
+        Some(value) => value
+            .parse::<usize>()
+            .context("Invalid Content-Length header")?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(anyhow::anyhow!(
+            "Request body too large ({content_length} bytes)"
+        ));
+    }
+    request.body.resize(content_length, 0);
+    reader
+        .read_exact(&mut request.body)
+        .await
+        .context("Failed to read request body")?;
+
+    Ok(Some(request))
+}
+
+fn status_reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// Write a complete response with a fixed-length body.
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+    extra_headers: &[(&str, String)],
+    body: &[u8],
+) -> Result<()> {
+    let mut head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status_reason(status),
+        body.len()
+    );
+    for (name, value) in extra_headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Start a streaming response whose body ends when the connection closes.
+/// The caller writes body bytes directly to `stream` afterwards.
+pub(crate) async fn write_stream_head(
+    stream: &mut TcpStream,
+    status: u16,
+    content_type: &str,
+) -> Result<()> {
+    let head = format!(
+        "HTTP/1.1 {status} {}\r\nContent-Type: {content_type}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        status_reason(status)
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Write a JSON error body in the OpenAI error format.
+pub(crate) async fn write_json_error(
+    stream: &mut TcpStream,
+    status: u16,
+    message: &str,
+    extra_headers: &[(&str, String)],
+) -> Result<()> {
+    let body = serde_json::json!({
+        "error": {
+            "message": message,
+            "type": status_reason(status),
+            "code": status,
+        }
+    });
+    write_response(
+        stream,
+        status,
+        "application/json",
+        extra_headers,
+        body.to_string().as_bytes(),
+    )
+    .await
+}
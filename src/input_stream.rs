@@ -1,47 +1,392 @@
+use crate::chunk_boundary::{find_break, ChunkBoundary};
+use crate::rate_limiter::CHARS_PER_TOKEN;
+use crate::source_language::SourceLanguage;
+use crate::tokenizer::Tokenizer;
+use std::{
+    io::{self, BufRead, Read},
+    sync::Arc,
+};
+
+/// Most bytes read at once. Longer lines are read in pieces so a stream
+/// without line breaks is still chunked with bounded memory.
+pub(crate) const MAX_READ_BYTES: usize = 64 * 1024;
+
+/// Reads UTF-8 text in pieces that end after a line break or after
+/// `MAX_READ_BYTES`, whichever comes first.
+pub(crate) struct TextReader<R> {
+    reader: R,
+    /// Bytes read but not yet returned: the start of a character that was
+    /// split by the read limit.
+    pending: Vec<u8>,
+}
+
+impl<R: BufRead> TextReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Replace `text` with the next piece of input. Returns false at the end
+    /// of the input.
+    pub(crate) fn read_piece(&mut self, text: &mut String) -> io::Result<bool> {
+        text.clear();
+        let limit = MAX_READ_BYTES.saturating_sub(self.pending.len()) as u64;
+        let read_bytes = (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.pending)?;
+        if self.pending.is_empty() {
+            return Ok(false);
+        }
+
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(piece) => piece.len(),
+            // A character cut off by the limit is completed by the next read
+            Err(e) if e.error_len().is_none() && read_bytes > 0 => e.valid_up_to(),
+            Err(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "stream did not contain valid UTF-8",
+                ))
+            }
+        };
+        text.push_str(std::str::from_utf8(&self.pending[..valid]).expect("prefix is valid UTF-8"));
+        self.pending.drain(..valid);
+        Ok(true)
+    }
+}
+
 pub(crate) struct InputChunker {
-    chunk_size_chars: usize,
-    chunk_overlap_chars: usize,
+    chunk_size: usize,
+    chunk_overlap: usize,
+    unit: ChunkUnit,
+    boundary: ChunkBoundary,
+    /// Share of the chunk size, from its end, searched for a boundary.
+    boundary_tolerance: f32,
+    /// Language of the source file being read, if it is code.
+    language: Option<SourceLanguage>,
+    /// Text still to be chunked is `buffer[start..]`. The consumed prefix is
+    /// only dropped on the next push, so cutting a chunk never moves the
+    /// rest of the buffer and each byte is copied a bounded number of times.
     buffer: String,
+    start: usize,
+    /// Characters in `buffer[start..]`.
+    pending_chars: usize,
+    /// Bytes of the stream before `buffer`.
+    dropped_bytes: u64,
+    /// Bytes of buffered text scanned or copied so far.
+    #[cfg(test)]
+    work: std::cell::Cell<usize>,
+}
+
+/// What `chunk_size` and `chunk_overlap` count.
+enum ChunkUnit {
+    Chars,
+    /// Tokens, with the start offset of each token in the buffer. Text is
+    /// tokenized as it is pushed so every byte is only tokenized once.
+    /// Tokens before `first` belong to the consumed prefix of the buffer.
+    Tokens {
+        tokenizer: Arc<dyn Tokenizer>,
+        starts: Vec<usize>,
+        first: usize,
+    },
 }
 
 impl InputChunker {
     pub(crate) fn new(chunk_size_chars: usize, chunk_overlap_chars: usize) -> Self {
         Self {
-            chunk_size_chars,
-            chunk_overlap_chars,
+            chunk_size: chunk_size_chars,
+            chunk_overlap: chunk_overlap_chars,
+            unit: ChunkUnit::Chars,
+            boundary: ChunkBoundary::Exact,
+            boundary_tolerance: 0.0,
+            language: None,
+            buffer: String::new(),
+            start: 0,
+            pending_chars: 0,
+            dropped_bytes: 0,
+            #[cfg(test)]
+            work: Default::default(),
+        }
+    }
+
+    /// Chunk by tokens as counted by `tokenizer` instead of by characters.
+    pub(crate) fn with_tokens(
+        tokenizer: Arc<dyn Tokenizer>,
+        chunk_size_tokens: usize,
+        chunk_overlap_tokens: usize,
+    ) -> Self {
+        Self {
+            chunk_size: chunk_size_tokens,
+            chunk_overlap: chunk_overlap_tokens,
+            unit: ChunkUnit::Tokens {
+                tokenizer,
+                starts: Vec::new(),
+                first: 0,
+            },
+            boundary: ChunkBoundary::Exact,
+            boundary_tolerance: 0.0,
+            language: None,
             buffer: String::new(),
+            start: 0,
+            pending_chars: 0,
+            dropped_bytes: 0,
+            #[cfg(test)]
+            work: Default::default(),
+        }
+    }
+
+    /// End chunks at the last `boundary` within the final `tolerance` share
+    /// of the chunk size, falling back to an exact cut when there is none.
+    pub(crate) fn with_boundary(mut self, boundary: ChunkBoundary, tolerance: f32) -> Self {
+        self.boundary = boundary;
+        self.boundary_tolerance = tolerance;
+        self
+    }
+
+    /// Set the language of the text pushed from now on.
+    pub(crate) fn set_language(&mut self, language: Option<SourceLanguage>) {
+        self.language = language;
+    }
+
+    // Lowest chunk length, in chunk units, that a boundary may shorten a
+    // `length` long chunk to. It stays above the overlap so chunks always
+    // make progress.
+    fn boundary_window_start(&self, length: usize) -> usize {
+        let tolerance = (length as f32 * self.boundary_tolerance) as usize;
+        length
+            .saturating_sub(tolerance)
+            .max(self.chunk_overlap + 1)
+            .min(length)
+    }
+
+    /// The tokenizer when chunking by tokens.
+    pub(crate) fn tokenizer(&self) -> Option<&Arc<dyn Tokenizer>> {
+        match &self.unit {
+            ChunkUnit::Chars => None,
+            ChunkUnit::Tokens { tokenizer, .. } => Some(tokenizer),
+        }
+    }
+
+    /// Change the size of the chunks that follow, e.g. to leave room for a
+    /// prompt template whose rolling summary grows between chunks.
+    pub(crate) fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    /// Bytes allocated for buffered text, which stays around the chunk size
+    /// plus one read no matter how long the input is.
+    #[cfg(test)]
+    pub(crate) fn buffer_capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Bytes of buffered text scanned or copied so far, which grows linearly
+    /// with the input however the chunks are cut.
+    #[cfg(test)]
+    pub(crate) fn work(&self) -> usize {
+        self.work.get()
+    }
+
+    fn count_work(&self, _bytes: usize) {
+        #[cfg(test)]
+        self.work.set(self.work.get() + _bytes);
+    }
+
+    /// Rough number of chunks a stream of `bytes` is cut into, taking a byte
+    /// per character and `CHARS_PER_TOKEN` characters per token. Boundaries
+    /// shorten chunks, so the real count can be somewhat higher.
+    pub(crate) fn estimate_chunk_count(&self, bytes: u64) -> usize {
+        let unit_bytes = match self.unit {
+            ChunkUnit::Chars => 1,
+            ChunkUnit::Tokens { .. } => CHARS_PER_TOKEN as u64,
+        };
+        let size = self.chunk_size as u64 * unit_bytes;
+        // Every chunk after the first repeats the overlap
+        let step = (self.chunk_size.saturating_sub(self.chunk_overlap) as u64 * unit_bytes).max(1);
+        match bytes {
+            0 => 0,
+            bytes if bytes <= size => 1,
+            bytes => 1 + (bytes - size).div_ceil(step) as usize,
         }
     }
 
+    /// Byte offset in the stream where the next chunk starts.
+    pub(crate) fn offset(&self) -> u64 {
+        self.dropped_bytes + self.start as u64
+    }
+
+    /// Start chunking a new stream, of which `offset` bytes were skipped.
+    /// Anything still buffered is discarded.
+    pub(crate) fn start_stream(&mut self, offset: u64) {
+        self.clear();
+        self.dropped_bytes = offset;
+    }
+
     pub(crate) fn push_str(&mut self, text: &str) {
+        self.drop_consumed();
+        self.count_work(text.len());
+        match &mut self.unit {
+            ChunkUnit::Chars => self.pending_chars += text.chars().count(),
+            ChunkUnit::Tokens {
+                tokenizer, starts, ..
+            } => {
+                // Tokenizing pushes separately can only split a token that
+                // would have spanned two pushes, so the count errs on the
+                // high side
+                let offset = self.buffer.len();
+                starts.extend(
+                    tokenizer
+                        .token_starts(text)
+                        .into_iter()
+                        .map(|start| start + offset),
+                );
+            }
+        }
         self.buffer.push_str(text);
     }
 
+    // Remove the text before `start`, which only the overlap of the last
+    // chunk could still have needed
+    fn drop_consumed(&mut self) {
+        if self.start == 0 {
+            return;
+        }
+        let consumed = self.start;
+        self.count_work(self.buffer.len() - consumed);
+        self.buffer.drain(..consumed);
+        self.dropped_bytes += consumed as u64;
+        if let ChunkUnit::Tokens { starts, first, .. } = &mut self.unit {
+            starts.drain(..*first);
+            *first = 0;
+            for start in starts.iter_mut() {
+                *start -= consumed;
+            }
+        }
+        self.start = 0;
+    }
+
+    // Forget all buffered text
+    fn clear(&mut self) {
+        self.dropped_bytes += self.buffer.len() as u64;
+        self.buffer.clear();
+        self.start = 0;
+        self.pending_chars = 0;
+        if let ChunkUnit::Tokens { starts, first, .. } = &mut self.unit {
+            starts.clear();
+            *first = 0;
+        }
+    }
+
     pub(crate) fn next_chunk(&mut self, flush: bool) -> Option<String> {
-        let buffer_chars = self.buffer.chars().count();
-        if buffer_chars == 0 {
+        match self.unit {
+            ChunkUnit::Chars => self.next_char_chunk(flush),
+            ChunkUnit::Tokens { .. } => self.next_token_chunk(flush),
+        }
+    }
+
+    fn next_char_chunk(&mut self, flush: bool) -> Option<String> {
+        let pending_chars = self.pending_chars;
+        if pending_chars == 0 {
             return None;
         }
-        if !flush && buffer_chars < self.chunk_size_chars {
+        if !flush && pending_chars < self.chunk_size {
             return None;
         }
 
-        let take_chars = buffer_chars.min(self.chunk_size_chars);
-        let chunk: String = self.buffer.chars().take(take_chars).collect();
+        let text = &self.buffer[self.start..];
+        let byte_offset = |chars: usize| {
+            text.char_indices()
+                .nth(chars)
+                .map_or(text.len(), |(offset, _)| offset)
+        };
+        let mut take_chars = pending_chars.min(self.chunk_size);
+        let mut end = byte_offset(take_chars);
+        if take_chars < pending_chars {
+            let window_start = byte_offset(self.boundary_window_start(take_chars));
+            if let Some(cut) = find_break(
+                self.boundary,
+                self.language,
+                text,
+                window_start,
+                end,
+                |_| true,
+            ) {
+                take_chars -= text[cut..end].chars().count();
+                end = cut;
+            }
+        }
+        let chunk = text[..end].to_string();
+        // Finding the end, a break before it and the next start each scan
+        // the chunk at most once
+        self.count_work(3 * end);
 
-        let step_chars = if flush && buffer_chars <= self.chunk_size_chars {
-            buffer_chars
+        let step_chars = if flush && pending_chars <= self.chunk_size {
+            pending_chars
         } else {
-            take_chars.saturating_sub(self.chunk_overlap_chars)
+            take_chars.saturating_sub(self.chunk_overlap)
         };
 
-        if step_chars == 0 {
-            // Should never happen due to validation, but this prevents an infinite loop.
-            self.buffer.clear();
+        if step_chars == 0 || step_chars == pending_chars {
+            // A zero step should never happen due to validation, but this
+            // prevents an infinite loop.
+            self.clear();
+            return Some(chunk);
+        }
+
+        self.start += byte_offset(step_chars);
+        self.pending_chars -= step_chars;
+        Some(chunk)
+    }
+
+    fn next_token_chunk(&mut self, flush: bool) -> Option<String> {
+        let window_start_token = self.boundary_window_start(self.chunk_size);
+        let ChunkUnit::Tokens { starts, first, .. } = &self.unit else {
+            return None;
+        };
+        let text = &self.buffer[self.start..];
+        if text.is_empty() {
+            return None;
+        }
+        // Offsets of the pending tokens within `text`
+        let pending = &starts[*first..];
+        let token_start = |token: usize| pending[token] - self.start;
+        // Cut only once a token follows the chunk; until then the last token
+        // could still be part of the next line
+        if pending.len() <= self.chunk_size {
+            if !flush {
+                return None;
+            }
+            let chunk = text.to_string();
+            self.count_work(chunk.len());
+            self.clear();
             return Some(chunk);
         }
 
-        self.buffer = self.buffer.chars().skip(step_chars).collect();
+        let mut cut_token = self.chunk_size;
+        if let Some(cut) = find_break(
+            self.boundary,
+            self.language,
+            text,
+            token_start(window_start_token),
+            token_start(self.chunk_size),
+            |offset| pending.binary_search(&(offset + self.start)).is_ok(),
+        ) {
+            cut_token = pending
+                .binary_search(&(cut + self.start))
+                .unwrap_or(cut_token);
+        }
+
+        let chunk = text[..token_start(cut_token)].to_string();
+        // Finding a break and copying the chunk each scan it at most once
+        self.count_work(2 * token_start(self.chunk_size));
+        let step_token = cut_token.saturating_sub(self.chunk_overlap).max(1);
+        let step = token_start(step_token);
+        self.start += step;
+        if let ChunkUnit::Tokens { first, .. } = &mut self.unit {
+            *first += step_token;
+        }
         Some(chunk)
     }
 }
@@ -1,27 +1,59 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use log::{debug, info, trace};
+use chunk_boundary::ChunkBoundary;
+use clap::{Parser, Subcommand};
+use config_command::ConfigArgs;
+use log::{debug, info, trace, warn};
+use mock_server::MockServerArgs;
+use model_settings::ModelSettings;
+use models_command::ModelsArgs;
+use proxy_server::{ProxyState, ServeArgs};
+use rate_limiter::RateLimiter;
+use recording::{ExchangeLog, RecordedChunk, RecordedExchange, Recorder, Replayer};
 use reqwest::Client;
+use response_cache::ResponseCache;
 use serde::{Deserialize, Serialize};
-use spinner::Spinner;
+use spinner::{Progress, Spinner};
+use sse::SseLines;
 use std::{
+    collections::BTreeMap,
     fs,
     fs::File,
     io::{self, IsTerminal, Read, Write},
-    path::PathBuf,
+    path::{Component, Path, PathBuf},
 };
+use tokenizer::TokenizerKind;
 use tokio_stream::StreamExt;
 
 mod aggregation;
+mod checkpoint;
+mod chunk_boundary;
 mod chunk_processor;
+mod config_command;
+mod config_migration;
+mod doctor_command;
+mod http_server;
 mod input_stream;
+mod mock_server;
+mod model_settings;
+mod models_command;
+mod per_file;
+mod proxy_server;
+mod rate_limiter;
+mod recording;
+mod response_cache;
+mod secrets;
+mod source_language;
 mod spinner;
+mod sse;
+mod tokenizer;
 
-#[cfg(test)]
-pub(crate) use chunk_processor::render_chunk_prompt;
 pub(crate) use chunk_processor::{process_large_input, should_use_chunked_mode};
 #[cfg(test)]
+pub(crate) use chunk_processor::{render_chunk_prompt, ChunkPosition};
+#[cfg(test)]
 pub(crate) use input_stream::InputChunker;
+#[cfg(test)]
+pub(crate) use rate_limiter::{estimate_tokens, TokenBucket};
 
 // Build-time constants
 const GIT_COMMIT_HASH: &str = env!("GIT_COMMIT_HASH", "unknown");
@@ -52,12 +84,30 @@ enum InputMode {
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 struct AppConfig {
+    /// Format version of the file this was read from; see `config_migration`.
+    #[serde(default)]
+    config_version: u32,
+    #[serde(default = "default_model")]
     model: String,
+    #[serde(default = "default_base_url")]
     base_url: String,
     api_key: Option<String>,
+    /// Shell command whose output is the API key, e.g. `pass show openai`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key_command: Option<String>,
+    /// File holding the API key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key_file: Option<PathBuf>,
+    /// Environment variable holding the API key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key_env: Option<String>,
     default_prompt: Option<String>,
+    /// System message `ai-cli serve` adds to requests that carry none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system_prompt: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     temperature: Option<f32>,
+    #[serde(default = "default_timeout_secs")]
     timeout_secs: u64,
     #[serde(default = "default_input_mode")]
     input_mode: InputMode,
@@ -67,38 +117,160 @@ struct AppConfig {
     chunk_overlap_chars: usize,
     #[serde(default)]
     max_chunks: usize,
+    /// Longest rolling summary passed from one chunk to the next.
+    #[serde(default = "default_rolling_summary_chars")]
+    rolling_summary_chars: usize,
+    /// Longest chunk output passed to the aggregation step.
+    #[serde(default = "default_aggregate_input_chars")]
+    aggregate_input_chars: usize,
+    /// Summarize chunk outputs over these limits with an extra request
+    /// instead of cutting them off.
+    #[serde(default)]
+    summarize_chunk_outputs: bool,
+    /// Chunk requests sent at once. Above 1, chunks are processed
+    /// independently, without the rolling summary of the chunk before.
+    #[serde(default = "default_parallel_chunks")]
+    parallel_chunks: usize,
     #[serde(default = "default_auto_chunk_threshold_chars")]
     auto_chunk_threshold_chars: usize,
     #[serde(default = "default_aggregate_chunks")]
     aggregate_chunks: bool,
+    /// Files processed at once with --per-file.
+    #[serde(default = "default_parallel_files")]
+    parallel_files: usize,
+    /// Combine the answers for all files with --per-file.
+    #[serde(default)]
+    aggregate_files: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     chunk_prompt_file: Option<PathBuf>,
+    /// Template for the prompts that combine chunk outputs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aggregate_prompt_file: Option<PathBuf>,
+    /// Longest aggregate prompt in characters. More chunk outputs than fit
+    /// are combined in batches first, level by level.
+    #[serde(default = "default_aggregate_batch_chars")]
+    aggregate_batch_chars: usize,
+    /// Natural break that chunks should end at when they have to be cut.
+    #[serde(default)]
+    chunk_boundary: ChunkBoundary,
+    /// Share of the chunk size, counted back from the cut, searched for a
+    /// `chunk_boundary`.
+    #[serde(default = "default_chunk_boundary_tolerance")]
+    chunk_boundary_tolerance: f32,
+    /// Chunk size in tokens, counting the rendered prompt template; replaces
+    /// `chunk_size_chars` when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_size_tokens: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_overlap_tokens: Option<usize>,
+    /// Tokenizer that counts `chunk_size_tokens`.
+    #[serde(default)]
+    tokenizer: TokenizerKind,
+    /// tiktoken vocabulary file for the `cl100k` and `o200k` tokenizers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokenizer_file: Option<PathBuf>,
     /// Suppress the activity spinner (equivalent to --no-progress).
     #[serde(default)]
     no_progress: bool,
+    /// Client-side request limit for chunked runs (0 means unlimited).
+    #[serde(default)]
+    requests_per_minute: u32,
+    /// Client-side estimated token limit for chunked runs (0 means unlimited).
+    #[serde(default)]
+    tokens_per_minute: u32,
+    /// Replay identical requests from the on-disk response cache.
+    #[serde(default)]
+    cache_enabled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_dir: Option<PathBuf>,
+    #[serde(default = "default_cache_ttl_secs")]
+    cache_ttl_secs: u64,
+    #[serde(default = "default_cache_max_bytes")]
+    cache_max_bytes: u64,
+    /// Models `ai-cli serve` tries in order when the requested one fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fallback_models: Vec<String>,
+    /// Regular expressions whose matches `ai-cli serve` removes from messages.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    redact_patterns: Vec<String>,
+    /// Profile from `[profiles.<name>]` applied when none is selected explicitly.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    default_profile: Option<String>,
+    /// Short names for models, usable anywhere a model name is, e.g. `-m fast`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    aliases: BTreeMap<String, String>,
+    /// Per-model defaults from `[models.<name>]`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    models: BTreeMap<String, ModelSettings>,
+    /// Name of the profile that was applied while loading, if any.
+    #[serde(skip)]
+    active_profile: Option<String>,
 }
 
 impl AppConfig {
     fn default() -> Self {
         AppConfig {
-            model: "llama3".to_string(),
-            base_url: "http://localhost:11434/v1".to_string(),
+            config_version: config_migration::CONFIG_VERSION,
+            model: default_model(),
+            base_url: default_base_url(),
             api_key: None,
+            api_key_command: None,
+            api_key_file: None,
+            api_key_env: None,
             default_prompt: None,
+            system_prompt: None,
             temperature: None, // Use LLM default temperature
-            timeout_secs: 300, // 300 seconds default timeout
+            timeout_secs: default_timeout_secs(),
             input_mode: default_input_mode(),
             chunk_size_chars: default_chunk_size_chars(),
             chunk_overlap_chars: default_chunk_overlap_chars(),
             max_chunks: 0,
+            rolling_summary_chars: default_rolling_summary_chars(),
+            aggregate_input_chars: default_aggregate_input_chars(),
+            summarize_chunk_outputs: false,
+            parallel_chunks: default_parallel_chunks(),
             auto_chunk_threshold_chars: default_auto_chunk_threshold_chars(),
             aggregate_chunks: default_aggregate_chunks(),
+            parallel_files: default_parallel_files(),
+            aggregate_files: false,
             chunk_prompt_file: None,
+            aggregate_prompt_file: None,
+            aggregate_batch_chars: default_aggregate_batch_chars(),
+            chunk_boundary: ChunkBoundary::default(),
+            chunk_boundary_tolerance: default_chunk_boundary_tolerance(),
+            chunk_size_tokens: None,
+            chunk_overlap_tokens: None,
+            tokenizer: TokenizerKind::default(),
+            tokenizer_file: None,
             no_progress: false,
+            requests_per_minute: 0,
+            tokens_per_minute: 0,
+            cache_enabled: false,
+            cache_dir: None,
+            cache_ttl_secs: default_cache_ttl_secs(),
+            cache_max_bytes: default_cache_max_bytes(),
+            fallback_models: Vec::new(),
+            redact_patterns: Vec::new(),
+            default_profile: None,
+            aliases: BTreeMap::new(),
+            models: BTreeMap::new(),
+            active_profile: None,
         }
     }
 }
 
+fn default_model() -> String {
+    "llama3".to_string()
+}
+
+fn default_base_url() -> String {
+    "http://localhost:11434/v1".to_string()
+}
+
+fn default_timeout_secs() -> u64 {
+    300 // 300 seconds default timeout
+}
+
 fn default_input_mode() -> InputMode {
     InputMode::Auto
 }
@@ -111,6 +283,22 @@ fn default_chunk_overlap_chars() -> usize {
     1_000
 }
 
+fn default_rolling_summary_chars() -> usize {
+    2_000
+}
+
+fn default_aggregate_input_chars() -> usize {
+    1_500
+}
+
+fn default_parallel_chunks() -> usize {
+    1
+}
+
+fn default_chunk_boundary_tolerance() -> f32 {
+    0.2
+}
+
 fn default_auto_chunk_threshold_chars() -> usize {
     50_000
 }
@@ -119,8 +307,24 @@ fn default_aggregate_chunks() -> bool {
     true
 }
 
+fn default_parallel_files() -> usize {
+    1
+}
+
+fn default_aggregate_batch_chars() -> usize {
+    16_000
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    86_400
+}
+
+fn default_cache_max_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
 /// OpenAI Compatible API Client
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, about, long_about = None)]
 pub struct Args {
     // Now these are options to override the default config
@@ -181,6 +385,63 @@ pub struct Args {
     /// Suppress the activity spinner (useful for cron jobs and scripts)
     #[arg(long, help = "Disable the activity indicator (spinner)")]
     no_progress: bool,
+
+    /// Always send requests to the API without reading or writing the response cache
+    #[arg(long, conflicts_with = "refresh_cache")]
+    no_cache: bool,
+
+    /// Ignore cached responses but store the fresh ones
+    #[arg(long)]
+    refresh_cache: bool,
+
+    /// Save every raw HTTP exchange (request body and response stream) to a directory
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    record: Option<PathBuf>,
+
+    /// Serve responses from a directory written by --record instead of the network
+    #[arg(long, value_name = "DIR")]
+    replay: Option<PathBuf>,
+
+    /// Continue a failed chunked run from its checkpoint file
+    #[arg(long, value_name = "CHECKPOINT")]
+    resume: Option<PathBuf>,
+
+    /// Run the prompt on each input file separately
+    #[arg(long, requires = "files", conflicts_with = "resume")]
+    per_file: bool,
+
+    /// With --per-file, also write each file's answer to a matching path under this directory
+    #[arg(long, value_name = "DIR", requires = "per_file")]
+    output_dir: Option<PathBuf>,
+
+    /// Configuration profile to apply from [profiles.<name>] (or set AI_CLI_PROFILE)
+    #[arg(long, value_name = "NAME", global = true)]
+    profile: Option<String>,
+
+    /// Read configuration from this file instead of the default location
+    #[arg(long, value_name = "PATH", global = true, conflicts_with = "no_config")]
+    config: Option<PathBuf>,
+
+    /// Ignore the config file and use built-in defaults
+    #[arg(long, global = true)]
+    no_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Run a local OpenAI-compatible mock server for demos and testing
+    MockServer(MockServerArgs),
+    /// Run a local OpenAI-compatible proxy that forwards to the configured backend
+    Serve(ServeArgs),
+    /// Manage the configuration file
+    Config(ConfigArgs),
+    /// List the models served by the configured endpoint
+    Models(ModelsArgs),
+    /// Diagnose configuration and connectivity problems with the configured endpoint
+    Doctor,
 }
 
 #[derive(Serialize)]
@@ -248,10 +509,67 @@ pub async fn main() -> Result<()> {
 
     builder.init();
 
+    if let Some(Command::MockServer(settings)) = &args.command {
+        return mock_server::run(settings).await;
+    }
+    if let Some(Command::Config(config_args)) = &args.command {
+        return config_command::run(&args, config_args).await;
+    }
+    if let Some(Command::Doctor) = &args.command {
+        return doctor_command::run(&args).await;
+    }
+
     // Load and merge configuration from file and command line
     let config = get_final_config(&args).await?;
     let client = Client::builder().build()?;
     debug!("HTTP client initialized with no global timeout");
+    let exchange_log = if let Some(dir) = &args.record {
+        info!("Recording HTTP exchanges to {}", dir.display());
+        Some(ExchangeLog::Record(Recorder::new(dir.clone())?))
+    } else if let Some(dir) = &args.replay {
+        info!("Replaying HTTP exchanges from {}", dir.display());
+        Some(ExchangeLog::Replay(Replayer::new(dir)?))
+    } else {
+        None
+    };
+    // Recording and replaying must see every request, so bypass the cache
+    let cache = if exchange_log.is_some() {
+        debug!("Response cache disabled while recording or replaying");
+        None
+    } else {
+        ResponseCache::from_config(&config, args.refresh_cache)?
+    };
+
+    if let Some(Command::Models(settings)) = &args.command {
+        return models_command::run(settings, &config, &client).await;
+    }
+    if let Some(Command::Serve(settings)) = &args.command {
+        if exchange_log.is_some() {
+            return Err(anyhow::anyhow!(
+                "--record and --replay cannot be used with `serve`"
+            ));
+        }
+        let state = ProxyState {
+            config,
+            client,
+            cache,
+            token: None,
+            redactions: Vec::new(),
+        };
+        return proxy_server::run(settings, state).await;
+    }
+
+    if args.per_file {
+        return per_file::process_files_separately(
+            &args,
+            &config,
+            &client,
+            !config.no_progress,
+            cache.as_ref(),
+            exchange_log.as_ref(),
+        )
+        .await;
+    }
 
     let use_chunked_mode = should_use_chunked_mode(&args, &config)?;
     info!(
@@ -260,7 +578,17 @@ pub async fn main() -> Result<()> {
     );
 
     if use_chunked_mode {
-        process_large_input(&args, &config, &client, !config.no_progress).await?;
+        process_large_input(
+            &args,
+            &config,
+            &client,
+            !config.no_progress,
+            cache.as_ref(),
+            exchange_log.as_ref(),
+            &mut RateLimiter::new(config.requests_per_minute, config.tokens_per_minute),
+            false,
+        )
+        .await?;
     } else {
         // Read all input sources
         info!("Reading input from files and/or stdin");
@@ -294,6 +622,8 @@ pub async fn main() -> Result<()> {
             request,
             config.timeout_secs,
             !config.no_progress,
+            cache.as_ref(),
+            exchange_log.as_ref(),
         )
         .await?;
         println!(); // Print a newline at the end for clean output
@@ -314,18 +644,47 @@ fn validate_temperature(temperature: f32) -> Result<f32> {
     Ok(temperature)
 }
 
-// Load and merge configuration from file and command line
-async fn get_final_config(args: &Args) -> Result<AppConfig> {
-    debug!("Loading configuration from file");
-    // First load from config file
-    let mut config = load_config()?;
+// Merge the config files, environment and command line without validating
+// the result, so `config validate` can report every problem
+fn resolve_config(args: &Args, layers: &[ConfigLayer]) -> Result<AppConfig> {
+    // The profile comes from --profile, then AI_CLI_PROFILE, then default_profile
+    let profile = requested_profile(args);
+    let mut config = config_from_layers(
+        layers.iter().map(|layer| layer.table.clone()).collect(),
+        profile.as_deref(),
+    )?;
     debug!("Base configuration loaded");
 
+    // Per-model defaults sit between the files and the environment, so the
+    // final model has to be known before either override is applied
+    let env = |name: &str| std::env::var(name).ok();
+    let requested_model = args
+        .model
+        .clone()
+        .or_else(|| env_value(&env, "AI_CLI_MODEL"))
+        .unwrap_or_else(|| config.model.clone());
+    let model = model_settings::resolve_model_alias(&config.aliases, &requested_model);
+    let active_profile = config.active_profile.clone();
+    let set_in_files = |key: &str| {
+        layers.iter().any(|layer| {
+            layer.table.contains_key(key)
+                || active_profile
+                    .as_deref()
+                    .and_then(|profile| layer.table.get("profiles")?.get(profile)?.get(key))
+                    .is_some()
+        })
+    };
+    model_settings::apply_model_settings(&mut config, &model, &set_in_files);
+
+    // Environment variables override the file, command line arguments override both
+    apply_env_overrides(&mut config, &env)?;
+
     // Then override with command line arguments if provided
     if let Some(model) = &args.model {
         debug!("Overriding model with command line argument: {model}");
         config.model = model.clone();
     }
+    config.model = model_settings::resolve_model_alias(&config.aliases, &config.model);
 
     if let Some(base_url) = &args.base_url {
         debug!("Overriding base_url with command line argument: {base_url}");
@@ -339,12 +698,8 @@ async fn get_final_config(args: &Args) -> Result<AppConfig> {
 
     if let Some(temperature) = args.temperature {
         debug!("Overriding temperature with command line argument: {temperature}");
-        config.temperature = Some(validate_temperature(temperature)?);
-    } else if let Some(temperature) = config.temperature {
-        // Validate the config file temperature as well
-        config.temperature = Some(validate_temperature(temperature)?);
+        config.temperature = Some(temperature);
     }
-    // If temperature is None in both config and args, leave it as None to use LLM default
 
     if let Some(timeout) = args.timeout {
         debug!("Overriding timeout with command line argument: {timeout}s");
@@ -361,10 +716,40 @@ async fn get_final_config(args: &Args) -> Result<AppConfig> {
         config.no_progress = true;
     }
 
+    if args.no_cache {
+        debug!("Disabling response cache from command line argument");
+        config.cache_enabled = false;
+    }
+
+    Ok(config)
+}
+
+// Load and merge configuration from file and command line
+async fn get_final_config(args: &Args) -> Result<AppConfig> {
+    debug!("Loading configuration from file");
+    // First load from the user config file and any project config file
+    let layers = config_layers_for_args(args)?;
+    for layer in &layers {
+        for key in unknown_config_keys(&layer.table) {
+            warn!("{}", unknown_key_message(&layer.path, &key));
+        }
+    }
+    let mut config = resolve_config(args, &layers)?;
+
+    // If temperature is None in both config and args, leave it as None to use LLM default
+    if let Some(temperature) = config.temperature {
+        validate_temperature(temperature)?;
+    }
     validate_chunk_settings(&config)?;
+    // Only now run api_key_command and friends, once config is known to be valid
+    secrets::resolve_api_key(&mut config)?;
 
+    match &config.active_profile {
+        Some(profile) => info!("Applied configuration profile: {profile}"),
+        None => debug!("No configuration profile applied"),
+    }
     info!(
-        "Final configuration: model={}, base_url={}, temperature={:?}, timeout={}s, input_mode={:?}, chunk_size={}, chunk_overlap={}, max_chunks={}, auto_threshold={}, aggregate_chunks={}",
+        "Final configuration: model={}, base_url={}, temperature={:?}, timeout={}s, input_mode={:?}, chunk_size={}, chunk_overlap={}, max_chunks={}, parallel_chunks={}, auto_threshold={}, aggregate_chunks={}, requests_per_minute={}, tokens_per_minute={}, cache_enabled={}",
         config.model,
         config.base_url,
         config.temperature,
@@ -373,8 +758,12 @@ async fn get_final_config(args: &Args) -> Result<AppConfig> {
         config.chunk_size_chars,
         config.chunk_overlap_chars,
         config.max_chunks,
+        config.parallel_chunks,
         config.auto_chunk_threshold_chars,
-        config.aggregate_chunks
+        config.aggregate_chunks,
+        config.requests_per_minute,
+        config.tokens_per_minute,
+        config.cache_enabled
     );
     if config.api_key.is_some() {
         debug!("API key is configured");
@@ -384,6 +773,206 @@ async fn get_final_config(args: &Args) -> Result<AppConfig> {
     Ok(config)
 }
 
+// Read a non-empty environment variable through `env`
+fn env_value(env: &dyn Fn(&str) -> Option<String>, name: &str) -> Option<String> {
+    let value = env(name).filter(|value| !value.is_empty())?;
+    if name.ends_with("API_KEY") {
+        debug!("Using {name} from environment");
+    } else {
+        debug!("Overriding config with environment variable {name}={value}");
+    }
+    Some(value)
+}
+
+fn env_parse<T: std::str::FromStr>(
+    env: &dyn Fn(&str) -> Option<String>,
+    name: &str,
+) -> Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    env_value(env, name)
+        .map(|value| {
+            value
+                .parse::<T>()
+                .map_err(|e| anyhow::anyhow!("Invalid value '{value}' for {name}: {e}"))
+        })
+        .transpose()
+}
+
+fn env_bool(env: &dyn Fn(&str) -> Option<String>, name: &str) -> Result<Option<bool>> {
+    env_value(env, name)
+        .map(|value| match value.to_ascii_lowercase().as_str() {
+            "1" | "true" | "yes" | "on" => Ok(true),
+            "0" | "false" | "no" | "off" => Ok(false),
+            _ => Err(anyhow::anyhow!(
+                "Invalid value '{value}' for {name}: expected true or false"
+            )),
+        })
+        .transpose()
+}
+
+// A list-valued variable: comma-separated, or a TOML array of strings for
+// items that contain commas themselves, such as regular expressions
+fn env_list(env: &dyn Fn(&str) -> Option<String>, name: &str) -> Result<Option<Vec<String>>> {
+    env_value(env, name)
+        .map(|value| {
+            if value.trim_start().starts_with('[') {
+                return toml::from_str::<toml::Table>(&format!("list = {value}"))
+                    .ok()
+                    .and_then(|mut table| table.remove("list")?.try_into().ok())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!(
+                            "Invalid value '{value}' for {name}: expected an array of strings"
+                        )
+                    });
+            }
+            Ok(value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(String::from)
+                .collect())
+        })
+        .transpose()
+}
+
+// Apply the environment configuration layer. The conventional OPENAI_*
+// variables are read first so the AI_CLI_* equivalents take precedence.
+fn apply_env_overrides(config: &mut AppConfig, env: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    if let Some(base_url) = env_value(env, "OPENAI_BASE_URL") {
+        config.base_url = base_url;
+    }
+    if let Some(api_key) = env_value(env, "OPENAI_API_KEY") {
+        config.api_key = Some(api_key);
+    }
+
+    if let Some(model) = env_value(env, "AI_CLI_MODEL") {
+        config.model = model;
+    }
+    if let Some(base_url) = env_value(env, "AI_CLI_BASE_URL") {
+        config.base_url = base_url;
+    }
+    if let Some(api_key) = env_value(env, "AI_CLI_API_KEY") {
+        config.api_key = Some(api_key);
+    }
+    if let Some(api_key_command) = env_value(env, "AI_CLI_API_KEY_COMMAND") {
+        config.api_key_command = Some(api_key_command);
+    }
+    if let Some(api_key_file) = env_value(env, "AI_CLI_API_KEY_FILE") {
+        config.api_key_file = Some(PathBuf::from(api_key_file));
+    }
+    if let Some(api_key_env) = env_value(env, "AI_CLI_API_KEY_ENV") {
+        config.api_key_env = Some(api_key_env);
+    }
+    if let Some(default_prompt) = env_value(env, "AI_CLI_DEFAULT_PROMPT") {
+        config.default_prompt = Some(default_prompt);
+    }
+    if let Some(system_prompt) = env_value(env, "AI_CLI_SYSTEM_PROMPT") {
+        config.system_prompt = Some(system_prompt);
+    }
+    if let Some(temperature) = env_parse(env, "AI_CLI_TEMPERATURE")? {
+        config.temperature = Some(temperature);
+    }
+    if let Some(timeout_secs) = env_parse(env, "AI_CLI_TIMEOUT_SECS")? {
+        config.timeout_secs = timeout_secs;
+    }
+    if let Some(input_mode) = env_value(env, "AI_CLI_INPUT_MODE") {
+        config.input_mode = <InputMode as clap::ValueEnum>::from_str(&input_mode, true)
+            .map_err(|e| anyhow::anyhow!("Invalid value for AI_CLI_INPUT_MODE: {e}"))?;
+    }
+    if let Some(chunk_size_chars) = env_parse(env, "AI_CLI_CHUNK_SIZE_CHARS")? {
+        config.chunk_size_chars = chunk_size_chars;
+    }
+    if let Some(chunk_overlap_chars) = env_parse(env, "AI_CLI_CHUNK_OVERLAP_CHARS")? {
+        config.chunk_overlap_chars = chunk_overlap_chars;
+    }
+    if let Some(max_chunks) = env_parse(env, "AI_CLI_MAX_CHUNKS")? {
+        config.max_chunks = max_chunks;
+    }
+    if let Some(rolling_summary_chars) = env_parse(env, "AI_CLI_ROLLING_SUMMARY_CHARS")? {
+        config.rolling_summary_chars = rolling_summary_chars;
+    }
+    if let Some(aggregate_input_chars) = env_parse(env, "AI_CLI_AGGREGATE_INPUT_CHARS")? {
+        config.aggregate_input_chars = aggregate_input_chars;
+    }
+    if let Some(summarize) = env_bool(env, "AI_CLI_SUMMARIZE_CHUNK_OUTPUTS")? {
+        config.summarize_chunk_outputs = summarize;
+    }
+    if let Some(parallel_chunks) = env_parse(env, "AI_CLI_PARALLEL_CHUNKS")? {
+        config.parallel_chunks = parallel_chunks;
+    }
+    if let Some(threshold) = env_parse(env, "AI_CLI_AUTO_CHUNK_THRESHOLD_CHARS")? {
+        config.auto_chunk_threshold_chars = threshold;
+    }
+    if let Some(aggregate_chunks) = env_bool(env, "AI_CLI_AGGREGATE_CHUNKS")? {
+        config.aggregate_chunks = aggregate_chunks;
+    }
+    if let Some(parallel_files) = env_parse(env, "AI_CLI_PARALLEL_FILES")? {
+        config.parallel_files = parallel_files;
+    }
+    if let Some(aggregate_files) = env_bool(env, "AI_CLI_AGGREGATE_FILES")? {
+        config.aggregate_files = aggregate_files;
+    }
+    if let Some(chunk_prompt_file) = env_value(env, "AI_CLI_CHUNK_PROMPT_FILE") {
+        config.chunk_prompt_file = Some(PathBuf::from(chunk_prompt_file));
+    }
+    if let Some(aggregate_prompt_file) = env_value(env, "AI_CLI_AGGREGATE_PROMPT_FILE") {
+        config.aggregate_prompt_file = Some(PathBuf::from(aggregate_prompt_file));
+    }
+    if let Some(aggregate_batch_chars) = env_parse(env, "AI_CLI_AGGREGATE_BATCH_CHARS")? {
+        config.aggregate_batch_chars = aggregate_batch_chars;
+    }
+    if let Some(chunk_boundary) = env_value(env, "AI_CLI_CHUNK_BOUNDARY") {
+        config.chunk_boundary = <ChunkBoundary as clap::ValueEnum>::from_str(&chunk_boundary, true)
+            .map_err(|e| anyhow::anyhow!("Invalid value for AI_CLI_CHUNK_BOUNDARY: {e}"))?;
+    }
+    if let Some(tolerance) = env_parse(env, "AI_CLI_CHUNK_BOUNDARY_TOLERANCE")? {
+        config.chunk_boundary_tolerance = tolerance;
+    }
+    if let Some(chunk_size_tokens) = env_parse(env, "AI_CLI_CHUNK_SIZE_TOKENS")? {
+        config.chunk_size_tokens = Some(chunk_size_tokens);
+    }
+    if let Some(chunk_overlap_tokens) = env_parse(env, "AI_CLI_CHUNK_OVERLAP_TOKENS")? {
+        config.chunk_overlap_tokens = Some(chunk_overlap_tokens);
+    }
+    if let Some(tokenizer) = env_value(env, "AI_CLI_TOKENIZER") {
+        config.tokenizer = <TokenizerKind as clap::ValueEnum>::from_str(&tokenizer, true)
+            .map_err(|e| anyhow::anyhow!("Invalid value for AI_CLI_TOKENIZER: {e}"))?;
+    }
+    if let Some(tokenizer_file) = env_value(env, "AI_CLI_TOKENIZER_FILE") {
+        config.tokenizer_file = Some(PathBuf::from(tokenizer_file));
+    }
+    if let Some(no_progress) = env_bool(env, "AI_CLI_NO_PROGRESS")? {
+        config.no_progress = no_progress;
+    }
+    if let Some(requests_per_minute) = env_parse(env, "AI_CLI_REQUESTS_PER_MINUTE")? {
+        config.requests_per_minute = requests_per_minute;
+    }
+    if let Some(tokens_per_minute) = env_parse(env, "AI_CLI_TOKENS_PER_MINUTE")? {
+        config.tokens_per_minute = tokens_per_minute;
+    }
+    if let Some(cache_enabled) = env_bool(env, "AI_CLI_CACHE_ENABLED")? {
+        config.cache_enabled = cache_enabled;
+    }
+    if let Some(cache_dir) = env_value(env, "AI_CLI_CACHE_DIR") {
+        config.cache_dir = Some(PathBuf::from(cache_dir));
+    }
+    if let Some(cache_ttl_secs) = env_parse(env, "AI_CLI_CACHE_TTL_SECS")? {
+        config.cache_ttl_secs = cache_ttl_secs;
+    }
+    if let Some(cache_max_bytes) = env_parse(env, "AI_CLI_CACHE_MAX_BYTES")? {
+        config.cache_max_bytes = cache_max_bytes;
+    }
+    if let Some(fallback_models) = env_list(env, "AI_CLI_FALLBACK_MODELS")? {
+        config.fallback_models = fallback_models;
+    }
+    if let Some(redact_patterns) = env_list(env, "AI_CLI_REDACT_PATTERNS")? {
+        config.redact_patterns = redact_patterns;
+    }
+    Ok(())
+}
+
 fn validate_chunk_settings(config: &AppConfig) -> Result<()> {
     if config.chunk_size_chars == 0 {
         return Err(anyhow::anyhow!("chunk_size_chars must be greater than 0"));
@@ -395,69 +984,578 @@ fn validate_chunk_settings(config: &AppConfig) -> Result<()> {
             config.chunk_size_chars
         ));
     }
+    if config.parallel_chunks == 0 {
+        return Err(anyhow::anyhow!("parallel_chunks must be greater than 0"));
+    }
+    for pattern in &config.redact_patterns {
+        regex::Regex::new(pattern)
+            .with_context(|| format!("Invalid redact_patterns entry '{pattern}'"))?;
+    }
+    if config.parallel_files == 0 {
+        return Err(anyhow::anyhow!("parallel_files must be greater than 0"));
+    }
+    if config.aggregate_input_chars == 0 {
+        return Err(anyhow::anyhow!(
+            "aggregate_input_chars must be greater than 0"
+        ));
+    }
+    if config.aggregate_batch_chars == 0 {
+        return Err(anyhow::anyhow!(
+            "aggregate_batch_chars must be greater than 0"
+        ));
+    }
     if config.auto_chunk_threshold_chars == 0 {
         return Err(anyhow::anyhow!(
             "auto_chunk_threshold_chars must be greater than 0"
         ));
     }
+    if !(0.0..1.0).contains(&config.chunk_boundary_tolerance) {
+        return Err(anyhow::anyhow!(
+            "chunk_boundary_tolerance ({}) must be at least 0.0 and below 1.0",
+            config.chunk_boundary_tolerance
+        ));
+    }
+    match (config.chunk_size_tokens, config.chunk_overlap_tokens) {
+        (Some(0), _) => {
+            return Err(anyhow::anyhow!("chunk_size_tokens must be greater than 0"));
+        }
+        (Some(size), Some(overlap)) if overlap >= size => {
+            return Err(anyhow::anyhow!(
+                "chunk_overlap_tokens ({overlap}) must be smaller than chunk_size_tokens ({size})"
+            ));
+        }
+        (None, Some(_)) => {
+            return Err(anyhow::anyhow!(
+                "chunk_overlap_tokens requires chunk_size_tokens"
+            ));
+        }
+        _ => {}
+    }
+    if config.tokenizer_file.is_some() && config.tokenizer == TokenizerKind::Heuristic {
+        return Err(anyhow::anyhow!(
+            "tokenizer_file requires tokenizer = \"cl100k\" or \"o200k\""
+        ));
+    }
     Ok(())
 }
 
-// Load configuration from config file
-fn load_config() -> Result<AppConfig> {
-    let config_dir = get_config_dir()?;
-    let config_path = config_dir.join("config.toml");
+/// A parsed config file and where it came from.
+struct ConfigLayer {
+    path: PathBuf,
+    table: toml::Table,
+    /// Keys dropped because the file is not trusted with them.
+    ignored_keys: Vec<String>,
+}
+
+/// Keys a project config file may not set. A `.ai-cli.toml` comes with
+/// whatever repository was cloned, so it must not run commands, pick where
+/// the API key is read from or send the key to another host. Its paths are
+/// kept inside the project by `escapes_project`.
+const PROJECT_EXCLUDED_KEYS: &[&str] = &[
+    "base_url",
+    "api_key",
+    "api_key_command",
+    "api_key_file",
+    "api_key_env",
+];
+
+/// Whether a path set in a project config file reaches outside the project:
+/// it is absolute or climbs out with `..`. Such paths could make a cloned
+/// repository read any file into a prompt or write cache files anywhere.
+fn escapes_project(path: &str) -> bool {
+    let path = Path::new(path);
+    path.has_root()
+        || path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+// Remove the keys in PROJECT_EXCLUDED_KEYS, and paths that escape the
+// project, from a project config table, including inside `[profiles.*]` and
+// `[models.*]`, and return their dotted names
+fn strip_project_excluded_keys(table: &mut toml::Table) -> Vec<String> {
+    let mut removed = Vec::new();
+    strip_excluded_keys_at(table, "", &mut removed);
+    removed
+}
+
+fn strip_excluded_keys_at(table: &mut toml::Table, prefix: &str, removed: &mut Vec<String>) {
+    for key in PROJECT_EXCLUDED_KEYS {
+        if table.remove(*key).is_some() {
+            removed.push(format!("{prefix}{key}"));
+        }
+    }
+    for key in CONFIG_PATH_KEYS {
+        if matches!(table.get(*key), Some(toml::Value::String(path)) if escapes_project(path)) {
+            table.remove(*key);
+            removed.push(format!("{prefix}{key}"));
+        }
+    }
+    for section in ["profiles", "models"] {
+        let Some(toml::Value::Table(entries)) = table.get_mut(section) else {
+            continue;
+        };
+        for (name, entry) in entries.iter_mut() {
+            if let toml::Value::Table(entry) = entry {
+                strip_excluded_keys_at(entry, &format!("{prefix}{section}.{name}."), removed);
+            }
+        }
+    }
+}
+
+// The profile comes from --profile, then AI_CLI_PROFILE, then default_profile
+fn requested_profile(args: &Args) -> Option<String> {
+    args.profile.clone().or_else(|| {
+        std::env::var("AI_CLI_PROFILE")
+            .ok()
+            .filter(|profile| !profile.is_empty())
+    })
+}
+
+// Config file layers selected by the command line: none with --no-config,
+// otherwise the user config file and any project config file
+fn config_layers_for_args(args: &Args) -> Result<Vec<ConfigLayer>> {
+    if args.no_config {
+        debug!("Skipping config files (--no-config)");
+        return Ok(Vec::new());
+    }
+    let project_config_path = std::env::current_dir()
+        .ok()
+        .and_then(|dir| find_project_config(&dir));
+    load_config_layers(
+        &resolve_config_path(args)?,
+        args.config.is_some(),
+        project_config_path.as_deref(),
+    )
+}
+
+// Load configuration from the user config file and an optional project
+// config file layered over it.
+#[cfg(test)]
+fn load_config(
+    config_path: &Path,
+    required: bool,
+    project_config_path: Option<&Path>,
+    profile: Option<&str>,
+) -> Result<AppConfig> {
+    let layers = load_config_layers(config_path, required, project_config_path)?;
+    config_from_layers(
+        layers.into_iter().map(|layer| layer.table).collect(),
+        profile,
+    )
+}
+
+// Read the user config file and an optional project config file, in
+// precedence order. A missing user file is an error only when the path was
+// given explicitly; otherwise the defaults are used and nothing is written
+// to disk.
+fn load_config_layers(
+    config_path: &Path,
+    required: bool,
+    project_config_path: Option<&Path>,
+) -> Result<Vec<ConfigLayer>> {
     debug!("Config path: {}", config_path.display());
+    let mut layers = Vec::new();
 
-    if !config_path.exists() {
-        // Create default config if file doesn't exist
-        info!("Config file not found, creating default configuration");
-        create_default_config(&config_dir)?;
-        return Ok(AppConfig::default());
+    if config_path.exists() {
+        info!("Reading existing config file");
+        let table = read_config_table(config_path)?;
+        if secrets::table_has_api_key(&table) {
+            secrets::warn_if_world_readable(config_path, "Config file containing api_key");
+        }
+        layers.push(ConfigLayer {
+            path: config_path.to_path_buf(),
+            table,
+            ignored_keys: Vec::new(),
+        });
+    } else if required {
+        return Err(anyhow::anyhow!(
+            "Config file not found: {}",
+            config_path.display()
+        ));
+    } else {
+        info!(
+            "Config file not found at {}, using defaults (run `ai-cli config init` to create one)",
+            config_path.display()
+        );
+    }
+
+    if let Some(project_config_path) = project_config_path {
+        info!(
+            "Reading project config file: {}",
+            project_config_path.display()
+        );
+        let mut project_table = read_config_table(project_config_path)?;
+        let ignored_keys = strip_project_excluded_keys(&mut project_table);
+        for key in &ignored_keys {
+            warn!(
+                "Ignoring {key} in project config file {}: API key sources, base_url and paths outside the project may only be set by the user config file, --config, the environment or the command line",
+                project_config_path.display()
+            );
+        }
+        if let Some(project_dir) = project_config_path.parent() {
+            resolve_relative_paths(&mut project_table, project_dir);
+        }
+        layers.push(ConfigLayer {
+            path: project_config_path.to_path_buf(),
+            table: project_table,
+            ignored_keys,
+        });
     }
 
-    // Read existing config file
-    info!("Reading existing config file");
-    let config_contents = fs::read_to_string(&config_path).context(format!(
+    debug!("Loaded {} config file(s)", layers.len());
+    Ok(layers)
+}
+
+fn read_config_table(config_path: &Path) -> Result<toml::Table> {
+    let config_contents = fs::read_to_string(config_path).context(format!(
         "Failed to read config file: {}",
         config_path.display()
     ))?;
+    let mut table: toml::Table = toml::from_str(&config_contents)
+        .with_context(|| format!("Invalid config file: {}", config_path.display()))?;
+
+    // Deserialize the file on its own as well so type errors point at the
+    // offending line and column rather than the merged configuration
+    let file_profiles = toml::from_str::<AppConfig>(&config_contents)
+        .and_then(|_| toml::from_str::<ConfigFileProfiles>(&config_contents))
+        .with_context(|| format!("Invalid config file: {}", config_path.display()))?;
+    debug!(
+        "{} defines {} profile(s)",
+        config_path.display(),
+        file_profiles.profiles.len()
+    );
 
-    let config: AppConfig =
-        toml::from_str(&config_contents).context("Failed to parse config file as TOML")?;
+    config_migration::migrate_table(&mut table, config_path);
+    Ok(table)
+}
 
-    debug!("Configuration loaded successfully");
+/// The `[profiles.<name>]` sections of a config file, for type checking.
+#[derive(Deserialize)]
+struct ConfigFileProfiles {
+    #[serde(default)]
+    profiles: std::collections::BTreeMap<String, AppConfig>,
+}
+
+// Layer config tables over the built-in defaults (later layers win), then
+// apply the requested or default profile
+fn config_from_layers(layers: Vec<toml::Table>, profile: Option<&str>) -> Result<AppConfig> {
+    let mut table = toml::Table::try_from(AppConfig::default())
+        .context("Failed to serialize default config")?;
+    for layer in layers {
+        merge_tables(&mut table, layer);
+    }
+    let active_profile = apply_profile(&mut table, profile)?;
+
+    let mut config: AppConfig = toml::Value::Table(table)
+        .try_into()
+        .context("Invalid configuration")?;
+    config.active_profile = active_profile;
     Ok(config)
 }
 
+// Merge `overlay` into `base`; nested tables such as `profiles` merge key by key
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Every key accepted at the top level of a config file, besides `profiles`.
+const CONFIG_KEYS: &[&str] = &[
+    "config_version",
+    "model",
+    "base_url",
+    "api_key",
+    "api_key_command",
+    "api_key_file",
+    "api_key_env",
+    "default_prompt",
+    "system_prompt",
+    "temperature",
+    "timeout_secs",
+    "input_mode",
+    "chunk_size_chars",
+    "chunk_overlap_chars",
+    "max_chunks",
+    "rolling_summary_chars",
+    "aggregate_input_chars",
+    "summarize_chunk_outputs",
+    "parallel_chunks",
+    "auto_chunk_threshold_chars",
+    "aggregate_chunks",
+    "parallel_files",
+    "aggregate_files",
+    "chunk_prompt_file",
+    "aggregate_prompt_file",
+    "aggregate_batch_chars",
+    "chunk_boundary",
+    "chunk_boundary_tolerance",
+    "chunk_size_tokens",
+    "chunk_overlap_tokens",
+    "tokenizer",
+    "tokenizer_file",
+    "no_progress",
+    "requests_per_minute",
+    "tokens_per_minute",
+    "cache_enabled",
+    "cache_dir",
+    "cache_ttl_secs",
+    "cache_max_bytes",
+    "fallback_models",
+    "redact_patterns",
+    "default_profile",
+    "aliases",
+    "models",
+];
+
+/// Config keys that only make sense at the top level of a file.
+const PROFILE_EXCLUDED_KEYS: &[&str] = &["config_version", "default_profile"];
+
+// The known key closest to a misspelt one, e.g. `chunk_size` for
+// `chunk_size_chars`
+fn suggest_config_key(key: &str) -> Option<&'static str> {
+    let candidates = if key.starts_with("models.") || key.contains(".models.") {
+        model_settings::MODEL_SETTINGS_KEYS
+    } else {
+        CONFIG_KEYS
+    };
+    let key = key.rsplit('.').next().unwrap_or(key);
+    candidates
+        .iter()
+        .map(|known| (edit_distance(key, known), *known))
+        .filter(|(distance, known)| *distance <= 3 || known.starts_with(key))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known)
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// Describe an unknown key, with a suggestion when it looks like a typo
+fn unknown_key_message(path: &Path, key: &str) -> String {
+    match suggest_config_key(key) {
+        Some(known) => format!(
+            "{}: unknown key '{key}' (did you mean '{known}'?)",
+            path.display()
+        ),
+        None => format!("{}: unknown key '{key}'", path.display()),
+    }
+}
+
+// Keys in a config table (including inside profiles) that ai-cli does not know
+fn unknown_config_keys(table: &toml::Table) -> Vec<String> {
+    let mut unknown = Vec::new();
+    collect_unknown_keys(table, "", &mut unknown);
+    if let Some(toml::Value::Table(profiles)) = table.get("profiles") {
+        for (name, profile) in profiles {
+            if let toml::Value::Table(profile) = profile {
+                let prefix = format!("profiles.{name}.");
+                unknown.extend(
+                    profile
+                        .keys()
+                        .filter(|key| PROFILE_EXCLUDED_KEYS.contains(&key.as_str()))
+                        .map(|key| format!("{prefix}{key}")),
+                );
+                collect_unknown_keys(profile, &prefix, &mut unknown);
+            }
+        }
+    }
+    unknown
+}
+
+// Unknown keys in a top-level or profile table, including the fields of
+// its `[models.<name>]` tables
+fn collect_unknown_keys(table: &toml::Table, prefix: &str, unknown: &mut Vec<String>) {
+    for (key, value) in table {
+        if prefix.is_empty() && key == "profiles" {
+            continue;
+        }
+        if !CONFIG_KEYS.contains(&key.as_str()) {
+            unknown.push(format!("{prefix}{key}"));
+            continue;
+        }
+        if let ("models", toml::Value::Table(models)) = (key.as_str(), value) {
+            for (model, settings) in models {
+                if let toml::Value::Table(settings) = settings {
+                    unknown.extend(
+                        settings
+                            .keys()
+                            .filter(|field| {
+                                !model_settings::MODEL_SETTINGS_KEYS.contains(&field.as_str())
+                            })
+                            .map(|field| format!("{prefix}models.{model}.{field}")),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Config keys holding file system paths.
+const CONFIG_PATH_KEYS: &[&str] = &[
+    "chunk_prompt_file",
+    "aggregate_prompt_file",
+    "cache_dir",
+    "api_key_file",
+    "tokenizer_file",
+];
+
+// Make relative paths in a project config relative to the file's directory,
+// including paths set inside its profiles
+fn resolve_relative_paths(table: &mut toml::Table, base_dir: &Path) {
+    for key in CONFIG_PATH_KEYS {
+        if let Some(toml::Value::String(path)) = table.get_mut(*key) {
+            if Path::new(path.as_str()).is_relative() {
+                *path = base_dir.join(path.as_str()).to_string_lossy().into_owned();
+            }
+        }
+    }
+    if let Some(toml::Value::Table(profiles)) = table.get_mut("profiles") {
+        for (_, profile) in profiles.iter_mut() {
+            if let toml::Value::Table(profile) = profile {
+                resolve_relative_paths(profile, base_dir);
+            }
+        }
+    }
+}
+
+/// Name of the per-project config file discovered from the working directory.
+const PROJECT_CONFIG_FILE: &str = ".ai-cli.toml";
+
+// Walk up from `start` to the file system root looking for a project config
+fn find_project_config(start: &Path) -> Option<PathBuf> {
+    start
+        .ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|candidate| candidate.is_file())
+}
+
+// Merge the selected `[profiles.<name>]` table over the base config table and
+// return the name of the applied profile
+fn apply_profile(table: &mut toml::Table, requested: Option<&str>) -> Result<Option<String>> {
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => return Err(anyhow::anyhow!("'profiles' must be a table")),
+        None => toml::Table::new(),
+    };
+
+    let name = match requested {
+        Some(name) => name.to_string(),
+        None => match table.get("default_profile") {
+            Some(toml::Value::String(name)) => name.clone(),
+            Some(_) => return Err(anyhow::anyhow!("'default_profile' must be a string")),
+            None => return Ok(None),
+        },
+    };
+
+    let profile = match profiles.get(&name) {
+        Some(toml::Value::Table(profile)) => profile,
+        Some(_) => return Err(anyhow::anyhow!("Profile '{name}' must be a table")),
+        None => {
+            let available: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            return Err(anyhow::anyhow!(
+                "Profile '{}' not found in config (available: {})",
+                name,
+                if available.is_empty() {
+                    "none".to_string()
+                } else {
+                    available.join(", ")
+                }
+            ));
+        }
+    };
+
+    debug!("Applying configuration profile: {name}");
+    for (key, value) in profile {
+        if key == "profiles" || key == "default_profile" {
+            return Err(anyhow::anyhow!("Profile '{name}' cannot set '{key}'"));
+        }
+        // Tables such as `aliases` and `models` merge with the top-level ones
+        match (table.get_mut(key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay.clone());
+            }
+            _ => {
+                table.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    Ok(Some(name))
+}
+
 // Create default config directory and file if they don't exist
-fn create_default_config(config_dir: &PathBuf) -> Result<()> {
-    debug!("Creating config directory: {}", config_dir.display());
-    // Create directory if it doesn't exist
-    fs::create_dir_all(config_dir).context("Failed to create config directory")?;
+// Only called by `ai-cli config init`; normal runs never write the config file.
+fn create_default_config(config_path: &Path, force: bool) -> Result<()> {
+    if config_path.exists() && !force {
+        return Err(anyhow::anyhow!(
+            "Config file already exists: {} (use --force to overwrite)",
+            config_path.display()
+        ));
+    }
+
+    if let Some(config_dir) = config_path.parent() {
+        debug!("Creating config directory: {}", config_dir.display());
+        // Create directory if it doesn't exist
+        fs::create_dir_all(config_dir).context("Failed to create config directory")?;
+    }
 
     // Create default config file
     let default_config = AppConfig::default();
     let toml_config =
         toml::to_string(&default_config).context("Failed to serialize default config")?;
 
-    let config_file_path = config_dir.join("config.toml");
-    debug!("Writing default config to: {}", config_file_path.display());
-    fs::write(config_file_path, toml_config).context("Failed to write default config file")?;
+    debug!("Writing default config to: {}", config_path.display());
+    fs::write(config_path, toml_config).context("Failed to write default config file")?;
 
     info!("Default configuration created successfully");
     Ok(())
 }
 
-// Get or create config directory
+// Get config directory, honouring XDG_CONFIG_HOME when it is set
 fn get_config_dir() -> Result<PathBuf> {
-    let home_dir = dirs::home_dir().ok_or(anyhow::anyhow!("Could not find home directory"))?;
-
-    let config_dir = home_dir.join(".config").join("ai-cli");
+    let config_dir = config_dir_from(std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from))?;
     debug!("Config directory: {}", config_dir.display());
     Ok(config_dir)
 }
 
+// Relative XDG_CONFIG_HOME values are invalid per the XDG spec and ignored
+fn config_dir_from(xdg_config_home: Option<PathBuf>) -> Result<PathBuf> {
+    let config_home = match xdg_config_home.filter(|path| path.is_absolute()) {
+        Some(config_home) => config_home,
+        None => dirs::home_dir()
+            .ok_or(anyhow::anyhow!("Could not find home directory"))?
+            .join(".config"),
+    };
+    Ok(config_home.join("ai-cli"))
+}
+
+// Resolve the config file path from --config or the default location
+fn resolve_config_path(args: &Args) -> Result<PathBuf> {
+    match &args.config {
+        Some(config_path) => Ok(config_path.clone()),
+        None => Ok(get_config_dir()?.join("config.toml")),
+    }
+}
+
 async fn read_input(args: &Args) -> Result<String> {
     let mut input = String::new();
 
@@ -513,6 +1611,29 @@ async fn read_input(args: &Args) -> Result<String> {
     Ok(input)
 }
 
+// Join an endpoint path onto the configured base URL
+fn api_url(base_url: &str, path: &str) -> String {
+    format!("{}/{path}", base_url.trim_end_matches('/'))
+}
+
+// Add the bearer token used for every request to the API
+pub(crate) fn with_auth(
+    request_builder: reqwest::RequestBuilder,
+    api_key: Option<&String>,
+) -> reqwest::RequestBuilder {
+    match api_key {
+        Some(api_key) => {
+            debug!("Adding API key to request headers");
+            request_builder.header("Authorization", format!("Bearer {api_key}"))
+        }
+        None => {
+            debug!("No API key provided - this may cause authentication errors if the API requires authentication");
+            request_builder
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn stream_response(
     client: &Client,
     base_url: &str,
@@ -520,6 +1641,8 @@ async fn stream_response(
     request: ChatCompletionRequest,
     first_chunk_timeout_secs: u64,
     show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
 ) -> Result<()> {
     stream_response_collect(
         client,
@@ -529,11 +1652,15 @@ async fn stream_response(
         first_chunk_timeout_secs,
         true,
         show_progress,
+        None,
+        cache,
+        exchange_log,
     )
     .await?;
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn stream_response_collect(
     client: &Client,
     base_url: &str,
@@ -542,24 +1669,70 @@ async fn stream_response_collect(
     first_chunk_timeout_secs: u64,
     print_output: bool,
     show_progress: bool,
+    progress: Option<Progress>,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
 ) -> Result<String> {
-    // Construct the full URL
-    let url = if base_url.ends_with('/') {
-        format!("{}/chat/completions", base_url.trim_end_matches('/'))
-    } else {
-        format!("{base_url}/chat/completions")
+    // Replay an identical earlier response from the cache if we have one
+    let cache_key = match cache {
+        Some(_) => Some(ResponseCache::key(base_url, &request)?),
+        None => None,
     };
+    if let (Some(cache), Some(key)) = (cache, cache_key.as_deref()) {
+        if let Some(cached_body) = cache.get(key) {
+            info!("Replaying cached response");
+            let mut lines = SseLines::default();
+            lines.push(cached_body.as_bytes());
+            let mut collected_output = String::new();
+            finish_sse_lines(&mut lines, &mut collected_output, print_output)?;
+            return Ok(collected_output);
+        }
+    }
+
+    // Serve the exchange from a recording instead of the network
+    if let Some(ExchangeLog::Replay(replayer)) = exchange_log {
+        let exchange = replayer.take(&request)?;
+        info!(
+            "Replaying recorded exchange ({} chunks)",
+            exchange.chunks.len()
+        );
+        if !(200..300).contains(&exchange.status) {
+            return Err(anyhow::anyhow!(
+                "API request failed with status {}: {}",
+                exchange.status,
+                exchange.body()
+            ));
+        }
+        // Replay the stream with the same pacing it was recorded with
+        let mut lines = SseLines::default();
+        let mut collected_output = String::new();
+        for chunk in &exchange.chunks {
+            if chunk.delay_ms > 0 {
+                tokio::time::sleep(std::time::Duration::from_millis(chunk.delay_ms)).await;
+            }
+            lines.push(&chunk.bytes()?);
+            process_sse_lines(&mut lines, &mut collected_output, print_output)?;
+        }
+        finish_sse_lines(&mut lines, &mut collected_output, print_output)?;
+        return Ok(collected_output);
+    }
+
+    // Construct the full URL
+    let url = api_url(base_url, "chat/completions");
     debug!("API endpoint: {url}");
 
-    // Add API key to headers if provided
-    let mut request_builder = client.post(&url).json(&request);
+    // Capture the raw exchange when --record is active
+    let recorder = match exchange_log {
+        Some(ExchangeLog::Record(recorder)) => Some(recorder),
+        _ => None,
+    };
+    let mut recording = match recorder {
+        Some(_) => Some(RecordedExchange::new(&url, &request)?),
+        None => None,
+    };
 
-    if let Some(api_key) = api_key {
-        debug!("Adding API key to request headers");
-        request_builder = request_builder.header("Authorization", format!("Bearer {api_key}"));
-    } else {
-        debug!("No API key provided - this may cause authentication errors if the API requires authentication");
-    }
+    // Add API key to headers if provided
+    let request_builder = with_auth(client.post(&url).json(&request), api_key);
 
     // Enhanced logging for debugging - log full request details
     trace!("=== SERVICE CALL DETAILS ===");
@@ -586,7 +1759,11 @@ async fn stream_response_collect(
     // It is created here (after building the request but before sending) so
     // that it is visible during both the network round-trip and the wait for
     // the first streaming chunk.
-    let mut spinner = Spinner::new("Thinking...", show_progress);
+    let mut spinner = match progress {
+        Some(progress) => Spinner::with_progress(progress, show_progress),
+        None => Spinner::new("Thinking...", show_progress),
+    };
+    let mut last_chunk_at = std::time::Instant::now();
 
     let response = request_builder
         .send()
@@ -601,11 +1778,22 @@ async fn stream_response_collect(
         status.canonical_reason().unwrap_or("Unknown")
     );
 
+    if let Some(recording) = recording.as_mut() {
+        recording.status = status.as_u16();
+    }
+
     if !status.is_success() {
         let error_body = response
             .text()
             .await
             .unwrap_or_else(|_| "Unable to read error response body".to_string());
+        if let (Some(recorder), Some(mut recording)) = (recorder, recording) {
+            recording.chunks.push(RecordedChunk::new(
+                last_chunk_at.elapsed().as_millis() as u64,
+                error_body.as_bytes(),
+            ));
+            recorder.save(&recording)?;
+        }
         return Err(anyhow::anyhow!(
             "API request failed with status {}: {}",
             status.as_u16(),
@@ -616,8 +1804,10 @@ async fn stream_response_collect(
     debug!("API connection successful, starting to stream response");
     let mut stream = response.bytes_stream();
 
-    let mut incomplete = String::new();
+    let mut lines = SseLines::default();
     let mut collected_output = String::new();
+    // Raw SSE body, kept only when the response will be written to the cache
+    let mut raw_body = cache.map(|_| Vec::new());
     let mut chunk_count = 0;
 
     info!("Starting to stream response");
@@ -633,14 +1823,26 @@ async fn stream_response_collect(
         chunk_count += 1;
         let chunk =
             first_chunk_result.with_context(|| String::from("Failed to read response chunk"))?;
-        let text = std::str::from_utf8(&chunk)
-            .with_context(|| String::from("Failed to decode response as UTF-8"))?;
         debug!("Received chunk {}: {} bytes", chunk_count, chunk.len());
-        trace!("Chunk {chunk_count} content: {text:?}");
+        trace!(
+            "Chunk {chunk_count} content: {:?}",
+            String::from_utf8_lossy(&chunk)
+        );
         // The server has started responding — clear the spinner so that the
         // streamed output is not mixed with the spinner on the terminal.
         spinner.finish_and_clear();
-        incomplete.push_str(text);
+        if let Some(raw_body) = raw_body.as_mut() {
+            raw_body.extend_from_slice(&chunk);
+        }
+        if let Some(recording) = recording.as_mut() {
+            recording.chunks.push(RecordedChunk::new(
+                last_chunk_at.elapsed().as_millis() as u64,
+                &chunk,
+            ));
+            last_chunk_at = std::time::Instant::now();
+        }
+        lines.push(&chunk);
+        process_sse_lines(&mut lines, &mut collected_output, print_output)?;
     } else {
         return Err(anyhow::anyhow!("Stream ended before any data was received"));
     }
@@ -649,53 +1851,95 @@ async fn stream_response_collect(
     while let Some(chunk) = stream.next().await {
         chunk_count += 1;
         let chunk = chunk.with_context(|| String::from("Failed to read response chunk"))?;
-        let text = std::str::from_utf8(&chunk)
-            .with_context(|| String::from("Failed to decode response as UTF-8"))?;
 
         debug!("Received chunk {}: {} bytes", chunk_count, chunk.len());
-        trace!("Chunk {chunk_count} content: {text:?}");
-        incomplete.push_str(text);
-
-        // Process complete lines only
-        while let Some(pos) = incomplete.find('\n') {
-            let line = incomplete[..pos].trim();
-            if line.starts_with("data: ") && !line.starts_with("data: [DONE]") {
-                let data = &line[6..];
-                if !data.is_empty() {
-                    match serde_json::from_str::<ChatCompletionResponse>(data) {
-                        Ok(response) => {
-                            for choice in &response.choices {
-                                if let Some(content) = choice.delta.content.as_ref() {
-                                    collected_output.push_str(content);
-                                    if print_output {
-                                        print!("{content}");
-                                        io::stdout().flush()?;
-                                    }
+        trace!(
+            "Chunk {chunk_count} content: {:?}",
+            String::from_utf8_lossy(&chunk)
+        );
+        if let Some(raw_body) = raw_body.as_mut() {
+            raw_body.extend_from_slice(&chunk);
+        }
+        if let Some(recording) = recording.as_mut() {
+            recording.chunks.push(RecordedChunk::new(
+                last_chunk_at.elapsed().as_millis() as u64,
+                &chunk,
+            ));
+            last_chunk_at = std::time::Instant::now();
+        }
+        lines.push(&chunk);
+        process_sse_lines(&mut lines, &mut collected_output, print_output)?;
+    }
+
+    info!("Streaming completed after {chunk_count} chunks");
+    if lines.has_partial_line() {
+        debug!(
+            "Remaining incomplete data: {}",
+            String::from_utf8_lossy(&lines.take_rest())
+        );
+    }
+
+    if let (Some(cache), Some(key), Some(raw_body)) = (cache, cache_key.as_deref(), raw_body) {
+        match String::from_utf8(raw_body) {
+            Ok(raw_body) => cache.put(key, &raw_body),
+            Err(_) => debug!("Response ends inside a character, not caching"),
+        }
+    }
+    if let (Some(recorder), Some(recording)) = (recorder, recording) {
+        recorder.save(&recording)?;
+    }
+    Ok(collected_output)
+}
+
+// Parse every complete SSE line in `lines`, appending content deltas to
+// `collected_output` and leaving any trailing partial line in the buffer.
+fn process_sse_lines(
+    lines: &mut SseLines,
+    collected_output: &mut String,
+    print_output: bool,
+) -> Result<()> {
+    while let Some(line) = lines.next_line() {
+        let line = line.context("Failed to decode response as UTF-8")?;
+        let line = line.trim();
+        if line.starts_with("data: ") && !line.starts_with("data: [DONE]") {
+            let data = &line[6..];
+            if !data.is_empty() {
+                match serde_json::from_str::<ChatCompletionResponse>(data) {
+                    Ok(response) => {
+                        for choice in &response.choices {
+                            if let Some(content) = choice.delta.content.as_ref() {
+                                collected_output.push_str(content);
+                                if print_output {
+                                    print!("{content}");
+                                    io::stdout().flush()?;
                                 }
                             }
                         }
-                        Err(e) => {
-                            debug!("Failed to parse JSON response: {e}");
-                            debug!("Raw data: {data}");
-                        }
+                    }
+                    Err(e) => {
+                        debug!("Failed to parse JSON response: {e}");
+                        debug!("Raw data: {data}");
                     }
                 }
-            } else if line.starts_with("data: [DONE]") {
-                debug!("Received end-of-stream marker");
             }
-            incomplete = incomplete[pos + 1..].to_string();
+        } else if line.starts_with("data: [DONE]") {
+            debug!("Received end-of-stream marker");
         }
     }
+    Ok(())
+}
 
-    info!("Streaming completed after {chunk_count} chunks");
-    debug!(
-        "Final incomplete buffer length: {} characters",
-        incomplete.len()
-    );
-    if !incomplete.is_empty() {
-        debug!("Remaining incomplete data: {incomplete}");
+// Parse what is left of a stored body, including a last line that has no
+// newline
+fn finish_sse_lines(
+    lines: &mut SseLines,
+    collected_output: &mut String,
+    print_output: bool,
+) -> Result<()> {
+    if lines.has_partial_line() {
+        lines.push(b"\n");
     }
-    Ok(collected_output)
+    process_sse_lines(lines, collected_output, print_output)
 }
 
 #[cfg(test)]
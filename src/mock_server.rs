@@ -0,0 +1,311 @@
+use crate::http_server::{
+    read_request, write_json_error, write_response, write_stream_head, HttpRequest,
+};
+use crate::ChatMessage;
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use serde::Deserialize;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+/// Run a local OpenAI-compatible server with scripted or echo responses
+#[derive(clap::Args, Debug, Clone, Default)]
+pub(crate) struct MockServerArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub(crate) host: String,
+
+    /// Port to listen on (0 picks a free port)
+    #[arg(long, default_value_t = 8089)]
+    pub(crate) port: u16,
+
+    /// Scripted response text, served in rotation (repeatable). Without any,
+    /// the last user message is echoed back
+    #[arg(long = "response", value_name = "TEXT")]
+    pub(crate) responses: Vec<String>,
+
+    /// Model IDs reported by /v1/models
+    #[arg(long = "model-id", value_name = "ID", default_value = "mock-model")]
+    pub(crate) model_ids: Vec<String>,
+
+    /// Delay before the response headers are sent
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub(crate) latency_ms: u64,
+
+    /// Delay between streamed chunks
+    #[arg(long, value_name = "MS", default_value_t = 0)]
+    pub(crate) chunk_delay_ms: u64,
+
+    /// Maximum characters of content per streamed chunk
+    #[arg(long, value_name = "CHARS", default_value_t = 16)]
+    pub(crate) chunk_chars: usize,
+
+    /// Answer every Nth completion request with HTTP 500
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) fail_every: usize,
+
+    /// Answer every Nth completion request with HTTP 429
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) rate_limit_every: usize,
+
+    /// Insert a malformed SSE event into every Nth streamed response
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub(crate) malformed_every: usize,
+
+    /// Answer completion requests for models not given with --model-id
+    /// with HTTP 404
+    #[arg(long)]
+    pub(crate) reject_unknown_models: bool,
+}
+
+#[derive(Deserialize)]
+struct IncomingChatRequest {
+    #[serde(default)]
+    model: Option<String>,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+}
+
+struct MockState {
+    settings: MockServerArgs,
+    request_count: AtomicUsize,
+    /// Completion requests being answered right now, and the most at once.
+    active_requests: AtomicUsize,
+    max_active_requests: AtomicUsize,
+}
+
+// Counts a completion request as active until it is answered
+struct ActiveRequest<'a>(&'a MockState);
+
+impl<'a> ActiveRequest<'a> {
+    fn start(state: &'a MockState) -> Self {
+        let active = state.active_requests.fetch_add(1, Ordering::SeqCst) + 1;
+        state
+            .max_active_requests
+            .fetch_max(active, Ordering::SeqCst);
+        Self(state)
+    }
+}
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.active_requests.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Bind the listener and serve requests until the process is stopped.
+pub(crate) async fn run(settings: &MockServerArgs) -> Result<()> {
+    let listener = TcpListener::bind((settings.host.as_str(), settings.port))
+        .await
+        .with_context(|| format!("Failed to bind {}:{}", settings.host, settings.port))?;
+    let addr = listener.local_addr()?;
+    println!("Mock server listening on http://{addr}/v1");
+    serve(listener, settings.clone()).await
+}
+
+/// Serve requests from an already bound listener.
+pub(crate) async fn serve(listener: TcpListener, settings: MockServerArgs) -> Result<()> {
+    let state = Arc::new(MockState {
+        settings,
+        request_count: AtomicUsize::new(0),
+        active_requests: AtomicUsize::new(0),
+        max_active_requests: AtomicUsize::new(0),
+    });
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&mut stream, &state).await {
+                warn!("Mock server error for {peer}: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: &mut TcpStream, state: &MockState) -> Result<()> {
+    let Some(request) = read_request(stream).await? else {
+        return Ok(());
+    };
+    info!("{} {}", request.method, request.path);
+
+    match (request.method.as_str(), request.route()) {
+        ("GET", "/v1/models") | ("GET", "/models") => {
+            let data: Vec<_> = state
+                .settings
+                .model_ids
+                .iter()
+                .map(|id| serde_json::json!({"id": id, "object": "model", "owned_by": "ai-cli"}))
+                .collect();
+            let body = serde_json::json!({"object": "list", "data": data});
+            write_response(
+                stream,
+                200,
+                "application/json",
+                &[],
+                body.to_string().as_bytes(),
+            )
+            .await
+        }
+        ("GET", "/v1/mock/stats") | ("GET", "/mock/stats") => {
+            let body = serde_json::json!({
+                "requests": state.request_count.load(Ordering::SeqCst),
+                "max_concurrent_requests": state.max_active_requests.load(Ordering::SeqCst),
+            });
+            write_response(
+                stream,
+                200,
+                "application/json",
+                &[],
+                body.to_string().as_bytes(),
+            )
+            .await
+        }
+        ("POST", "/v1/chat/completions") | ("POST", "/chat/completions") => {
+            handle_chat_completion(stream, state, &request).await
+        }
+        (_, "/v1/models" | "/models" | "/v1/chat/completions" | "/chat/completions") => {
+            write_json_error(stream, 405, "Method not allowed", &[]).await
+        }
+        _ => write_json_error(stream, 404, "Not found", &[]).await,
+    }
+}
+
+async fn handle_chat_completion(
+    stream: &mut TcpStream,
+    state: &MockState,
+    request: &HttpRequest,
+) -> Result<()> {
+    let count = state.request_count.fetch_add(1, Ordering::SeqCst) + 1;
+    let _active = ActiveRequest::start(state);
+    let settings = &state.settings;
+
+    let incoming: IncomingChatRequest = match serde_json::from_slice(&request.body) {
+        Ok(incoming) => incoming,
+        Err(e) => {
+            return write_json_error(stream, 400, &format!("Invalid request body: {e}"), &[]).await;
+        }
+    };
+
+    if settings.latency_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(settings.latency_ms)).await;
+    }
+
+    if is_nth(count, settings.rate_limit_every) {
+        debug!("Request {count}: simulating rate limit");
+        return write_json_error(
+            stream,
+            429,
+            "Rate limit reached (simulated)",
+            &[("Retry-After", "1".to_string())],
+        )
+        .await;
+    }
+    if is_nth(count, settings.fail_every) {
+        debug!("Request {count}: simulating server error");
+        return write_json_error(stream, 500, "Internal server error (simulated)", &[]).await;
+    }
+
+    let model = incoming
+        .model
+        .clone()
+        .unwrap_or_else(|| "mock-model".to_string());
+    if settings.reject_unknown_models && !settings.model_ids.contains(&model) {
+        debug!("Request {count}: unknown model {model}");
+        return write_json_error(
+            stream,
+            404,
+            &format!("The model '{model}' does not exist"),
+            &[],
+        )
+        .await;
+    }
+    let content = response_text(settings, &incoming, count);
+
+    if !incoming.stream {
+        let body = serde_json::json!({
+            "id": format!("mock-{count}"),
+            "object": "chat.completion",
+            "model": model,
+            "choices": [{
+                "index": 0,
+                "message": {"role": "assistant", "content": content},
+                "finish_reason": "stop",
+            }],
+        });
+        return write_response(
+            stream,
+            200,
+            "application/json",
+            &[],
+            body.to_string().as_bytes(),
+        )
+        .await;
+    }
+
+    write_stream_head(stream, 200, "text/event-stream").await?;
+    let malformed = is_nth(count, settings.malformed_every);
+    let pieces = split_chars(&content, settings.chunk_chars.max(1));
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 && settings.chunk_delay_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(settings.chunk_delay_ms)).await;
+        }
+        if malformed && i == pieces.len() / 2 {
+            stream
+                .write_all(b"data: {\"choices\": [{\"delta\":\n\n")
+                .await?;
+        }
+        let event = serde_json::json!({
+            "id": format!("mock-{count}"),
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{"index": 0, "delta": {"content": piece}}],
+        });
+        stream
+            .write_all(format!("data: {event}\n\n").as_bytes())
+            .await?;
+        stream.flush().await?;
+    }
+    stream.write_all(b"data: [DONE]\n\n").await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn is_nth(count: usize, every: usize) -> bool {
+    every > 0 && count.is_multiple_of(every)
+}
+
+// Scripted responses rotate per request; otherwise echo the last user message
+fn response_text(settings: &MockServerArgs, request: &IncomingChatRequest, count: usize) -> String {
+    if !settings.responses.is_empty() {
+        return settings.responses[(count - 1) % settings.responses.len()].clone();
+    }
+    request
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == "user")
+        .map(|message| message.content.clone())
+        .unwrap_or_default()
+}
+
+pub(crate) fn split_chars(text: &str, max_chars: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars
+        .chunks(max_chars)
+        .map(|piece| piece.iter().collect())
+        .collect()
+}
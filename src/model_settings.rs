@@ -0,0 +1,109 @@
+use crate::rate_limiter::CHARS_PER_TOKEN;
+use crate::tokenizer::TokenizerKind;
+use crate::{default_chunk_overlap_chars, default_chunk_size_chars, AppConfig};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Keys accepted in a `[models.<name>]` table.
+pub(crate) const MODEL_SETTINGS_KEYS: &[&str] = &[
+    "temperature",
+    "context_window",
+    "chunk_size_chars",
+    "base_url",
+    "api_key_env",
+    "tokenizer",
+];
+
+/// Defaults that apply whenever a particular model is selected.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub(crate) struct ModelSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    /// Context window in tokens; used to size chunks when `chunk_size_chars`
+    /// is not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) context_window: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chunk_size_chars: Option<usize>,
+    /// Endpoint of the provider serving this model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) base_url: Option<String>,
+    /// Environment variable holding the provider's API key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) api_key_env: Option<String>,
+    /// Tokenizer matching the model's vocabulary.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tokenizer: Option<TokenizerKind>,
+}
+
+/// Map an alias such as `fast` to the model name it stands for. Names that
+/// are not aliases are returned unchanged.
+pub(crate) fn resolve_model_alias(aliases: &BTreeMap<String, String>, model: &str) -> String {
+    match aliases.get(model) {
+        Some(resolved) => {
+            debug!("Model alias {model} resolves to {resolved}");
+            resolved.clone()
+        }
+        None => model.to_string(),
+    }
+}
+
+/// Chunk size that leaves half of the context window for the prompt
+/// template, the aggregation pass and the response.
+pub(crate) fn chunk_size_for_context(context_window: usize) -> usize {
+    context_window.saturating_mul(CHARS_PER_TOKEN) / 2
+}
+
+/// Apply the `[models.<name>]` defaults for `model` over the values from the
+/// config files. `set_in_files` tells whether the files set a key outright:
+/// a chunk size derived from `context_window` does not replace one that was.
+pub(crate) fn apply_model_settings(
+    config: &mut AppConfig,
+    model: &str,
+    set_in_files: &dyn Fn(&str) -> bool,
+) {
+    let Some(settings) = config.models.get(model).cloned() else {
+        return;
+    };
+    debug!("Applying model settings for {model}");
+
+    if let Some(temperature) = settings.temperature {
+        config.temperature = Some(temperature);
+    }
+    let chunk_size_chars = settings.chunk_size_chars.or_else(|| {
+        let context_window = settings.context_window?;
+        if set_in_files("chunk_size_chars") {
+            debug!("Keeping the configured chunk_size_chars over {model}'s context window");
+            return None;
+        }
+        let derived = chunk_size_for_context(context_window);
+        debug!(
+            "Derived chunk_size_chars={derived} from {model}'s context window of {context_window} tokens"
+        );
+        Some(derived)
+    });
+    if let Some(chunk_size_chars) = chunk_size_chars {
+        config.chunk_size_chars = chunk_size_chars;
+        // The default overlap suits the default chunk size; keep it in
+        // proportion so a small model's chunks are not all overlap
+        if !set_in_files("chunk_overlap_chars") {
+            config.chunk_overlap_chars = config
+                .chunk_overlap_chars
+                .min(chunk_size_chars * default_chunk_overlap_chars() / default_chunk_size_chars());
+        }
+    }
+    if let Some(base_url) = settings.base_url {
+        config.base_url = base_url;
+    }
+    if let Some(api_key_env) = settings.api_key_env {
+        // The model's provider needs its own key, not the one from the files
+        config.api_key = None;
+        config.api_key_command = None;
+        config.api_key_file = None;
+        config.api_key_env = Some(api_key_env);
+    }
+    if let Some(tokenizer) = settings.tokenizer {
+        config.tokenizer = tokenizer;
+    }
+}
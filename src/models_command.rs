@@ -0,0 +1,146 @@
+use crate::{api_url, with_auth, AppConfig};
+use anyhow::{Context, Result};
+use log::{debug, info};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// List the models served by the configured endpoint
+#[derive(clap::Args, Debug, Clone, Default)]
+pub(crate) struct ModelsArgs {
+    /// Print the model list as JSON
+    #[arg(long)]
+    pub(crate) json: bool,
+
+    /// Only verify that the configured model is served; exits non-zero if not
+    #[arg(long)]
+    pub(crate) check: bool,
+}
+
+/// One entry of the `GET /models` response.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) struct ModelInfo {
+    pub(crate) id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) owned_by: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ModelList {
+    data: Vec<ModelInfo>,
+}
+
+pub(crate) async fn run(settings: &ModelsArgs, config: &AppConfig, client: &Client) -> Result<()> {
+    let models = fetch_models(client, config).await?;
+
+    if settings.check {
+        check_model(&models, config)?;
+        println!(
+            "Model '{}' is available at {}",
+            config.model, config.base_url
+        );
+        return Ok(());
+    }
+
+    if settings.json {
+        println!("{}", serde_json::to_string_pretty(&models)?);
+    } else {
+        print!("{}", format_models(&models, config));
+    }
+    Ok(())
+}
+
+/// Fetch the model list with the same client, API key and timeout used for
+/// chat completions.
+pub(crate) async fn fetch_models(client: &Client, config: &AppConfig) -> Result<Vec<ModelInfo>> {
+    let url = api_url(&config.base_url, "models");
+    info!("Fetching models from {url}");
+    let response = with_auth(client.get(&url), config.api_key.as_ref())
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .send()
+        .await
+        .with_context(|| format!("Failed to send request to {url}"))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let error_body = response
+            .text()
+            .await
+            .unwrap_or_else(|_| "Unable to read error response body".to_string());
+        return Err(anyhow::anyhow!(
+            "Model list request failed with status {}: {}",
+            status.as_u16(),
+            error_body
+        ));
+    }
+
+    let list: ModelList = response
+        .json()
+        .await
+        .context("Failed to parse model list response")?;
+    debug!("Endpoint reports {} model(s)", list.data.len());
+    Ok(list.data)
+}
+
+/// Fail unless the configured model is in `models`.
+pub(crate) fn check_model(models: &[ModelInfo], config: &AppConfig) -> Result<()> {
+    if models.iter().any(|model| model.id == config.model) {
+        return Ok(());
+    }
+    let available: Vec<&str> = models.iter().map(|model| model.id.as_str()).collect();
+    Err(anyhow::anyhow!(
+        "Model '{}' is not available at {} (available: {})",
+        config.model,
+        config.base_url,
+        if available.is_empty() {
+            "none".to_string()
+        } else {
+            available.join(", ")
+        }
+    ))
+}
+
+/// Render the model list as a table, marking the configured model and
+/// listing any aliases that point at each model.
+pub(crate) fn format_models(models: &[ModelInfo], config: &AppConfig) -> String {
+    let rows: Vec<(String, String, String)> = models
+        .iter()
+        .map(|model| {
+            let marker = if model.id == config.model { "*" } else { " " };
+            let aliases: Vec<&str> = config
+                .aliases
+                .iter()
+                .filter(|(_, target)| **target == model.id)
+                .map(|(alias, _)| alias.as_str())
+                .collect();
+            (
+                format!("{marker} {}", model.id),
+                model.owned_by.clone().unwrap_or_default(),
+                aliases.join(", "),
+            )
+        })
+        .collect();
+
+    let id_width = rows
+        .iter()
+        .map(|(id, _, _)| id.chars().count())
+        .chain(["  ID".len()])
+        .max()
+        .unwrap_or_default();
+    let owner_width = rows
+        .iter()
+        .map(|(_, owner, _)| owner.chars().count())
+        .chain(["OWNED BY".len()])
+        .max()
+        .unwrap_or_default();
+
+    let mut output = format!(
+        "{:id_width$}  {:owner_width$}  ALIASES\n",
+        "  ID", "OWNED BY"
+    );
+    for (id, owner, aliases) in rows {
+        output.push_str(format!("{id:id_width$}  {owner:owner_width$}  {aliases}").trim_end());
+        output.push('\n');
+    }
+    output
+}
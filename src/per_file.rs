@@ -0,0 +1,306 @@
+use crate::aggregation::{render_aggregate_prompt, DEFAULT_AGGREGATE_PROMPT_TEMPLATE};
+use crate::chunk_processor::{
+    process_large_input, reduce_aggregate_inputs, should_use_chunked_mode,
+    stream_response_with_retries, truncate_chars, RequestTask,
+};
+use crate::rate_limiter::RateLimiter;
+use crate::recording::ExchangeLog;
+use crate::response_cache::ResponseCache;
+use crate::spinner::Spinner;
+use crate::{read_input, AppConfig, Args, ChatCompletionRequest, ChatMessage};
+use anyhow::{Context, Result};
+use futures::{
+    stream::{FuturesOrdered, StreamExt},
+    FutureExt,
+};
+use log::{debug, info};
+use reqwest::Client;
+use std::{
+    ffi::OsString,
+    fs,
+    future::Future,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// Run the prompt on each input file on its own. Every answer is printed
+/// under the name of its file, written to `--output-dir` if one is given,
+/// and with `aggregate_files` combined into one answer at the end.
+pub(crate) async fn process_files_separately(
+    args: &Args,
+    config: &AppConfig,
+    client: &Client,
+    show_progress: bool,
+    cache: Option<&ResponseCache>,
+    exchange_log: Option<&ExchangeLog>,
+) -> Result<()> {
+    info!("Processing {} file(s) separately", args.files.len());
+    let output_paths = match &args.output_dir {
+        Some(output_dir) => Some(
+            mirror_paths(&args.files)?
+                .into_iter()
+                .map(|relative_path| output_path(output_dir, &relative_path))
+                .collect::<Vec<_>>(),
+        ),
+        None => None,
+    };
+    let keep_answers = output_paths.is_some() || config.aggregate_files;
+    let mut outputs = FileOutputs {
+        files: &args.files,
+        output_paths,
+        aggregate_inputs: Vec::new(),
+        config,
+    };
+    // One limiter for every request, chunked files included, so the limits
+    // hold for the whole run
+    let mut rate_limiter = RateLimiter::new(config.requests_per_minute, config.tokens_per_minute);
+
+    // Requests for files that are still running, oldest first. Their answers
+    // are only printed once every earlier file is done.
+    let mut in_flight = FuturesOrdered::new();
+    for (file_index, file_path) in args.files.iter().enumerate() {
+        let mut file_args = args.clone();
+        file_args.files = vec![file_path.clone()];
+
+        if should_use_chunked_mode(&file_args, config)? {
+            // A chunked file prints its answers as they arrive, so the files
+            // before it have to be finished first
+            while !in_flight.is_empty() {
+                print_next_in_order(&mut in_flight, &mut outputs, show_progress).await?;
+            }
+            debug!("Processing {} in chunks", file_path.display());
+            print_label(file_path, file_index);
+            let answer = process_large_input(
+                &file_args,
+                config,
+                client,
+                show_progress,
+                cache,
+                exchange_log,
+                &mut rate_limiter,
+                keep_answers,
+            )
+            .await?;
+            outputs.record(file_index, answer.as_deref().unwrap_or_default())?;
+            continue;
+        }
+
+        // Name the file after the prompt, as chunks and aggregates do, so
+        // the model knows which file it is looking at
+        let prompt = file_args.prompt.take();
+        let input = read_input(&file_args).await?;
+        let file_line = format!("File: {}\n{input}", file_path.display());
+        let content = match prompt {
+            Some(prompt) => format!("Prompt: {prompt}\n{file_line}"),
+            None => file_line,
+        };
+        let request = ChatCompletionRequest {
+            model: config.model.clone(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content,
+            }],
+            stream: true,
+            temperature: config.temperature,
+        };
+        let label = format!("Request for {}", file_path.display());
+
+        if config.parallel_files > 1 {
+            if in_flight.len() >= config.parallel_files {
+                print_next_in_order(&mut in_flight, &mut outputs, show_progress).await?;
+            }
+            let request = RequestTask::spawn(
+                client,
+                config,
+                request,
+                label,
+                &rate_limiter,
+                cache,
+                exchange_log,
+            );
+            in_flight
+                .push_back(request.map(move |result| result.map(|answer| (file_index, answer))));
+        } else {
+            print_label(file_path, file_index);
+            let answer = stream_response_with_retries(
+                client,
+                config.base_url.as_str(),
+                config.api_key.as_ref(),
+                request,
+                config.timeout_secs,
+                label,
+                true,
+                show_progress,
+                None,
+                &mut rate_limiter,
+                cache,
+                exchange_log,
+            )
+            .await?;
+            println!();
+            outputs.record(file_index, &answer)?;
+        }
+    }
+    while !in_flight.is_empty() {
+        print_next_in_order(&mut in_flight, &mut outputs, show_progress).await?;
+    }
+
+    if config.aggregate_files && outputs.aggregate_inputs.len() > 1 {
+        let user_prompt = args
+            .prompt
+            .clone()
+            .or_else(|| config.default_prompt.clone())
+            .unwrap_or_else(|| "Process the files and provide useful output.".to_string());
+        let aggregate_template = if let Some(template_file) = &config.aggregate_prompt_file {
+            fs::read_to_string(template_file).with_context(|| {
+                format!(
+                    "Failed to read aggregate prompt template file: {}",
+                    template_file.display()
+                )
+            })?
+        } else {
+            DEFAULT_AGGREGATE_PROMPT_TEMPLATE.to_string()
+        };
+        let aggregate_inputs = reduce_aggregate_inputs(
+            args,
+            &aggregate_template,
+            &user_prompt,
+            outputs.aggregate_inputs,
+            &mut rate_limiter,
+            config,
+            client,
+            show_progress,
+            cache,
+            exchange_log,
+        )
+        .await?;
+
+        println!("\n==> All files <==");
+        let request = ChatCompletionRequest {
+            model: config.model.clone(),
+            messages: vec![ChatMessage {
+                role: "user".to_string(),
+                content: render_aggregate_prompt(
+                    &aggregate_template,
+                    &user_prompt,
+                    &aggregate_inputs,
+                ),
+            }],
+            stream: true,
+            temperature: config.temperature,
+        };
+        stream_response_with_retries(
+            client,
+            config.base_url.as_str(),
+            config.api_key.as_ref(),
+            request,
+            config.timeout_secs,
+            "Aggregate request".to_string(),
+            true,
+            show_progress,
+            None,
+            &mut rate_limiter,
+            cache,
+            exchange_log,
+        )
+        .await?;
+        println!();
+    }
+
+    Ok(())
+}
+
+// Where each file's answer goes once it is complete
+struct FileOutputs<'a> {
+    files: &'a [PathBuf],
+    output_paths: Option<Vec<PathBuf>>,
+    aggregate_inputs: Vec<String>,
+    config: &'a AppConfig,
+}
+
+impl FileOutputs<'_> {
+    fn record(&mut self, file_index: usize, answer: &str) -> Result<()> {
+        if let Some(output_paths) = &self.output_paths {
+            let output_path = &output_paths[file_index];
+            if let Some(parent) = output_path.parent() {
+                fs::create_dir_all(parent).with_context(|| {
+                    format!("Failed to create output directory: {}", parent.display())
+                })?;
+            }
+            fs::write(output_path, answer).with_context(|| {
+                format!("Failed to write output file: {}", output_path.display())
+            })?;
+            debug!("Wrote {}", output_path.display());
+        }
+        if self.config.aggregate_files {
+            self.aggregate_inputs.push(format!(
+                "File: {}\n{}",
+                self.files[file_index].display(),
+                truncate_chars(answer, self.config.aggregate_input_chars)
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Wait for the oldest running file request, then print and record its
+// answer. Requests yield the answer with the index of their file.
+async fn print_next_in_order<F>(
+    in_flight: &mut FuturesOrdered<F>,
+    outputs: &mut FileOutputs<'_>,
+    show_progress: bool,
+) -> Result<()>
+where
+    F: Future<Output = Result<(usize, String)>>,
+{
+    let mut spinner = Spinner::new("Thinking...", show_progress);
+    let (file_index, answer) = in_flight
+        .next()
+        .await
+        .context("No file request is running")??;
+    spinner.finish_and_clear();
+    print_label(&outputs.files[file_index], file_index);
+    println!("{answer}");
+    outputs.record(file_index, &answer)
+}
+
+// Header printed above the answer for a file, as `head` does for several
+fn print_label(file_path: &Path, file_index: usize) {
+    if file_index > 0 {
+        println!();
+    }
+    println!("==> {} <==", file_path.display());
+    let _ = io::stdout().flush();
+}
+
+/// Paths of `files` relative to the deepest directory that holds all of
+/// them, so the output directory mirrors the layout of the inputs.
+pub(crate) fn mirror_paths(files: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let files = files
+        .iter()
+        .map(|file| {
+            fs::canonicalize(file)
+                .with_context(|| format!("Failed to open file: {}", file.display()))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut base = files
+        .first()
+        .and_then(|file| file.parent())
+        .map(Path::to_path_buf)
+        .unwrap_or_default();
+    for file in &files {
+        while !file.starts_with(&base) && base.pop() {}
+    }
+    Ok(files
+        .iter()
+        .map(|file| file.strip_prefix(&base).unwrap_or(file).to_path_buf())
+        .collect())
+}
+
+/// Output file for the input at `relative_path`. The `.out` suffix keeps
+/// an output directory that overlaps the inputs from replacing them.
+pub(crate) fn output_path(output_dir: &Path, relative_path: &Path) -> PathBuf {
+    let mut file_name = OsString::from(relative_path.as_os_str());
+    file_name.push(".out");
+    output_dir.join(file_name)
+}
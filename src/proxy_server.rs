@@ -0,0 +1,424 @@
+use crate::http_server::{
+    read_request, write_json_error, write_response, write_stream_head, HttpRequest,
+};
+use crate::response_cache::ResponseCache;
+use crate::{api_url, with_auth, AppConfig};
+use anyhow::{Context, Result};
+use log::{debug, info, warn};
+use regex::Regex;
+use reqwest::Client;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+use tokio_stream::StreamExt;
+
+/// Retries for failed backend requests, matching chunked-mode retries.
+const MAX_RETRIES: u32 = 2;
+
+/// Expose an OpenAI-compatible endpoint that forwards to the configured backend
+#[derive(clap::Args, Debug, Clone)]
+pub(crate) struct ServeArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub(crate) host: String,
+
+    /// Port to listen on (0 picks a free port)
+    #[arg(long, default_value_t = 8080)]
+    pub(crate) port: u16,
+
+    /// Allow listening on an address other than loopback (requires a token)
+    #[arg(long)]
+    pub(crate) allow_remote: bool,
+
+    /// Token clients must send as `Authorization: Bearer <token>`
+    /// (default: $AI_CLI_SERVE_TOKEN)
+    #[arg(long)]
+    pub(crate) token: Option<String>,
+}
+
+/// Shared state for all proxy connections.
+pub(crate) struct ProxyState {
+    pub(crate) config: AppConfig,
+    pub(crate) client: Client,
+    pub(crate) cache: Option<ResponseCache>,
+    /// Token every client request must carry, if any.
+    pub(crate) token: Option<String>,
+    /// Compiled `redact_patterns`.
+    pub(crate) redactions: Vec<Regex>,
+}
+
+/// Bind the listener and forward requests until the process is stopped.
+pub(crate) async fn run(settings: &ServeArgs, mut state: ProxyState) -> Result<()> {
+    state.token = settings
+        .token
+        .clone()
+        .or_else(|| std::env::var("AI_CLI_SERVE_TOKEN").ok())
+        .filter(|token| !token.is_empty());
+    state.redactions = state
+        .config
+        .redact_patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .with_context(|| format!("Invalid redact_patterns entry '{pattern}'"))
+        })
+        .collect::<Result<_>>()?;
+
+    // Check every address the host resolves to before anything is bound
+    let addrs: Vec<_> = tokio::net::lookup_host((settings.host.as_str(), settings.port))
+        .await
+        .with_context(|| format!("Failed to resolve {}", settings.host))?
+        .collect();
+    for addr in &addrs {
+        check_exposure(addr.ip(), settings.allow_remote, state.token.is_some())?;
+    }
+    let listener = TcpListener::bind(addrs.as_slice())
+        .await
+        .with_context(|| format!("Failed to bind {}:{}", settings.host, settings.port))?;
+    let addr = listener.local_addr()?;
+    println!(
+        "Proxy listening on http://{addr}/v1 (forwarding to {})",
+        state.config.base_url
+    );
+    serve(listener, Arc::new(state)).await
+}
+
+/// Refuse to expose the proxy, and with it the configured API key, beyond
+/// this machine unless that was asked for and clients have to authenticate.
+pub(crate) fn check_exposure(ip: IpAddr, allow_remote: bool, has_token: bool) -> Result<()> {
+    if ip.is_loopback() {
+        return Ok(());
+    }
+    if !allow_remote {
+        return Err(anyhow::anyhow!(
+            "Refusing to listen on non-loopback address {ip}; pass --allow-remote and set a token with --token or AI_CLI_SERVE_TOKEN"
+        ));
+    }
+    if !has_token {
+        return Err(anyhow::anyhow!(
+            "Listening on {ip} requires a token; set one with --token or AI_CLI_SERVE_TOKEN"
+        ));
+    }
+    Ok(())
+}
+
+/// Forward requests from an already bound listener.
+pub(crate) async fn serve(listener: TcpListener, state: Arc<ProxyState>) -> Result<()> {
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(&mut stream, &state).await {
+                warn!("Proxy error for {peer}: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: &mut TcpStream, state: &ProxyState) -> Result<()> {
+    let Some(request) = read_request(stream).await? else {
+        return Ok(());
+    };
+    info!("{} {}", request.method, request.path);
+
+    if let Some(token) = &state.token {
+        if !is_authorized(&request, token) {
+            return write_json_error(
+                stream,
+                401,
+                "Missing or invalid proxy token",
+                &[("WWW-Authenticate", "Bearer".to_string())],
+            )
+            .await;
+        }
+    }
+
+    match (request.method.as_str(), request.route()) {
+        ("GET", "/v1/models") | ("GET", "/models") => forward_models(stream, state).await,
+        ("POST", "/v1/chat/completions") | ("POST", "/chat/completions") => {
+            forward_chat_completion(stream, state, &request).await
+        }
+        (_, "/v1/models" | "/models" | "/v1/chat/completions" | "/chat/completions") => {
+            write_json_error(stream, 405, "Method not allowed", &[]).await
+        }
+        _ => write_json_error(stream, 404, "Not found", &[]).await,
+    }
+}
+
+// Whether the request carries the proxy token. The comparison takes the
+// same time wherever the first difference is.
+fn is_authorized(request: &HttpRequest, token: &str) -> bool {
+    let Some(sent) = request
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+    sent.len() == token.len()
+        && sent
+            .bytes()
+            .zip(token.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Fill in the parts of a client request that ai-cli's config provides: the
+/// default model (or the model behind an alias), temperature and
+/// `system_prompt` as a system message.
+pub(crate) fn apply_config_defaults(body: &mut serde_json::Value, config: &AppConfig) {
+    let Some(object) = body.as_object_mut() else {
+        return;
+    };
+
+    let has_model = object
+        .get("model")
+        .and_then(|model| model.as_str())
+        .is_some_and(|model| !model.is_empty());
+    if !has_model {
+        object.insert("model".to_string(), config.model.clone().into());
+    } else if let Some(resolved) = object
+        .get("model")
+        .and_then(|model| model.as_str())
+        .and_then(|model| config.aliases.get(model))
+    {
+        object.insert("model".to_string(), resolved.clone().into());
+    }
+
+    if let Some(temperature) = config.temperature {
+        object
+            .entry("temperature")
+            .or_insert_with(|| temperature.into());
+    }
+
+    if let Some(system_prompt) = &config.system_prompt {
+        if let Some(messages) = object
+            .get_mut("messages")
+            .and_then(|messages| messages.as_array_mut())
+        {
+            let has_system = messages.iter().any(|message| message["role"] == "system");
+            if !has_system {
+                messages.insert(
+                    0,
+                    serde_json::json!({"role": "system", "content": system_prompt}),
+                );
+            }
+        }
+    }
+}
+
+/// Replace every match of the redaction patterns in the message contents,
+/// including the text parts of multi-part messages.
+pub(crate) fn redact_messages(body: &mut serde_json::Value, redactions: &[Regex]) {
+    if redactions.is_empty() {
+        return;
+    }
+    let Some(messages) = body
+        .get_mut("messages")
+        .and_then(|messages| messages.as_array_mut())
+    else {
+        return;
+    };
+    for message in messages {
+        match message.get_mut("content") {
+            Some(serde_json::Value::String(content)) => redact(content, redactions),
+            Some(serde_json::Value::Array(parts)) => {
+                for part in parts {
+                    if let Some(serde_json::Value::String(text)) = part.get_mut("text") {
+                        redact(text, redactions);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn redact(text: &mut String, redactions: &[Regex]) {
+    for redaction in redactions {
+        if redaction.is_match(text) {
+            *text = redaction.replace_all(text, "[REDACTED]").into_owned();
+        }
+    }
+}
+
+/// Models to try for a request: the requested one, then `fallback_models`
+/// with aliases resolved, skipping repeats.
+pub(crate) fn candidate_models(requested: &str, config: &AppConfig) -> Vec<String> {
+    let mut models = vec![requested.to_string()];
+    for fallback in &config.fallback_models {
+        let model = config.aliases.get(fallback).unwrap_or(fallback);
+        if !models.contains(model) {
+            models.push(model.clone());
+        }
+    }
+    models
+}
+
+async fn forward_models(stream: &mut TcpStream, state: &ProxyState) -> Result<()> {
+    let url = api_url(&state.config.base_url, "models");
+    let request_builder = with_auth(state.client.get(&url), state.config.api_key.as_ref());
+
+    match request_builder.send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            let body = response.bytes().await.unwrap_or_default();
+            write_response(stream, status, "application/json", &[], &body).await
+        }
+        Err(e) => write_json_error(stream, 502, &format!("Backend request failed: {e}"), &[]).await,
+    }
+}
+
+async fn forward_chat_completion(
+    stream: &mut TcpStream,
+    state: &ProxyState,
+    request: &HttpRequest,
+) -> Result<()> {
+    let mut body: serde_json::Value = match serde_json::from_slice(&request.body) {
+        Ok(body) => body,
+        Err(e) => {
+            return write_json_error(stream, 400, &format!("Invalid request body: {e}"), &[]).await;
+        }
+    };
+    apply_config_defaults(&mut body, &state.config);
+    redact_messages(&mut body, &state.redactions);
+    let streaming = body["stream"].as_bool().unwrap_or(false);
+    let content_type = if streaming {
+        "text/event-stream"
+    } else {
+        "application/json"
+    };
+
+    let cache_key = match &state.cache {
+        Some(_) => Some(ResponseCache::key(&state.config.base_url, &body)?),
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (&state.cache, cache_key.as_deref()) {
+        if let Some(cached_body) = cache.get(key) {
+            debug!("Serving cached response");
+            return write_response(stream, 200, content_type, &[], cached_body.as_bytes()).await;
+        }
+    }
+
+    // Fall back to the next model once retries for one are used up. Only
+    // answers from the requested model are cached under the request.
+    let requested_model = body["model"].as_str().unwrap_or_default().to_string();
+    let models = candidate_models(&requested_model, &state.config);
+    let mut response = None;
+    for (attempt, model) in models.iter().enumerate() {
+        let is_last = attempt + 1 == models.len();
+        body["model"] = model.clone().into();
+        let failure = match send_with_retries(state, &body).await {
+            Ok(sent) if is_last || !should_fall_back(sent.status()) => {
+                response = Some(sent);
+                break;
+            }
+            Ok(sent) => format!("status {}", sent.status().as_u16()),
+            Err(e) if is_last => {
+                return write_json_error(
+                    stream,
+                    502,
+                    &format!("Backend request failed: {e:#}"),
+                    &[],
+                )
+                .await;
+            }
+            Err(e) => format!("{e:#}"),
+        };
+        warn!(
+            "Model {model} failed ({failure}), falling back to {}",
+            models[attempt + 1]
+        );
+    }
+    let Some(response) = response else {
+        return write_json_error(stream, 502, "No model to send the request to", &[]).await;
+    };
+    let cache_key = cache_key.filter(|_| body["model"] == requested_model.as_str());
+
+    let status = response.status().as_u16();
+    let success = response.status().is_success();
+    if !success || !streaming {
+        let response_body = response.bytes().await.unwrap_or_default();
+        if !success {
+            // Pass backend errors through unchanged
+            return write_response(stream, status, "application/json", &[], &response_body).await;
+        }
+        if let (Some(cache), Some(key)) = (&state.cache, cache_key.as_deref()) {
+            cache.put(key, &String::from_utf8_lossy(&response_body));
+        }
+        return write_response(stream, status, content_type, &[], &response_body).await;
+    }
+
+    // Pass the event stream through as it arrives
+    write_stream_head(stream, status, content_type).await?;
+    let mut raw_body = state.cache.as_ref().map(|_| Vec::new());
+    let mut backend_stream = response.bytes_stream();
+    while let Some(chunk) = backend_stream.next().await {
+        let chunk = chunk.context("Failed to read backend response chunk")?;
+        if let Some(raw_body) = raw_body.as_mut() {
+            raw_body.extend_from_slice(&chunk);
+        }
+        stream.write_all(&chunk).await?;
+        stream.flush().await?;
+    }
+
+    if let (Some(cache), Some(key), Some(raw_body)) = (&state.cache, cache_key.as_deref(), raw_body)
+    {
+        cache.put(key, &String::from_utf8_lossy(&raw_body));
+    }
+    Ok(())
+}
+
+// Errors another model may not have: rate limits, server errors and
+// unknown models
+fn should_fall_back(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || matches!(status.as_u16(), 404 | 429)
+}
+
+// Send the request, retrying connection failures, 429s and 5xx responses
+async fn send_with_retries(
+    state: &ProxyState,
+    body: &serde_json::Value,
+) -> Result<reqwest::Response> {
+    let url = api_url(&state.config.base_url, "chat/completions");
+    let mut backoff_secs = 1u64;
+
+    for attempt in 0..=MAX_RETRIES {
+        let request_builder = with_auth(
+            state.client.post(&url).json(body),
+            state.config.api_key.as_ref(),
+        );
+
+        // Like the CLI, the timeout only covers the wait for the response to start
+        let sent = tokio::time::timeout(
+            Duration::from_secs(state.config.timeout_secs),
+            request_builder.send(),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out waiting for the backend"))
+        .and_then(|result| result.map_err(anyhow::Error::from));
+        let retryable = match sent {
+            Ok(response) => {
+                let status = response.status();
+                if attempt == MAX_RETRIES || !(status.is_server_error() || status.as_u16() == 429) {
+                    return Ok(response);
+                }
+                format!("status {}", status.as_u16())
+            }
+            Err(e) if attempt == MAX_RETRIES => return Err(e),
+            Err(e) => e.to_string(),
+        };
+
+        debug!(
+            "Backend attempt {} failed, retrying in {}s: {}",
+            attempt + 1,
+            backoff_secs,
+            retryable
+        );
+        tokio::time::sleep(Duration::from_secs(backoff_secs)).await;
+        backoff_secs = backoff_secs.saturating_mul(2);
+    }
+
+    Err(anyhow::anyhow!("Unexpected retry loop exit"))
+}
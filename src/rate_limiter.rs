@@ -0,0 +1,170 @@
+use crate::spinner::Spinner;
+use log::debug;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Rough characters-per-token ratio used to estimate request cost when no
+/// tokenizer is available.
+pub(crate) const CHARS_PER_TOKEN: usize = 4;
+
+/// Estimate the number of tokens in `text` using a simple character heuristic.
+pub(crate) fn estimate_tokens(text: &str) -> u64 {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN) as u64
+}
+
+/// A token bucket that refills continuously at `capacity` units per minute.
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    available: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn per_minute(capacity: u32, now: Instant) -> Self {
+        let capacity = f64::from(capacity);
+        Self {
+            capacity,
+            available: capacity,
+            refill_per_sec: capacity / 60.0,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.available = (self.available + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Time to wait before `cost` units are available. A cost larger than the
+    /// bucket is clamped to its capacity so oversized requests cannot block forever.
+    pub(crate) fn wait_time(&mut self, cost: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let cost = cost.min(self.capacity);
+        if self.available >= cost {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((cost - self.available) / self.refill_per_sec)
+        }
+    }
+
+    pub(crate) fn consume(&mut self, cost: f64, now: Instant) {
+        self.refill(now);
+        self.available -= cost.min(self.capacity);
+    }
+}
+
+/// Client-side limiter for requests and estimated tokens per minute.
+///
+/// A limit of `0` disables the corresponding bucket. Clones share their
+/// buckets, so requests running at the same time draw on the same limits.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    requests: Option<TokenBucket>,
+    tokens: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn wait_time(&mut self, token_cost: u64, now: Instant) -> Duration {
+        let request_wait = self
+            .requests
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.wait_time(1.0, now));
+        let token_wait = self.tokens.as_mut().map_or(Duration::ZERO, |bucket| {
+            bucket.wait_time(token_cost as f64, now)
+        });
+        request_wait.max(token_wait)
+    }
+
+    fn consume(&mut self, token_cost: u64, now: Instant) {
+        if let Some(bucket) = self.requests.as_mut() {
+            bucket.consume(1.0, now);
+        }
+        if let Some(bucket) = self.tokens.as_mut() {
+            bucket.consume(token_cost as f64, now);
+        }
+    }
+}
+
+impl RateLimiter {
+    pub(crate) fn new(requests_per_minute: u32, tokens_per_minute: u32) -> Self {
+        let now = Instant::now();
+        let buckets = Buckets {
+            requests: (requests_per_minute > 0)
+                .then(|| TokenBucket::per_minute(requests_per_minute, now)),
+            tokens: (tokens_per_minute > 0)
+                .then(|| TokenBucket::per_minute(tokens_per_minute, now)),
+        };
+        Self {
+            buckets: Arc::new(Mutex::new(buckets)),
+        }
+    }
+
+    // A panic while the lock was held cannot leave the buckets inconsistent
+    fn buckets(&self) -> std::sync::MutexGuard<'_, Buckets> {
+        self.buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        let buckets = self.buckets();
+        buckets.requests.is_some() || buckets.tokens.is_some()
+    }
+
+    /// Time to wait before a request costing `token_cost` tokens may be sent.
+    #[cfg(test)]
+    pub(crate) fn wait_time(&mut self, token_cost: u64, now: Instant) -> Duration {
+        self.buckets().wait_time(token_cost, now)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn consume(&mut self, token_cost: u64, now: Instant) {
+        self.buckets().consume(token_cost, now);
+    }
+
+    /// Wait until a request costing `token_cost` tokens fits within the
+    /// configured limits. The request is reserved before waiting, so
+    /// requests that acquire at the same time queue up behind each other
+    /// instead of all being let through once the wait is over. While
+    /// waiting, the spinner shows that the run is held back by the limiter
+    /// rather than the server.
+    pub(crate) async fn acquire(&mut self, token_cost: u64, show_progress: bool) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let wait = {
+            let mut buckets = self.buckets();
+            let now = Instant::now();
+            let wait = buckets.wait_time(token_cost, now);
+            buckets.consume(token_cost, now);
+            wait
+        };
+        if !wait.is_zero() {
+            debug!(
+                "Rate limiter delaying request by {:.1}s ({} estimated tokens)",
+                wait.as_secs_f64(),
+                token_cost
+            );
+            let mut spinner = Spinner::new(
+                &format!(
+                    "Waiting for rate limit ({}s)...",
+                    wait.as_secs_f64().ceil() as u64
+                ),
+                show_progress,
+            );
+            tokio::time::sleep(wait).await;
+            spinner.finish_and_clear();
+        }
+    }
+}
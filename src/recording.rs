@@ -0,0 +1,197 @@
+use crate::ChatCompletionRequest;
+use anyhow::{Context, Result};
+use base64::Engine;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/// One raw chunk of a response body and the delay before it arrived.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub(crate) struct RecordedChunk {
+    /// Milliseconds since the previous chunk (or since the request was sent).
+    pub(crate) delay_ms: u64,
+    /// The chunk's bytes, when they are valid UTF-8 on their own.
+    pub(crate) data: String,
+    /// The chunk's bytes in base64 otherwise, as when the chunk ends inside
+    /// a multibyte character. `data` is then empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) data_base64: Option<String>,
+}
+
+impl RecordedChunk {
+    pub(crate) fn new(delay_ms: u64, bytes: &[u8]) -> Self {
+        match std::str::from_utf8(bytes) {
+            Ok(data) => Self {
+                delay_ms,
+                data: data.to_string(),
+                data_base64: None,
+            },
+            Err(_) => Self {
+                delay_ms,
+                data: String::new(),
+                data_base64: Some(base64::engine::general_purpose::STANDARD.encode(bytes)),
+            },
+        }
+    }
+
+    /// The bytes of the chunk exactly as they arrived.
+    pub(crate) fn bytes(&self) -> Result<Vec<u8>> {
+        match &self.data_base64 {
+            Some(encoded) => base64::engine::general_purpose::STANDARD
+                .decode(encoded)
+                .context("Invalid base64 in recorded chunk"),
+            None => Ok(self.data.as_bytes().to_vec()),
+        }
+    }
+}
+
+/// A single HTTP exchange with the chat completions endpoint.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RecordedExchange {
+    pub(crate) url: String,
+    pub(crate) request: serde_json::Value,
+    pub(crate) status: u16,
+    pub(crate) chunks: Vec<RecordedChunk>,
+}
+
+impl RecordedExchange {
+    pub(crate) fn new(url: &str, request: &ChatCompletionRequest) -> Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            request: serde_json::to_value(request)
+                .context("Failed to serialize request for recording")?,
+            status: 0,
+            chunks: Vec::new(),
+        })
+    }
+
+    /// The full response body as it was received, as text.
+    pub(crate) fn body(&self) -> String {
+        let bytes: Vec<u8> = self
+            .chunks
+            .iter()
+            .flat_map(|chunk| chunk.bytes().unwrap_or_default())
+            .collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+/// Record or replay raw HTTP exchanges (`--record` / `--replay`). Clones
+/// share the numbering of recordings and the replay state.
+#[derive(Clone)]
+pub(crate) enum ExchangeLog {
+    Record(Recorder),
+    Replay(Replayer),
+}
+
+/// Writes every exchange to its own numbered JSON file in a directory.
+#[derive(Clone)]
+pub(crate) struct Recorder {
+    dir: PathBuf,
+    next_index: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    pub(crate) fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create recording directory: {}", dir.display()))?;
+        // Continue numbering after the highest exchange already in the
+        // directory, which need not be their count if some were deleted.
+        let next_index = exchange_files(&dir)?
+            .iter()
+            .filter_map(|path| path.file_stem()?.to_str()?.parse::<usize>().ok())
+            .max()
+            .unwrap_or(0)
+            + 1;
+        Ok(Self {
+            dir,
+            next_index: Arc::new(AtomicUsize::new(next_index)),
+        })
+    }
+
+    pub(crate) fn save(&self, exchange: &RecordedExchange) -> Result<()> {
+        let index = self.next_index.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{index:04}.json"));
+        let contents = serde_json::to_string_pretty(exchange)
+            .context("Failed to serialize recorded exchange")?;
+        fs::write(&path, contents)
+            .with_context(|| format!("Failed to write recording: {}", path.display()))?;
+        debug!("Recorded exchange to {}", path.display());
+        Ok(())
+    }
+}
+
+/// Serves recorded exchanges back in place of the network.
+///
+/// Requests are matched by their JSON body. Identical requests are served in
+/// recording order; once exhausted the last matching exchange is repeated so
+/// retries still get an answer.
+#[derive(Clone)]
+pub(crate) struct Replayer {
+    exchanges: Arc<Mutex<Vec<(RecordedExchange, bool)>>>,
+}
+
+impl Replayer {
+    pub(crate) fn new(dir: &Path) -> Result<Self> {
+        let mut exchanges = Vec::new();
+        for path in exchange_files(dir)? {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read recording: {}", path.display()))?;
+            let exchange: RecordedExchange = serde_json::from_str(&contents)
+                .with_context(|| format!("Failed to parse recording: {}", path.display()))?;
+            exchanges.push((exchange, false));
+        }
+        debug!(
+            "Loaded {} recorded exchange(s) from {}",
+            exchanges.len(),
+            dir.display()
+        );
+        Ok(Self {
+            exchanges: Arc::new(Mutex::new(exchanges)),
+        })
+    }
+
+    pub(crate) fn take(&self, request: &ChatCompletionRequest) -> Result<RecordedExchange> {
+        let request =
+            serde_json::to_value(request).context("Failed to serialize request for replay")?;
+        let mut exchanges = self
+            .exchanges
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Replay state was poisoned"))?;
+
+        let mut last_match = None;
+        for (exchange, used) in exchanges.iter_mut() {
+            if exchange.request != request {
+                continue;
+            }
+            if !*used {
+                *used = true;
+                return Ok(exchange.clone());
+            }
+            last_match = Some(exchange.clone());
+        }
+        last_match.ok_or_else(|| anyhow::anyhow!("No recorded exchange matches this request"))
+    }
+}
+
+// Recording files in name order, which is also recording order
+fn exchange_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)
+        .with_context(|| format!("Failed to read recording directory: {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) == Some("json") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
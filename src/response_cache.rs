@@ -0,0 +1,212 @@
+use crate::AppConfig;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const CACHE_FILE_EXTENSION: &str = "sse";
+
+/// On-disk cache of raw streaming responses keyed by request hash.
+///
+/// Each entry stores the exact SSE body returned by the server so a cache hit
+/// can be replayed through the normal streaming parser.
+#[derive(Clone)]
+pub(crate) struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    max_bytes: u64,
+    /// Skip lookups but still store fresh responses (`--refresh-cache`).
+    refresh: bool,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(dir: PathBuf, ttl: Duration, max_bytes: u64, refresh: bool) -> Self {
+        Self {
+            dir,
+            ttl,
+            max_bytes,
+            refresh,
+        }
+    }
+
+    /// Build the cache described by `config`, or `None` when caching is disabled.
+    pub(crate) fn from_config(config: &AppConfig, refresh: bool) -> Result<Option<Self>> {
+        if !config.cache_enabled {
+            debug!("Response cache disabled");
+            return Ok(None);
+        }
+
+        let dir = match &config.cache_dir {
+            Some(dir) => dir.clone(),
+            None => default_cache_dir()?,
+        };
+        debug!("Response cache directory: {}", dir.display());
+        Ok(Some(Self::new(
+            dir,
+            Duration::from_secs(config.cache_ttl_secs),
+            config.cache_max_bytes,
+            refresh,
+        )))
+    }
+
+    /// Hash of the serialized request plus the base URL it is sent to.
+    pub(crate) fn key(base_url: &str, request: &impl Serialize) -> Result<String> {
+        let body = serde_json::to_vec(request).context("Failed to serialize request for cache")?;
+        let mut hasher = Sha256::new();
+        hasher.update(base_url.trim_end_matches('/').as_bytes());
+        hasher.update([0u8]);
+        hasher.update(&body);
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect())
+    }
+
+    fn entry_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{CACHE_FILE_EXTENSION}"))
+    }
+
+    /// Whether `get` would return a stored stream for `key`.
+    pub(crate) fn contains(&self, key: &str) -> bool {
+        self.fresh_entry_path(key).is_some()
+    }
+
+    /// Return the stored stream for `key` if present and not expired.
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let path = self.fresh_entry_path(key)?;
+        match fs::read_to_string(&path) {
+            Ok(body) => {
+                debug!("Cache hit for {key}");
+                Some(body)
+            }
+            Err(e) => {
+                debug!("Failed to read cache entry {}: {e}", path.display());
+                None
+            }
+        }
+    }
+
+    // The entry file for `key` unless it is missing, expired or being refreshed
+    fn fresh_entry_path(&self, key: &str) -> Option<PathBuf> {
+        if self.refresh {
+            debug!("Refreshing cache entry {key}");
+            return None;
+        }
+
+        let path = self.entry_path(key);
+        let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or(Duration::ZERO);
+        if age > self.ttl {
+            debug!("Cache entry {key} expired ({}s old)", age.as_secs());
+            let _ = fs::remove_file(&path);
+            return None;
+        }
+        Some(path)
+    }
+
+    /// Store a complete response stream. Failures are logged and otherwise
+    /// ignored so that caching never breaks a successful request.
+    pub(crate) fn put(&self, key: &str, body: &str) {
+        if let Err(e) = self.try_put(key, body) {
+            warn!("Failed to write response cache entry: {e:#}");
+        }
+    }
+
+    fn try_put(&self, key: &str, body: &str) -> Result<()> {
+        if body.len() as u64 > self.max_bytes {
+            debug!("Response larger than cache_max_bytes, not caching");
+            return Ok(());
+        }
+        // Entries hold prompts and answers, so only the user may read them
+        let mut dir_builder = fs::DirBuilder::new();
+        dir_builder.recursive(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            dir_builder.mode(0o700);
+        }
+        dir_builder
+            .create(&self.dir)
+            .with_context(|| format!("Failed to create cache directory: {}", self.dir.display()))?;
+
+        // Write to a temporary file first so readers never see partial entries.
+        let path = self.entry_path(key);
+        let tmp_path = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(body.as_bytes()))
+            .with_context(|| format!("Failed to write cache file: {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to write cache file: {}", path.display()))?;
+        debug!("Stored cache entry {key} ({} bytes)", body.len());
+
+        self.prune()
+    }
+
+    /// Remove expired entries, then the oldest entries until the cache fits
+    /// within `max_bytes`.
+    fn prune(&self) -> Result<()> {
+        let mut entries = cache_entries(&self.dir)?;
+        let now = SystemTime::now();
+        entries.retain(|(path, _, modified)| {
+            let expired = now
+                .duration_since(*modified)
+                .is_ok_and(|age| age > self.ttl);
+            if expired {
+                let _ = fs::remove_file(path);
+            }
+            !expired
+        });
+
+        let mut total: u64 = entries.iter().map(|(_, size, _)| size).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+        for (path, size, _) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            debug!("Evicting cache entry {}", path.display());
+            let _ = fs::remove_file(&path);
+            total = total.saturating_sub(size);
+        }
+        Ok(())
+    }
+}
+
+fn cache_entries(dir: &Path) -> Result<Vec<(PathBuf, u64, SystemTime)>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)
+        .with_context(|| format!("Failed to read cache directory: {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(CACHE_FILE_EXTENSION) {
+            continue;
+        }
+        let metadata = fs::metadata(&path)?;
+        entries.push((path, metadata.len(), metadata.modified()?));
+    }
+    Ok(entries)
+}
+
+// Get the default cache directory, preferring the XDG cache dir
+fn default_cache_dir() -> Result<PathBuf> {
+    if let Some(cache_dir) = dirs::cache_dir() {
+        return Ok(cache_dir.join("ai-cli").join("responses"));
+    }
+    Ok(crate::get_config_dir()?.join("cache"))
+}
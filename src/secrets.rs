@@ -0,0 +1,150 @@
+use crate::AppConfig;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::{Command, Stdio},
+    sync::{Mutex, OnceLock},
+};
+
+/// Secrets already resolved during this run, keyed by their source, so a
+/// password manager is asked at most once.
+static RESOLVED: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Fill in `config.api_key` from `api_key_env`, `api_key_file` or
+/// `api_key_command` (first one set wins) when no key was given directly.
+///
+/// Error messages name the source but never include the secret itself.
+/// These settings only come from the user config file, `--config`, the
+/// environment or the command line: project config files are stripped of
+/// them when loaded, so a cloned repository cannot run a command here.
+pub(crate) fn resolve_api_key(config: &mut AppConfig) -> Result<()> {
+    if config.api_key.is_some() {
+        return Ok(());
+    }
+
+    let resolved = if let Some(name) = &config.api_key_env {
+        cached(&format!("env:{name}"), || read_env_secret(name))?
+    } else if let Some(path) = &config.api_key_file {
+        cached(&format!("file:{}", path.display()), || {
+            read_secret_file(path)
+        })?
+    } else if let Some(command) = &config.api_key_command {
+        cached(&format!("command:{command}"), || {
+            run_secret_command(command)
+        })?
+    } else {
+        return Ok(());
+    };
+    config.api_key = Some(resolved);
+    Ok(())
+}
+
+fn cached(source: &str, resolve: impl FnOnce() -> Result<String>) -> Result<String> {
+    let cache = RESOLVED.get_or_init(|| Mutex::new(HashMap::new()));
+    if let Some(secret) = cache
+        .lock()
+        .ok()
+        .and_then(|cache| cache.get(source).cloned())
+    {
+        debug!("Using cached API key from {source}");
+        return Ok(secret);
+    }
+    let secret = resolve()?;
+    if let Ok(mut cache) = cache.lock() {
+        cache.insert(source.to_string(), secret.clone());
+    }
+    Ok(secret)
+}
+
+fn read_env_secret(name: &str) -> Result<String> {
+    debug!("Reading API key from environment variable {name}");
+    match std::env::var(name) {
+        Ok(value) if !value.trim().is_empty() => Ok(value.trim().to_string()),
+        Ok(_) => Err(anyhow::anyhow!(
+            "Environment variable {name} (from api_key_env) is empty"
+        )),
+        Err(_) => Err(anyhow::anyhow!(
+            "Environment variable {name} (from api_key_env) is not set"
+        )),
+    }
+}
+
+fn read_secret_file(path: &Path) -> Result<String> {
+    debug!("Reading API key from file {}", path.display());
+    warn_if_world_readable(path, "API key file");
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read api_key_file: {}", path.display()))?;
+    let secret = contents.trim();
+    if secret.is_empty() {
+        return Err(anyhow::anyhow!("api_key_file is empty: {}", path.display()));
+    }
+    Ok(secret.to_string())
+}
+
+// Run the command through the shell and use its trimmed stdout. stderr is
+// left attached so tools like `pass` can prompt, but stdin is not shared so
+// the command cannot swallow piped input meant for the prompt.
+fn run_secret_command(command: &str) -> Result<String> {
+    debug!("Running api_key_command");
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let output = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to run api_key_command '{command}'"))?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "api_key_command '{command}' failed with {}",
+            output.status
+        ));
+    }
+    let stdout = String::from_utf8(output.stdout)
+        .map_err(|_| anyhow::anyhow!("api_key_command '{command}' printed invalid UTF-8"))?;
+    let secret = stdout.trim();
+    if secret.is_empty() {
+        return Err(anyhow::anyhow!(
+            "api_key_command '{command}' printed nothing"
+        ));
+    }
+    Ok(secret.to_string())
+}
+
+/// Whether a config table stores a plaintext `api_key`, at the top level or
+/// in a profile.
+pub(crate) fn table_has_api_key(table: &toml::Table) -> bool {
+    table.contains_key("api_key")
+        || matches!(table.get("profiles"), Some(toml::Value::Table(profiles))
+        if profiles.values().any(|profile| {
+            profile.as_table().is_some_and(|profile| profile.contains_key("api_key"))
+        }))
+}
+
+/// Warn when a file holding a secret can be read by other users.
+pub(crate) fn warn_if_world_readable(path: &Path, description: &str) {
+    if is_world_readable(path) {
+        warn!(
+            "{description} {} is readable by other users; restrict it with `chmod 600 {}`",
+            path.display(),
+            path.display()
+        );
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.permissions().mode() & 0o004 != 0)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_world_readable(_path: &Path) -> bool {
+    false
+}
@@ -0,0 +1,111 @@
+use std::path::Path;
+
+/// Programming languages whose top-level definitions chunks can start at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SourceLanguage {
+    Rust,
+    Python,
+    /// JavaScript and TypeScript.
+    JavaScript,
+    Go,
+}
+
+impl SourceLanguage {
+    /// Guess the language from a file extension.
+    pub(crate) fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "rs" => Some(SourceLanguage::Rust),
+            "py" | "pyi" => Some(SourceLanguage::Python),
+            "js" | "jsx" | "mjs" | "cjs" | "ts" | "tsx" | "mts" | "cts" => {
+                Some(SourceLanguage::JavaScript)
+            }
+            "go" => Some(SourceLanguage::Go),
+            _ => None,
+        }
+    }
+
+    /// Whether a chunk may start at `line`, given the line before it: the
+    /// line opens a top-level definition, or the comments, attributes or
+    /// decorators written above one, and is not itself attached to a
+    /// definition opened above it.
+    pub(crate) fn starts_definition(self, line: &str, previous_line: &str) -> bool {
+        if line.starts_with(char::is_whitespace) || self.is_preamble(previous_line) {
+            return false;
+        }
+        self.is_preamble(line) || self.definition_keywords().contains(&item_keyword(line))
+    }
+
+    // Keywords that open a top-level definition, after any visibility
+    // modifier
+    fn definition_keywords(self) -> &'static [&'static str] {
+        match self {
+            SourceLanguage::Rust => &[
+                "fn",
+                "async",
+                "const",
+                "unsafe",
+                "extern",
+                "impl",
+                "struct",
+                "enum",
+                "trait",
+                "mod",
+                "static",
+                "type",
+                "union",
+                "macro_rules",
+            ],
+            SourceLanguage::Python => &["def", "async", "class"],
+            SourceLanguage::JavaScript => &[
+                "function",
+                "async",
+                "class",
+                "export",
+                "const",
+                "let",
+                "var",
+                "interface",
+                "type",
+                "enum",
+                "abstract",
+                "declare",
+                "namespace",
+            ],
+            SourceLanguage::Go => &["func", "type", "var", "const"],
+        }
+    }
+
+    // Lines that belong to the definition below them
+    fn is_preamble(self, line: &str) -> bool {
+        let line = line.trim_start();
+        match self {
+            SourceLanguage::Rust => line.starts_with("#[") || line.starts_with("//"),
+            SourceLanguage::Python => line.starts_with('@') || line.starts_with('#'),
+            SourceLanguage::JavaScript => {
+                line.starts_with('@')
+                    || line.starts_with("//")
+                    || line.starts_with("/*")
+                    || line.starts_with('*')
+            }
+            SourceLanguage::Go => line.starts_with("//"),
+        }
+    }
+}
+
+// First word of a line, skipping Rust's `pub` and `pub(crate)`
+fn item_keyword(line: &str) -> &str {
+    let mut rest = line;
+    if let Some(after_pub) = rest.strip_prefix("pub") {
+        if let Some(scoped) = after_pub.strip_prefix('(') {
+            rest = scoped.split_once(')').map_or("", |(_, after)| after);
+        } else if after_pub.starts_with(' ') {
+            rest = after_pub;
+        }
+    }
+    let rest = rest.trim_start();
+    let end = rest
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(rest.len());
+    &rest[..end]
+}
@@ -1,7 +1,7 @@
 use std::{
     io::{IsTerminal, Write},
     sync::mpsc,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 const TICK_MS: u64 = 100;
@@ -25,6 +25,17 @@ impl Spinner {
     /// not a terminal, so output piped to a file or another process is
     /// never disturbed.
     pub fn new(message: &str, enabled: bool) -> Self {
+        let message = message.to_string();
+        Self::start(move || message.clone(), enabled)
+    }
+
+    /// Create a spinner showing how far a run of chunks has got, with the
+    /// elapsed time kept current while it spins.
+    pub fn with_progress(progress: Progress, enabled: bool) -> Self {
+        Self::start(move || progress.message(), enabled)
+    }
+
+    fn start(message: impl Fn() -> String + Send + 'static, enabled: bool) -> Self {
         if !enabled || !std::io::stderr().is_terminal() {
             return Self {
                 cancel_tx: None,
@@ -34,7 +45,6 @@ impl Spinner {
 
         // capacity=1 so the implicit drop-send in finish_and_clear never blocks
         let (tx, rx) = mpsc::sync_channel::<()>(1);
-        let msg = message.to_string();
 
         let handle = std::thread::spawn(move || {
             let stderr = std::io::stderr();
@@ -50,7 +60,8 @@ impl Spinner {
                         // a previous chunk's response in multi-chunk mode).
                         let _ = writeln!(err);
                     }
-                    let _ = write!(err, "\r{} {}", frame, msg);
+                    // Clear the rest of the line in case the message got shorter
+                    let _ = write!(err, "\r{} {}\x1b[K", frame, message());
                     let _ = err.flush();
                 }
                 idx += 1;
@@ -99,6 +110,85 @@ impl Drop for Spinner {
     }
 }
 
+/// Position in a run of chunks whose total may only be estimated, shown as
+/// e.g. "Chunk 12/40, 1:05 elapsed, ETA 2:10".
+#[derive(Clone, Copy, Debug)]
+pub struct Progress {
+    started: Instant,
+    /// Chunks done before this run started, e.g. by a resumed run.
+    first: usize,
+    current: usize,
+    /// When the wait for the current chunk began.
+    waiting_since: Instant,
+    total: Option<usize>,
+}
+
+impl Progress {
+    /// Start timing a run that continues after chunk `first` and is
+    /// expected to end after chunk `total`, if that is known.
+    pub fn new(first: usize, total: Option<usize>) -> Self {
+        let now = Instant::now();
+        Self {
+            started: now,
+            first,
+            current: first + 1,
+            waiting_since: now,
+            total,
+        }
+    }
+
+    /// The same run, now waiting for chunk `current`.
+    pub fn at(self, current: usize) -> Self {
+        Self {
+            current,
+            waiting_since: Instant::now(),
+            ..self
+        }
+    }
+
+    /// Estimated number of chunks, if the input length is known.
+    pub fn total(&self) -> Option<usize> {
+        self.total
+    }
+
+    pub fn message(&self) -> String {
+        self.message_after(self.started.elapsed(), self.waiting_since.elapsed())
+    }
+
+    // The message `elapsed` into the run, `waited` into the current chunk
+    fn message_after(&self, elapsed: Duration, waited: Duration) -> String {
+        let mut message = format!("Chunk {}", self.current);
+        // An estimate that turned out low must not read e.g. "Chunk 41/40"
+        let total = self.total.map(|total| total.max(self.current));
+        if let Some(total) = total {
+            message.push_str(&format!("/{total}"));
+        }
+        message.push_str(&format!(", {} elapsed", format_duration(elapsed)));
+
+        // Chunks answered in this run set the pace for the rest, and the
+        // time already spent on the current one counts down from it
+        let done = self.current.saturating_sub(self.first + 1);
+        if let (Some(total), true) = (total, done > 0) {
+            let pace = elapsed.saturating_sub(waited) / done as u32;
+            let remaining = (total + 1 - self.current) as u32;
+            let eta = (pace * remaining).saturating_sub(waited);
+            message.push_str(&format!(", ETA {}", format_duration(eta)));
+        }
+        message
+    }
+}
+
+// "1:05", or "1:02:05" from an hour on
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{secs:02}")
+    } else {
+        format!("{minutes}:{secs:02}")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -118,6 +208,42 @@ mod tests {
         spinner.finish_and_clear();
     }
 
+    #[test]
+    fn test_progress_message() {
+        // Nothing is known about the pace before the first answer
+        let progress = Progress::new(0, Some(40));
+        let secs = Duration::from_secs;
+        assert_eq!(
+            progress.message_after(secs(3), secs(3)),
+            "Chunk 1/40, 0:03 elapsed"
+        );
+        // 11 chunks took 55 s, so the other 29 take about 145 s
+        assert_eq!(
+            progress.at(12).message_after(secs(55), secs(0)),
+            "Chunk 12/40, 0:55 elapsed, ETA 2:25"
+        );
+        assert_eq!(
+            progress.at(12).message_after(secs(60), secs(5)),
+            "Chunk 12/40, 1:00 elapsed, ETA 2:20"
+        );
+        // A resumed run only counts its own chunks
+        let resumed = Progress::new(10, Some(40));
+        assert_eq!(
+            resumed.at(12).message_after(secs(10), secs(0)),
+            "Chunk 12/40, 0:10 elapsed, ETA 4:50"
+        );
+        assert_eq!(
+            progress.at(41).message_after(secs(3700), secs(100)),
+            "Chunk 41/41, 1:01:40 elapsed, ETA 0:00"
+        );
+        assert_eq!(
+            Progress::new(0, None)
+                .at(5)
+                .message_after(secs(20), secs(1)),
+            "Chunk 5, 0:20 elapsed"
+        );
+    }
+
     #[test]
     fn test_spinner_finish_twice() {
         // finish_and_clear should be safe to call multiple times.
@@ -0,0 +1,41 @@
+use std::string::FromUtf8Error;
+
+/// Splits a server-sent events body into lines as its bytes arrive.
+///
+/// Bytes are buffered until a newline ends the line, because a network
+/// chunk can end inside a multibyte character, though a line never can.
+/// Streamed responses, cached and recorded ones, and `doctor` all read
+/// their bodies through this.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct SseLines {
+    pending: Vec<u8>,
+}
+
+impl SseLines {
+    /// Add the next bytes of the body.
+    pub(crate) fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+    }
+
+    /// The next complete line without its line ending, or an error holding
+    /// its bytes when they are not valid UTF-8.
+    pub(crate) fn next_line(&mut self) -> Option<Result<String, FromUtf8Error>> {
+        let pos = self.pending.iter().position(|&byte| byte == b'\n')?;
+        let mut line: Vec<u8> = self.pending.drain(..=pos).collect();
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        Some(String::from_utf8(line))
+    }
+
+    /// Whether bytes after the last newline are still buffered.
+    pub(crate) fn has_partial_line(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// The bytes after the last newline, once the body has ended.
+    pub(crate) fn take_rest(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+}
@@ -0,0 +1,309 @@
+use crate::rate_limiter::CHARS_PER_TOKEN;
+use crate::{get_config_dir, AppConfig};
+use anyhow::{Context, Result};
+use base64::Engine;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::{fs, path::Path, path::PathBuf, sync::Arc};
+
+/// Pre-tokenization pattern of the cl100k_base encoding. The original ends
+/// with `\s+(?!\S)|\s+`; the lookahead is applied by hand in `token_starts`.
+const CL100K_PATTERN: &str = r"'(?i:[sdmt]|ll|ve|re)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+";
+
+/// Pre-tokenization pattern of the o200k_base encoding, with the same
+/// lookahead caveat as `CL100K_PATTERN`.
+const O200K_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+",
+);
+
+/// Which tokenizer counts `chunk_size_tokens`.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, clap::ValueEnum, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TokenizerKind {
+    /// Estimate from character classes; needs no vocabulary file.
+    #[default]
+    Heuristic,
+    /// BPE with the cl100k_base vocabulary (GPT-4, GPT-3.5).
+    Cl100k,
+    /// BPE with the o200k_base vocabulary (GPT-4o and later).
+    O200k,
+}
+
+impl TokenizerKind {
+    fn vocabulary_name(self) -> Option<&'static str> {
+        match self {
+            TokenizerKind::Heuristic => None,
+            TokenizerKind::Cl100k => Some("cl100k_base"),
+            TokenizerKind::O200k => Some("o200k_base"),
+        }
+    }
+}
+
+pub(crate) trait Tokenizer: Send + Sync {
+    /// Byte offsets at which the tokens of `text` start. Every offset is a
+    /// char boundary, so a token that ends inside a multi-byte character is
+    /// counted together with the next one.
+    fn token_starts(&self, text: &str) -> Vec<usize>;
+
+    fn count_tokens(&self, text: &str) -> usize {
+        self.token_starts(text).len()
+    }
+}
+
+/// Build the tokenizer selected by `tokenizer` and `tokenizer_file`.
+///
+/// Without `tokenizer_file`, the BPE tokenizers read
+/// `<config dir>/tokenizers/<name>.tiktoken` and fall back to the heuristic
+/// with a warning when it is missing. An explicit `tokenizer_file` that
+/// cannot be loaded is an error.
+pub(crate) fn from_config(config: &AppConfig) -> Result<Arc<dyn Tokenizer>> {
+    let Some(name) = config.tokenizer.vocabulary_name() else {
+        return Ok(Arc::new(HeuristicTokenizer));
+    };
+    if let Some(path) = &config.tokenizer_file {
+        return Ok(Arc::new(BpeTokenizer::load(config.tokenizer, path)?));
+    }
+
+    let path = default_vocabulary_path(name)?;
+    if !path.exists() {
+        warn!(
+            "Tokenizer vocabulary {} not found; estimating tokens instead. Download {name}.tiktoken there or set tokenizer_file",
+            path.display()
+        );
+        return Ok(Arc::new(HeuristicTokenizer));
+    }
+    Ok(Arc::new(BpeTokenizer::load(config.tokenizer, &path)?))
+}
+
+fn default_vocabulary_path(name: &str) -> Result<PathBuf> {
+    Ok(get_config_dir()?
+        .join("tokenizers")
+        .join(format!("{name}.tiktoken")))
+}
+
+/// Token estimate that needs no vocabulary. It errs on the high side so
+/// chunks sized with it still fit: short runs of ASCII letters or digits
+/// form a token, and so does every other non-space character, which keeps
+/// code and CJK text from being badly undercounted.
+pub(crate) struct HeuristicTokenizer;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CharClass {
+    Word,
+    Digit,
+    Newline,
+    Space,
+    Other,
+}
+
+impl CharClass {
+    fn of(c: char) -> Self {
+        if c.is_ascii_digit() {
+            CharClass::Digit
+        } else if c.is_ascii_alphabetic() || (c.is_alphabetic() && u32::from(c) < 0x250) {
+            // Latin letters, including accented ones, merge into words
+            CharClass::Word
+        } else if c == '\n' || c == '\r' {
+            CharClass::Newline
+        } else if c.is_whitespace() {
+            CharClass::Space
+        } else {
+            CharClass::Other
+        }
+    }
+
+    fn max_run(self) -> usize {
+        match self {
+            CharClass::Word | CharClass::Space => CHARS_PER_TOKEN,
+            CharClass::Digit => 3,
+            CharClass::Newline => usize::MAX,
+            CharClass::Other => 1,
+        }
+    }
+}
+
+impl Tokenizer for HeuristicTokenizer {
+    fn token_starts(&self, text: &str) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut chars = text.char_indices().peekable();
+        while let Some((start, c)) = chars.next() {
+            starts.push(start);
+            let mut class = CharClass::of(c);
+            // A space before a word belongs to the word, as in BPE vocabularies
+            if c == ' '
+                && matches!(chars.peek(), Some(&(_, next)) if CharClass::of(next) == CharClass::Word)
+            {
+                class = CharClass::Word;
+            }
+            let mut taken = 1;
+            while taken < class.max_run() {
+                match chars.peek() {
+                    Some(&(_, next)) if CharClass::of(next) == class => {
+                        chars.next();
+                        taken += 1;
+                    }
+                    _ => break,
+                }
+            }
+        }
+        starts
+    }
+}
+
+/// Byte pair encoding with a tiktoken vocabulary (`<base64 token> <rank>`
+/// per line), as used by OpenAI models.
+pub(crate) struct BpeTokenizer {
+    ranks: HashMap<Vec<u8>, u32>,
+    pattern: Regex,
+}
+
+impl BpeTokenizer {
+    pub(crate) fn new(kind: TokenizerKind, ranks: HashMap<Vec<u8>, u32>) -> Self {
+        let pattern = match kind {
+            TokenizerKind::O200k => O200K_PATTERN,
+            TokenizerKind::Cl100k | TokenizerKind::Heuristic => CL100K_PATTERN,
+        };
+        Self {
+            ranks,
+            pattern: Regex::new(pattern).expect("tokenizer pattern is valid"),
+        }
+    }
+
+    pub(crate) fn load(kind: TokenizerKind, path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read tokenizer vocabulary: {}", path.display()))?;
+        let ranks = parse_vocabulary(&contents)
+            .with_context(|| format!("Invalid tokenizer vocabulary: {}", path.display()))?;
+        debug!(
+            "Loaded {} tokens for the {kind:?} tokenizer from {}",
+            ranks.len(),
+            path.display()
+        );
+        Ok(Self::new(kind, ranks))
+    }
+
+    // Offsets of the tokens within one pre-tokenized piece: start from single
+    // bytes and keep merging the adjacent pair with the lowest rank (the
+    // leftmost one on ties). The rank of each pair is cached by the start of
+    // its left part and the pairs wait in a heap, so a merge only looks up
+    // the two pairs around it instead of rescanning the whole piece.
+    fn piece_starts(&self, piece: &[u8]) -> Vec<usize> {
+        let len = piece.len();
+        if len <= 1 || self.ranks.contains_key(piece) {
+            return vec![0];
+        }
+        // The parts form a linked list indexed by their start offset
+        let mut next: Vec<usize> = (1..=len).collect();
+        let mut prev: Vec<Option<usize>> = (0..len).map(|i| i.checked_sub(1)).collect();
+        let pair_rank = |start: usize, next: &[usize]| -> Option<u32> {
+            let mid = next[start];
+            (mid < len)
+                .then(|| self.ranks.get(&piece[start..next[mid]]).copied())
+                .flatten()
+        };
+        let mut ranks: Vec<Option<u32>> = (0..len).map(|i| pair_rank(i, &next)).collect();
+        let mut heap: BinaryHeap<Reverse<(u32, usize)>> = ranks
+            .iter()
+            .enumerate()
+            .filter_map(|(start, rank)| rank.map(|rank| Reverse((rank, start))))
+            .collect();
+
+        while let Some(Reverse((rank, start))) = heap.pop() {
+            // Entries for merged-away parts or superseded ranks are stale
+            if ranks[start] != Some(rank) {
+                continue;
+            }
+            let mid = next[start];
+            ranks[mid] = None;
+            next[start] = next[mid];
+            if next[start] < len {
+                prev[next[start]] = Some(start);
+            }
+            for left in std::iter::once(start).chain(prev[start]) {
+                ranks[left] = pair_rank(left, &next);
+                if let Some(rank) = ranks[left] {
+                    heap.push(Reverse((rank, left)));
+                }
+            }
+        }
+
+        let mut starts = Vec::new();
+        let mut start = 0;
+        while start < len {
+            starts.push(start);
+            start = next[start];
+        }
+        starts
+    }
+}
+
+impl Tokenizer for BpeTokenizer {
+    fn token_starts(&self, text: &str) -> Vec<usize> {
+        let mut starts = Vec::new();
+        let mut pos = 0;
+        while pos < text.len() {
+            let (piece_start, mut end) = match self.pattern.find_at(text, pos) {
+                Some(found) => (found.start(), found.end()),
+                None => (pos, text.len()),
+            };
+            if piece_start > pos {
+                // The patterns match every character, but never lose text
+                end = piece_start;
+            } else {
+                let piece = &text[pos..end];
+                // `\s+(?!\S)`: leave the last space of a run for the word after it
+                if piece.chars().all(char::is_whitespace)
+                    && !piece.ends_with(['\r', '\n'])
+                    && piece.chars().nth(1).is_some()
+                    && text[end..].starts_with(|c: char| !c.is_whitespace())
+                {
+                    end -= piece.chars().next_back().map_or(0, char::len_utf8);
+                }
+            }
+
+            for offset in self.piece_starts(&text.as_bytes()[pos..end]) {
+                let mut start = pos + offset;
+                while !text.is_char_boundary(start) {
+                    start += 1;
+                }
+                if starts.last() != Some(&start) && start < text.len() {
+                    starts.push(start);
+                }
+            }
+            pos = end;
+        }
+        starts
+    }
+}
+
+/// Parse a tiktoken vocabulary file into token ranks.
+pub(crate) fn parse_vocabulary(contents: &str) -> Result<HashMap<Vec<u8>, u32>> {
+    let mut ranks = HashMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let line_number = index + 1;
+        let (token, rank) = line.split_once(' ').ok_or_else(|| {
+            anyhow::anyhow!("line {line_number}: expected '<base64 token> <rank>'")
+        })?;
+        let token = base64::engine::general_purpose::STANDARD
+            .decode(token)
+            .map_err(|e| anyhow::anyhow!("line {line_number}: invalid base64 token: {e}"))?;
+        let rank = rank
+            .trim()
+            .parse()
+            .map_err(|e| anyhow::anyhow!("line {line_number}: invalid rank: {e}"))?;
+        ranks.insert(token, rank);
+    }
+    if ranks.is_empty() {
+        return Err(anyhow::anyhow!("no tokens found"));
+    }
+    Ok(ranks)
+}
@@ -19,6 +19,17 @@ async fn test_read_empty_stdinput() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     assert!(args.files.is_empty());
@@ -43,6 +54,17 @@ async fn test_read_from_file() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     let input = read_input(&args).await.unwrap();
@@ -63,6 +85,17 @@ async fn test_read_with_prompt() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     // Note: This test would need proper stdin mocking to work correctly
@@ -113,6 +146,17 @@ fn test_version_flag_parsing() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     assert!(args.version);
@@ -129,6 +173,17 @@ fn test_version_flag_parsing() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     assert!(!args.version);
@@ -164,18 +219,49 @@ fn test_config_without_temperature() {
     let config = AppConfig {
         model: "llama3".to_string(),
         base_url: "http://localhost:11434/v1".to_string(),
+        config_version: config_migration::CONFIG_VERSION,
         api_key: None,
+        api_key_command: None,
+        api_key_file: None,
+        api_key_env: None,
         default_prompt: None,
+        system_prompt: None,
         temperature: None, // This should be allowed now
         timeout_secs: 300,
         input_mode: InputMode::Auto,
         chunk_size_chars: 16_000,
         chunk_overlap_chars: 1_000,
         max_chunks: 0,
+        rolling_summary_chars: 2_000,
+        aggregate_input_chars: 1_500,
+        summarize_chunk_outputs: false,
+        parallel_chunks: 1,
         auto_chunk_threshold_chars: 50_000,
         aggregate_chunks: true,
+        parallel_files: 1,
+        aggregate_files: false,
         chunk_prompt_file: None,
+        aggregate_prompt_file: None,
+        aggregate_batch_chars: 16_000,
+        chunk_boundary: ChunkBoundary::default(),
+        chunk_boundary_tolerance: 0.2,
+        chunk_size_tokens: None,
+        chunk_overlap_tokens: None,
+        tokenizer: TokenizerKind::default(),
+        tokenizer_file: None,
         no_progress: false,
+        requests_per_minute: 0,
+        tokens_per_minute: 0,
+        cache_enabled: false,
+        cache_dir: None,
+        cache_ttl_secs: 86_400,
+        cache_max_bytes: 100 * 1024 * 1024,
+        fallback_models: Vec::new(),
+        redact_patterns: Vec::new(),
+        default_profile: None,
+        aliases: BTreeMap::new(),
+        models: BTreeMap::new(),
+        active_profile: None,
     };
     assert_eq!(config.temperature, None);
 }
@@ -191,6 +277,19 @@ fn test_validate_chunk_settings() {
     let mut config = AppConfig::default();
     config.chunk_overlap_chars = config.chunk_size_chars;
     assert!(validate_chunk_settings(&config).is_err());
+
+    let mut config = AppConfig::default();
+    config.parallel_chunks = 0;
+    assert!(validate_chunk_settings(&config).is_err());
+
+    let mut config = AppConfig::default();
+    config.aggregate_input_chars = 0;
+    assert!(validate_chunk_settings(&config).is_err());
+
+    // A zero rolling summary just passes nothing on
+    let mut config = AppConfig::default();
+    config.rolling_summary_chars = 0;
+    assert!(validate_chunk_settings(&config).is_ok());
 }
 
 #[test]
@@ -207,6 +306,17 @@ fn test_should_use_chunked_mode_off() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     let mut config = AppConfig::default();
@@ -228,6 +338,17 @@ fn test_should_use_chunked_mode_chunked() {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     };
 
     let mut config = AppConfig::default();
@@ -252,15 +373,612 @@ fn test_input_chunker_overlap() {
     assert!(chunker.next_chunk(true).is_none());
 }
 
+#[test]
+fn test_validate_token_chunk_settings() {
+    let mut config = AppConfig::default();
+    config.chunk_size_tokens = Some(4000);
+    config.chunk_overlap_tokens = Some(200);
+    assert!(validate_chunk_settings(&config).is_ok());
+
+    config.chunk_overlap_tokens = Some(4000);
+    assert!(validate_chunk_settings(&config).is_err());
+
+    config.chunk_size_tokens = None;
+    config.chunk_overlap_tokens = Some(200);
+    let error = validate_chunk_settings(&config).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "chunk_overlap_tokens requires chunk_size_tokens"
+    );
+
+    let mut config = AppConfig::default();
+    config.tokenizer_file = Some(PathBuf::from("cl100k_base.tiktoken"));
+    assert!(validate_chunk_settings(&config).is_err());
+    config.tokenizer = tokenizer::TokenizerKind::Cl100k;
+    assert!(validate_chunk_settings(&config).is_ok());
+}
+
+#[test]
+fn test_heuristic_tokenizer() {
+    use tokenizer::Tokenizer;
+    let heuristic = tokenizer::HeuristicTokenizer;
+    assert_eq!(
+        heuristic.token_starts("The quick brown fox"),
+        vec![0, 3, 7, 9, 13, 15]
+    );
+    // Punctuation and non-Latin scripts count a token per character
+    assert_eq!(heuristic.count_tokens("fn main() {}"), 8);
+    assert_eq!(heuristic.count_tokens("你好世界"), 4);
+    assert_eq!(heuristic.count_tokens("12345\n\n\n"), 3);
+    assert_eq!(heuristic.count_tokens(""), 0);
+}
+
+#[test]
+fn test_bpe_tokenizer() {
+    use tokenizer::Tokenizer;
+    let mut ranks: std::collections::HashMap<Vec<u8>, u32> = (0..=255u8)
+        .map(|byte| (vec![byte], 1000 + u32::from(byte)))
+        .collect();
+    for (rank, token) in ["he", "ll", "hell", "hello", " w", " wo", " b"]
+        .iter()
+        .enumerate()
+    {
+        ranks.insert(token.as_bytes().to_vec(), rank as u32);
+    }
+    let bpe = tokenizer::BpeTokenizer::new(tokenizer::TokenizerKind::Cl100k, ranks);
+
+    // "hello" is one token; " world" merges to " wo" and then single bytes
+    assert_eq!(bpe.token_starts("hello world"), vec![0, 5, 8, 9, 10]);
+    // The last space of a run stays with the following word
+    assert_eq!(bpe.token_starts("a  b"), vec![0, 1, 2]);
+    // Byte tokens inside a character are counted with the character
+    assert_eq!(bpe.token_starts("é"), vec![0]);
+}
+
+#[test]
+fn test_bpe_tokenizer_long_piece() {
+    use tokenizer::Tokenizer;
+    let ranks = [("a", 0), ("aa", 1), ("aaaa", 2)]
+        .into_iter()
+        .map(|(token, rank)| (token.as_bytes().to_vec(), rank))
+        .collect();
+    let bpe = tokenizer::BpeTokenizer::new(tokenizer::TokenizerKind::Cl100k, ranks);
+
+    // One 200k-byte piece: pairs merge left to right into "aa", then "aaaa"
+    let starts = bpe.token_starts(&"a".repeat(200_001));
+    assert_eq!(starts.len(), 50_001);
+    assert!(starts.iter().enumerate().all(|(i, &start)| start == i * 4));
+}
+
+#[test]
+fn test_parse_tokenizer_vocabulary() {
+    let ranks = tokenizer::parse_vocabulary("YQ== 0\nYg== 1\nYWI= 2\n").unwrap();
+    assert_eq!(ranks.len(), 3);
+    assert_eq!(ranks[b"ab".as_slice()], 2);
+
+    let error = tokenizer::parse_vocabulary("YQ== 0\nnot-base64 1\n").unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("line 2: invalid base64 token"));
+    assert!(tokenizer::parse_vocabulary("").is_err());
+
+    let mut config = AppConfig::default();
+    config.tokenizer = tokenizer::TokenizerKind::Cl100k;
+    config.tokenizer_file = Some(PathBuf::from("/nonexistent/cl100k_base.tiktoken"));
+    let error = tokenizer::from_config(&config).err().unwrap();
+    assert!(error
+        .to_string()
+        .contains("Failed to read tokenizer vocabulary"));
+}
+
+#[test]
+fn test_input_chunker_tokens() {
+    let mut chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 2, 1);
+    chunker.push_str("one two six ten");
+
+    assert_eq!(chunker.next_chunk(false).unwrap(), "one two");
+    assert_eq!(chunker.next_chunk(false).unwrap(), " two six");
+    // The last two tokens fit in a chunk, but only a flush may emit them
+    assert!(chunker.next_chunk(false).is_none());
+    assert_eq!(chunker.next_chunk(true).unwrap(), " six ten");
+    assert!(chunker.next_chunk(true).is_none());
+}
+
+#[test]
+fn test_find_chunk_breaks() {
+    use chunk_boundary::{find_break, ChunkBoundary};
+    let find = |boundary, text: &str| find_break(boundary, None, text, 0, text.len(), |_| true);
+
+    let prose = "First sentence. Second one\nwraps here.\n\nNew paragraph";
+    assert_eq!(find(ChunkBoundary::Exact, prose), None);
+    assert_eq!(find(ChunkBoundary::Line, prose), Some(40));
+    assert_eq!(find(ChunkBoundary::Paragraph, prose), Some(40));
+    assert_eq!(
+        find(ChunkBoundary::Sentence, "First sentence. Second one\nwraps"),
+        Some(15)
+    );
+    // Falls back to a word break when there is no line break
+    assert_eq!(find(ChunkBoundary::Line, "one two three"), Some(8));
+
+    let markdown = "# Intro\ntext\n\n## Usage\nmore\n```sh\n# comment\n\necho\n```\ntail";
+    assert_eq!(find(ChunkBoundary::MarkdownHeading, markdown), Some(14));
+    // The blank line inside the fence is not a paragraph break
+    assert_eq!(find(ChunkBoundary::Paragraph, markdown), Some(14));
+
+    let code = "fn a() {\n    x();\n\n    y();\n}\n\nfn b() {\n    z();\n";
+    assert_eq!(find(ChunkBoundary::CodeBlock, code), Some(31));
+
+    // Only breaks inside the window count
+    assert_eq!(
+        find_break(ChunkBoundary::Line, None, "ab\ncdef", 4, 7, |_| true),
+        None
+    );
+}
+
+#[test]
+fn test_source_language_definitions() {
+    use chunk_boundary::{find_break, ChunkBoundary};
+    use source_language::SourceLanguage;
+    assert_eq!(
+        SourceLanguage::from_path(Path::new("src/main.rs")),
+        Some(SourceLanguage::Rust)
+    );
+    assert_eq!(
+        SourceLanguage::from_path(Path::new("app/View.TSX")),
+        Some(SourceLanguage::JavaScript)
+    );
+    assert_eq!(SourceLanguage::from_path(Path::new("notes.md")), None);
+
+    let find = |boundary, language, text: &str| {
+        find_break(boundary, language, text, 0, text.len(), |_| true)
+    };
+    let rust = "fn a() {\n    b();\n}\n/// Docs\n#[test]\npub(crate) fn c() {\n    d();\n";
+    // Starts at the doc comment, not between it and the function
+    assert_eq!(
+        find(ChunkBoundary::Auto, Some(SourceLanguage::Rust), rust),
+        Some(20)
+    );
+    assert_eq!(find(ChunkBoundary::Auto, None, rust), None);
+
+    let python = "class A:\n    def f(self):\n        pass\n\n@cache\ndef g():\n    return 1\n";
+    assert_eq!(
+        find(ChunkBoundary::Auto, Some(SourceLanguage::Python), python),
+        Some(40)
+    );
+
+    let javascript = "export function a() {\n  return 1;\n}\n/**\n * B\n */\nconst b = () => 2;\n";
+    assert_eq!(
+        find(
+            ChunkBoundary::CodeBlock,
+            Some(SourceLanguage::JavaScript),
+            javascript
+        ),
+        Some(36)
+    );
+
+    let go = "func a() {\n\tb()\n}\n\n// C does c\nfunc c() {\n";
+    assert_eq!(
+        find(ChunkBoundary::Auto, Some(SourceLanguage::Go), go),
+        Some(19)
+    );
+}
+
+#[test]
+fn test_input_chunker_boundaries() {
+    let mut chunker =
+        InputChunker::new(12, 0).with_boundary(chunk_boundary::ChunkBoundary::Line, 0.5);
+    chunker.push_str("alpha beta\ngamma delta\nepsilon\n");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "alpha beta\n");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "gamma delta\n");
+    assert_eq!(chunker.next_chunk(true).unwrap(), "epsilon\n");
+
+    // A break outside the tolerance window is ignored
+    let mut chunker =
+        InputChunker::new(14, 0).with_boundary(chunk_boundary::ChunkBoundary::Line, 0.1);
+    chunker.push_str("alpha beta\ngamma delta\n");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "alpha beta\ngam");
+
+    let mut chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 4, 0)
+            .with_boundary(chunk_boundary::ChunkBoundary::Sentence, 0.5);
+    chunker.push_str("Hi. One two six.");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "Hi.");
+    assert_eq!(chunker.next_chunk(true).unwrap(), " One two six.");
+}
+
+#[test]
+fn test_batch_aggregate_inputs() {
+    use aggregation::{batch_outputs, render_aggregate_prompt};
+    let outputs = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    assert_eq!(
+        render_aggregate_prompt(
+            "{{user_prompt}}: {{chunk_outputs}}",
+            "List",
+            &outputs(&["a", "b"])
+        ),
+        "List: a\n\nb"
+    );
+    assert_eq!(
+        batch_outputs(
+            "{{chunk_outputs}}",
+            "",
+            outputs(&["aaaa", "bbbb", "cccc", "dddd", "ee"]),
+            10
+        ),
+        vec![
+            outputs(&["aaaa", "bbbb"]),
+            outputs(&["cccc", "dddd"]),
+            outputs(&["ee"])
+        ]
+    );
+    // The template counts against the budget
+    assert_eq!(
+        batch_outputs(
+            "Sum: {{chunk_outputs}}",
+            "",
+            outputs(&["aa", "bb", "cc"]),
+            12
+        )
+        .len(),
+        2
+    );
+    // Oversized outputs are still paired up so each level shrinks the list
+    let long = "x".repeat(20);
+    assert_eq!(
+        batch_outputs("{{chunk_outputs}}", "", vec![long.clone(); 3], 10),
+        vec![vec![long.clone(); 2], vec![long]]
+    );
+}
+
+#[test]
+fn test_text_reader_pieces() {
+    use input_stream::{TextReader, MAX_READ_BYTES};
+    // The read limit falls in the middle of a two-byte character
+    let input = format!("a{}\nnext\n", "é".repeat(MAX_READ_BYTES / 2));
+    let mut reader = TextReader::new(input.as_bytes());
+    let mut piece = String::new();
+    let mut pieces = Vec::new();
+    while reader.read_piece(&mut piece).unwrap() {
+        assert!(piece.len() <= MAX_READ_BYTES);
+        pieces.push(piece.clone());
+    }
+    assert_eq!(pieces.len(), 3);
+    assert_eq!(pieces[2], "next\n");
+    assert_eq!(pieces.concat(), input);
+
+    let mut reader = TextReader::new(&b"ok\n\xff\n"[..]);
+    assert!(reader.read_piece(&mut piece).unwrap());
+    let error = reader.read_piece(&mut piece).unwrap_err();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+}
+
+// Endless lines of mixed-width text, cut off after `remaining` bytes
+struct RepeatedText {
+    remaining: usize,
+    offset: usize,
+}
+
+const REPEATED_LINE: &[u8] = "Zeile mit Umlauten äöü und 漢字, then some ASCII.\n".as_bytes();
+
+impl RepeatedText {
+    fn new(lines: usize) -> Self {
+        Self {
+            remaining: lines * REPEATED_LINE.len(),
+            offset: 0,
+        }
+    }
+}
+
+impl std::io::Read for RepeatedText {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+        while written < buf.len() && self.remaining > 0 {
+            let available = &REPEATED_LINE[self.offset..];
+            let n = available.len().min(buf.len() - written).min(self.remaining);
+            buf[written..written + n].copy_from_slice(&available[..n]);
+            written += n;
+            self.remaining -= n;
+            self.offset = (self.offset + n) % REPEATED_LINE.len();
+        }
+        Ok(written)
+    }
+}
+
+// Chunk a stream the way chunked mode does, returning the number of chunks,
+// the number of chunked bytes and the largest buffer the chunker needed
+fn chunk_stream(chunker: &mut InputChunker, input: impl std::io::Read) -> (usize, usize, usize) {
+    let mut reader = input_stream::TextReader::new(std::io::BufReader::new(input));
+    let mut piece = String::new();
+    let (mut chunks, mut bytes, mut capacity) = (0, 0, 0);
+    while reader.read_piece(&mut piece).unwrap() {
+        chunker.push_str(&piece);
+        capacity = capacity.max(chunker.buffer_capacity());
+        while let Some(chunk) = chunker.next_chunk(false) {
+            chunks += 1;
+            bytes += chunk.len();
+        }
+    }
+    while let Some(chunk) = chunker.next_chunk(true) {
+        chunks += 1;
+        bytes += chunk.len();
+    }
+    (chunks, bytes, capacity)
+}
+
+#[test]
+fn test_input_chunker_long_lines() {
+    // A single 4 MB line, which used to be rescanned for every chunk
+    let input = "0123456789".repeat(400_000);
+    let mut chunker = InputChunker::new(1000, 0);
+    let (chunks, bytes, capacity) = chunk_stream(&mut chunker, input.as_bytes());
+    assert_eq!(chunks, 4000);
+    assert_eq!(bytes, input.len());
+    assert!(capacity <= 4 * input_stream::MAX_READ_BYTES);
+
+    let mut chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 500, 100)
+            .with_boundary(chunk_boundary::ChunkBoundary::Line, 0.2);
+    let (chunks, _, capacity) = chunk_stream(&mut chunker, RepeatedText::new(20_000));
+    assert!(chunks > 1);
+    assert!(capacity <= 4 * input_stream::MAX_READ_BYTES);
+
+    // Overlapping chunks still line up across pushes
+    let mut chunker = InputChunker::new(7, 3);
+    for line in ["äbc\n", "def\n", "ghi\n"] {
+        chunker.push_str(line);
+    }
+    assert_eq!(chunker.next_chunk(false).unwrap(), "äbc\ndef");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "def\nghi");
+    assert_eq!(chunker.next_chunk(true).unwrap(), "ghi\n");
+    assert!(chunker.next_chunk(true).is_none());
+}
+
+/// Chunking must take time linear in the input and memory independent of
+/// it, so ten times the input may not scan or copy more per byte.
+#[test]
+fn test_input_chunker_linear_work() {
+    let line = REPEATED_LINE.len();
+    let small_lines = (256 << 10) / line;
+    let large_lines = (2560 << 10) / line;
+    let make_chunker = |name| match name {
+        "chars" => InputChunker::new(8000, 400)
+            .with_boundary(chunk_boundary::ChunkBoundary::Paragraph, 0.2),
+        _ => InputChunker::with_tokens(
+            std::sync::Arc::new(tokenizer::HeuristicTokenizer),
+            2000,
+            100,
+        )
+        .with_boundary(chunk_boundary::ChunkBoundary::Line, 0.2),
+    };
+
+    for name in ["chars", "tokens"] {
+        let mut work_per_byte = Vec::new();
+        for lines in [small_lines, large_lines] {
+            let mut chunker = make_chunker(name);
+            let (_, _, capacity) = chunk_stream(&mut chunker, RepeatedText::new(lines));
+            assert!(capacity <= 4 * input_stream::MAX_READ_BYTES + 8 * 8000);
+            work_per_byte.push(chunker.work() as f64 / (lines * line) as f64);
+        }
+        assert!(
+            work_per_byte[1] < work_per_byte[0] * 1.1 && work_per_byte[1] < 10.0,
+            "{name} chunking work grew from {:.2} to {:.2} per byte",
+            work_per_byte[0],
+            work_per_byte[1]
+        );
+    }
+}
+
+#[test]
+fn test_checkpoint_resume_point() {
+    use checkpoint::{Checkpoint, InputIdentity};
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("input.txt");
+    fs::write(&input_path, "some input").unwrap();
+    let input = InputIdentity::of(std::slice::from_ref(&input_path)).unwrap();
+    let path = dir.path().join("job.jsonl");
+
+    let mut checkpoint = Checkpoint::create(path.clone(), "settings", &input);
+    checkpoint.save_chunk(1, 5, "first", Some("one"));
+    checkpoint.save_chunk(2, 10, "second", Some("two"));
+    assert_eq!(checkpoint.path(), Some(path.as_path()));
+
+    let (_, resume_point) = Checkpoint::resume(&path, "settings", &input).unwrap();
+    assert_eq!(resume_point.chunk_index, 2);
+    assert_eq!(resume_point.offset, 10);
+    assert_eq!(resume_point.rolling_summary, "second");
+    assert_eq!(resume_point.aggregate_inputs, vec!["one", "two"]);
+    assert!(!resume_point.chunks_done);
+
+    // A record cut short by a crash is ignored
+    let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+    write!(file, "{{\"record\":\"chunk\",\"chunk_in").unwrap();
+    let (mut checkpoint, resume_point) = Checkpoint::resume(&path, "settings", &input).unwrap();
+    assert_eq!(resume_point.chunk_index, 2);
+    checkpoint.save_chunks_done();
+
+    let error = Checkpoint::resume(&path, "other settings", &input)
+        .err()
+        .unwrap();
+    assert!(format!("{error:#}").contains("settings changed"));
+    fs::write(&input_path, "changed input").unwrap();
+    let changed = InputIdentity::of(&[input_path]).unwrap();
+    assert!(Checkpoint::resume(&path, "settings", &changed).is_err());
+
+    let mut chunker = InputChunker::new(4, 1);
+    chunker.start_stream(100);
+    chunker.push_str("abcdefg");
+    assert_eq!(chunker.next_chunk(false).unwrap(), "abcd");
+    assert_eq!(chunker.offset(), 103);
+    assert_eq!(chunker.next_chunk(true).unwrap(), "defg");
+    assert_eq!(chunker.offset(), 107);
+}
+
+#[test]
+fn test_checkpoint_default_path() {
+    use checkpoint::{default_path, Checkpoint, InputIdentity};
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("input.txt");
+    fs::write(&input_path, "some input").unwrap();
+    let input = InputIdentity::of(&[input_path]).unwrap();
+
+    // A rerun of a job on files finds its checkpoint again
+    assert_eq!(
+        default_path("settings", &input).unwrap(),
+        default_path("settings", &input).unwrap()
+    );
+    // Jobs reading stdin get a checkpoint of their own
+    let stdin_path = default_path("settings", &InputIdentity::Stdin).unwrap();
+    let stdin_name = stdin_path.file_name().unwrap().to_string_lossy();
+    assert!(stdin_name.contains(&format!("-{}-", std::process::id())));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let path = dir.path().join("job.jsonl");
+        Checkpoint::create(path.clone(), "settings", &input);
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
+
+#[test]
+fn test_reserve_prompt_tokens_for_template() {
+    let mut config = AppConfig::default();
+    config.chunk_size_tokens = Some(6);
+    let mut chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 6, 0);
+    chunker.push_str("one two six ten red");
+
+    // "Sum:" takes two tokens and the join margin two more
+    let position = |index| ChunkPosition {
+        index,
+        ..ChunkPosition::default()
+    };
+    chunk_processor::reserve_prompt_tokens(
+        &mut chunker,
+        &config,
+        "Sum:{{chunk_text}}",
+        "",
+        "",
+        &position(1),
+    )
+    .unwrap();
+    assert_eq!(chunker.next_chunk(false).unwrap(), "one two");
+
+    let error = chunk_processor::reserve_prompt_tokens(
+        &mut chunker,
+        &config,
+        "{{rolling_summary}}{{chunk_text}}",
+        "",
+        "a much longer summary of the previous chunk",
+        &position(2),
+    )
+    .unwrap_err();
+    assert!(error.to_string().contains("raise chunk_size_tokens"));
+}
+
+#[test]
+fn test_estimate_chunk_count_after_template() {
+    let dir = tempfile::tempdir().unwrap();
+    let input_path = dir.path().join("input.txt");
+    fs::write(&input_path, "x".repeat(80)).unwrap();
+    let mut args = default_test_args();
+    args.files = vec![input_path];
+    let mut config = AppConfig::default();
+    config.chunk_size_tokens = Some(6);
+    let mut chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 6, 0);
+
+    // The template takes four of the six tokens, leaving chunks of 8 bytes
+    let estimate = chunk_processor::estimate_chunk_count(
+        &args,
+        &config,
+        &mut chunker,
+        "Sum:{{chunk_text}}",
+        "",
+        &checkpoint::ResumePoint::default(),
+    );
+    assert_eq!(estimate, Some(10));
+}
+
 #[test]
 fn test_render_chunk_prompt_template() {
     let template =
         "chunk={{chunk_index}} prompt={{user_prompt}} prev={{rolling_summary}} text={{chunk_text}}";
-    let rendered = render_chunk_prompt(template, "summarize", "older", "new-data", 3);
+    let position = ChunkPosition {
+        index: 3,
+        ..ChunkPosition::default()
+    };
+    let rendered = render_chunk_prompt(template, "summarize", "older", "new-data", &position);
     assert_eq!(
         rendered,
         "chunk=3 prompt=summarize prev=older text=new-data"
     );
+
+    let template =
+        "{{chunk_index}}/{{chunk_count_estimate}} {{file_name}}@{{byte_offset}}: {{chunk_text}}";
+    let position = ChunkPosition {
+        index: 3,
+        count_estimate: Some(40),
+        file_name: "notes/a.md",
+        byte_offset: 8000,
+    };
+    assert_eq!(
+        render_chunk_prompt(template, "", "", "{{file_name}}", &position),
+        "3/40 notes/a.md@8000: {{file_name}}"
+    );
+    let position = ChunkPosition {
+        count_estimate: None,
+        ..position
+    };
+    assert!(render_chunk_prompt(template, "", "", "", &position).starts_with("3/unknown "));
+}
+
+#[test]
+fn test_per_file_output_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path().canonicalize().unwrap();
+    for file in ["src/main.rs", "src/io/read.rs", "docs/design.md"] {
+        let path = root.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+    }
+
+    let files = vec![root.join("src/main.rs"), root.join("src/io/../io/read.rs")];
+    assert_eq!(
+        per_file::mirror_paths(&files).unwrap(),
+        vec![PathBuf::from("main.rs"), PathBuf::from("io/read.rs")]
+    );
+    let files = vec![root.join("src/main.rs"), root.join("docs/design.md")];
+    let relative_paths = per_file::mirror_paths(&files).unwrap();
+    assert_eq!(
+        relative_paths,
+        vec![
+            PathBuf::from("src/main.rs"),
+            PathBuf::from("docs/design.md")
+        ]
+    );
+    assert_eq!(
+        per_file::output_path(Path::new("out"), &relative_paths[1]),
+        PathBuf::from("out/docs/design.md.out")
+    );
+    assert!(per_file::mirror_paths(&[root.join("missing.txt")]).is_err());
+}
+
+#[test]
+fn test_estimate_chunk_count() {
+    let chunker = InputChunker::new(100, 20);
+    assert_eq!(chunker.estimate_chunk_count(0), 0);
+    assert_eq!(chunker.estimate_chunk_count(100), 1);
+    // Chunks after the first advance by 80 bytes
+    assert_eq!(chunker.estimate_chunk_count(180), 2);
+    assert_eq!(chunker.estimate_chunk_count(181), 3);
+
+    let chunker =
+        InputChunker::with_tokens(std::sync::Arc::new(tokenizer::HeuristicTokenizer), 100, 0);
+    assert_eq!(chunker.estimate_chunk_count(4000), 10);
 }
 
 /// Helper: create a default [`Args`] with all optional fields set to their
@@ -278,6 +996,17 @@ fn default_test_args() -> Args {
         timeout: None,
         input_mode: None,
         no_progress: false,
+        no_cache: false,
+        refresh_cache: false,
+        record: None,
+        replay: None,
+        resume: None,
+        per_file: false,
+        output_dir: None,
+        profile: None,
+        config: None,
+        no_config: false,
+        command: None,
     }
 }
 
@@ -300,3 +1029,1999 @@ fn test_no_progress_flag_parsing() {
     let args_without_flag = default_test_args();
     assert!(!args_without_flag.no_progress);
 }
+
+#[test]
+fn test_rate_limits_default_unlimited() {
+    let config = AppConfig::default();
+    assert_eq!(config.requests_per_minute, 0);
+    assert_eq!(config.tokens_per_minute, 0);
+    assert!(!RateLimiter::new(0, 0).is_enabled());
+}
+
+#[test]
+fn test_estimate_tokens() {
+    assert_eq!(estimate_tokens(""), 0);
+    assert_eq!(estimate_tokens("abcd"), 1);
+    assert_eq!(estimate_tokens("abcde"), 2);
+}
+
+#[test]
+fn test_token_bucket_refill() {
+    let start = std::time::Instant::now();
+    let mut bucket = TokenBucket::per_minute(60, start);
+
+    // A full bucket serves the request immediately.
+    assert_eq!(bucket.wait_time(60.0, start), std::time::Duration::ZERO);
+    bucket.consume(60.0, start);
+
+    // Empty bucket refills at one unit per second.
+    let wait = bucket.wait_time(2.0, start);
+    assert_eq!(wait.as_secs(), 2);
+
+    let later = start + std::time::Duration::from_secs(2);
+    assert_eq!(bucket.wait_time(2.0, later), std::time::Duration::ZERO);
+}
+
+#[test]
+fn test_token_bucket_clamps_oversized_cost() {
+    let start = std::time::Instant::now();
+    let mut bucket = TokenBucket::per_minute(10, start);
+    // A request larger than the bucket only has to wait for a full bucket.
+    assert_eq!(bucket.wait_time(1_000.0, start), std::time::Duration::ZERO);
+    bucket.consume(1_000.0, start);
+    assert_eq!(bucket.wait_time(1_000.0, start).as_secs(), 60);
+}
+
+#[test]
+fn test_rate_limiter_requests_per_minute() {
+    let start = std::time::Instant::now();
+    let mut limiter = RateLimiter::new(2, 0);
+    assert!(limiter.is_enabled());
+    assert_eq!(limiter.wait_time(100, start), std::time::Duration::ZERO);
+    limiter.consume(100, start);
+    limiter.consume(100, start);
+    assert_eq!(limiter.wait_time(100, start).as_secs(), 30);
+}
+
+#[test]
+fn test_rate_limiter_clones_share_buckets() {
+    let start = std::time::Instant::now();
+    let mut limiter = RateLimiter::new(2, 0);
+    let mut clone = limiter.clone();
+    clone.consume(100, start);
+    clone.consume(100, start);
+    assert_eq!(limiter.wait_time(100, start).as_secs(), 30);
+}
+
+#[tokio::test]
+async fn test_retries_acquire_rate_limiter() {
+    // The second request fails once, so three attempts are sent in all
+    let base_url = start_mock_server(MockServerArgs {
+        fail_every: 2,
+        ..MockServerArgs::default()
+    })
+    .await;
+    let client = Client::new();
+    let mut limiter = RateLimiter::new(3, 0);
+    for _ in 0..2 {
+        chunk_processor::stream_response_with_retries(
+            &client,
+            &base_url,
+            None,
+            test_request("hi"),
+            5,
+            "Test request".to_string(),
+            false,
+            false,
+            None,
+            &mut limiter,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+    }
+    assert!(!limiter.wait_time(0, std::time::Instant::now()).is_zero());
+}
+
+#[tokio::test]
+async fn test_cached_retries_skip_rate_limiter() {
+    // With one request a minute, only a cache hit can answer the second
+    let base_url = start_mock_server(MockServerArgs::default()).await;
+    let cache_dir = tempfile::tempdir().unwrap();
+    let cache = ResponseCache::new(
+        cache_dir.path().to_path_buf(),
+        std::time::Duration::from_secs(60),
+        1_000_000,
+        false,
+    );
+    let client = Client::new();
+    let mut limiter = RateLimiter::new(1, 0);
+    for _ in 0..2 {
+        let request = chunk_processor::stream_response_with_retries(
+            &client,
+            &base_url,
+            None,
+            test_request("hi"),
+            5,
+            "Test request".to_string(),
+            false,
+            false,
+            None,
+            &mut limiter,
+            Some(&cache),
+            None,
+        );
+        let answer = tokio::time::timeout(std::time::Duration::from_secs(5), request)
+            .await
+            .expect("a cached answer should not wait for the rate limiter")
+            .unwrap();
+        assert_eq!(answer, "hi");
+    }
+}
+
+#[test]
+fn test_cache_disabled_by_default() {
+    assert!(!AppConfig::default().cache_enabled);
+    let config: AppConfig = toml::from_str("model = \"llama3\"\n").unwrap();
+    assert!(!config.cache_enabled);
+}
+
+#[test]
+fn test_cache_flags_parsing() {
+    let args = Args::try_parse_from(["ai-cli", "--no-cache"]).unwrap();
+    assert!(args.no_cache);
+    assert!(!args.refresh_cache);
+
+    let args = Args::try_parse_from(["ai-cli", "--refresh-cache"]).unwrap();
+    assert!(args.refresh_cache);
+
+    assert!(Args::try_parse_from(["ai-cli", "--no-cache", "--refresh-cache"]).is_err());
+}
+
+fn test_request(content: &str) -> ChatCompletionRequest {
+    ChatCompletionRequest {
+        model: "llama3".to_string(),
+        messages: vec![ChatMessage {
+            role: "user".to_string(),
+            content: content.to_string(),
+        }],
+        stream: true,
+        temperature: None,
+    }
+}
+
+#[test]
+fn test_response_cache_key() {
+    let base = "http://localhost:11434/v1";
+    let key = ResponseCache::key(base, &test_request("hello")).unwrap();
+    assert_eq!(key.len(), 64);
+    assert_eq!(
+        key,
+        ResponseCache::key(&format!("{base}/"), &test_request("hello")).unwrap()
+    );
+    assert_ne!(
+        key,
+        ResponseCache::key(base, &test_request("goodbye")).unwrap()
+    );
+    assert_ne!(
+        key,
+        ResponseCache::key("https://api.openai.com/v1", &test_request("hello")).unwrap()
+    );
+}
+
+#[test]
+fn test_response_cache_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ResponseCache::new(
+        dir.path().to_path_buf(),
+        std::time::Duration::from_secs(60),
+        1024,
+        false,
+    );
+    assert!(cache.get("abc").is_none());
+    cache.put("abc", "data: {}\n");
+    assert_eq!(cache.get("abc").as_deref(), Some("data: {}\n"));
+
+    // --refresh-cache skips lookups but keeps the stored entry.
+    let refreshing = ResponseCache::new(
+        dir.path().to_path_buf(),
+        std::time::Duration::from_secs(60),
+        1024,
+        true,
+    );
+    assert!(refreshing.get("abc").is_none());
+    assert!(cache.get("abc").is_some());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_response_cache_private_files() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = tempfile::tempdir().unwrap();
+    let cache_dir = dir.path().join("responses");
+    let cache = ResponseCache::new(
+        cache_dir.clone(),
+        std::time::Duration::from_secs(60),
+        1024,
+        false,
+    );
+    cache.put("abc", "data: {}\n");
+
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode(&cache_dir), 0o700);
+    assert_eq!(mode(&cache_dir.join("abc.sse")), 0o600);
+}
+
+#[test]
+fn test_response_cache_expired_entry() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ResponseCache::new(
+        dir.path().to_path_buf(),
+        std::time::Duration::ZERO,
+        1024,
+        false,
+    );
+    cache.put("abc", "data: {}\n");
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    assert!(cache.get("abc").is_none());
+}
+
+#[test]
+fn test_response_cache_size_limit() {
+    let dir = tempfile::tempdir().unwrap();
+    let cache = ResponseCache::new(
+        dir.path().to_path_buf(),
+        std::time::Duration::from_secs(60),
+        10,
+        false,
+    );
+    cache.put("first", "123456");
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    cache.put("second", "abcdef");
+    // Oldest entry is evicted to stay under the limit.
+    assert!(cache.get("first").is_none());
+    assert_eq!(cache.get("second").as_deref(), Some("abcdef"));
+
+    // Entries larger than the whole cache are never stored.
+    cache.put("huge", "this body is far too large");
+    assert!(cache.get("huge").is_none());
+}
+
+#[test]
+fn test_process_sse_lines() {
+    let mut lines = sse::SseLines::default();
+    lines.push(
+        b"data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n\ndata: {\"choices\":[{\"delta\":{\"content\":\"lo\"}}]}\ndata: [DONE]\ndata: {\"cho",
+    );
+    let mut output = String::new();
+    process_sse_lines(&mut lines, &mut output, false).unwrap();
+    assert_eq!(output, "Hello");
+    assert_eq!(lines.take_rest(), b"data: {\"cho");
+
+    // A character split across network chunks is decoded once its line ends
+    let event = "data: {\"choices\":[{\"delta\":{\"content\":\"é\"}}]}\n".as_bytes();
+    let split = event.iter().position(|&byte| byte >= 0x80).unwrap() + 1;
+    let mut output = String::new();
+    lines.push(&event[..split]);
+    process_sse_lines(&mut lines, &mut output, false).unwrap();
+    lines.push(&event[split..]);
+    process_sse_lines(&mut lines, &mut output, false).unwrap();
+    assert_eq!(output, "é");
+
+    // A complete line that is not UTF-8 fails the response
+    lines.push(b"data: \xff\n");
+    assert!(process_sse_lines(&mut lines, &mut output, false).is_err());
+}
+
+#[test]
+fn test_record_replay_flags_conflict() {
+    let args = Args::try_parse_from(["ai-cli", "--record", "/tmp/rec"]).unwrap();
+    assert_eq!(args.record, Some(PathBuf::from("/tmp/rec")));
+    assert!(Args::try_parse_from(["ai-cli", "--record", "a", "--replay", "b"]).is_err());
+}
+
+#[test]
+fn test_record_then_replay_exchanges() {
+    let dir = tempfile::tempdir().unwrap();
+    let recorder = Recorder::new(dir.path().to_path_buf()).unwrap();
+
+    for answer in ["first", "second"] {
+        let mut exchange =
+            RecordedExchange::new("http://localhost/v1/chat/completions", &test_request("hi"))
+                .unwrap();
+        exchange.status = 200;
+        exchange.chunks.push(RecordedChunk::new(
+            5,
+            format!("data: {answer}\n").as_bytes(),
+        ));
+        recorder.save(&exchange).unwrap();
+    }
+    assert!(dir.path().join("0001.json").exists());
+    assert!(dir.path().join("0002.json").exists());
+
+    // Identical requests are served in order, then the last one repeats.
+    let replayer = Replayer::new(dir.path()).unwrap();
+    let request = test_request("hi");
+    assert_eq!(replayer.take(&request).unwrap().body(), "data: first\n");
+    assert_eq!(replayer.take(&request).unwrap().body(), "data: second\n");
+    assert_eq!(replayer.take(&request).unwrap().body(), "data: second\n");
+    assert!(replayer.take(&test_request("other")).is_err());
+
+    // A new recorder continues numbering after existing files.
+    let recorder = Recorder::new(dir.path().to_path_buf()).unwrap();
+    let exchange = RecordedExchange::new("http://localhost", &request).unwrap();
+    recorder.save(&exchange).unwrap();
+    assert!(dir.path().join("0003.json").exists());
+
+    // Numbering continues after the highest file, not the number of files
+    fs::remove_file(dir.path().join("0002.json")).unwrap();
+    let recorder = Recorder::new(dir.path().to_path_buf()).unwrap();
+    recorder.save(&exchange).unwrap();
+    assert!(dir.path().join("0004.json").exists());
+    assert!(!dir.path().join("0002.json").exists());
+    assert_eq!(
+        Replayer::new(dir.path())
+            .unwrap()
+            .take(&test_request("hi"))
+            .unwrap()
+            .body(),
+        "data: first\n"
+    );
+}
+
+#[tokio::test]
+async fn test_replay_keeps_recorded_delays() {
+    let dir = tempfile::tempdir().unwrap();
+    let recorder = Recorder::new(dir.path().to_path_buf()).unwrap();
+    let mut exchange =
+        RecordedExchange::new("http://localhost/v1/chat/completions", &test_request("hi")).unwrap();
+    exchange.status = 200;
+    // The second event is split across chunks, as it can be on the wire.
+    for data in [
+        "data: {\"choices\":[{\"delta\":{\"content\":\"Hel\"}}]}\n",
+        "data: {\"choices\":[{\"delta\":",
+        "{\"content\":\"lo\"}}]}\ndata: [DONE]",
+    ] {
+        exchange
+            .chunks
+            .push(RecordedChunk::new(100, data.as_bytes()));
+    }
+    recorder.save(&exchange).unwrap();
+
+    let exchange_log = ExchangeLog::Replay(Replayer::new(dir.path()).unwrap());
+    let started = std::time::Instant::now();
+    let output = stream_response_collect(
+        &Client::new(),
+        "http://localhost/v1",
+        None,
+        test_request("hi"),
+        5,
+        false,
+        false,
+        None,
+        None,
+        Some(&exchange_log),
+    )
+    .await
+    .unwrap();
+    assert_eq!(output, "Hello");
+    assert!(started.elapsed() >= std::time::Duration::from_millis(300));
+}
+
+#[tokio::test]
+async fn test_replay_character_split_across_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let recorder = Recorder::new(dir.path().to_path_buf()).unwrap();
+    let mut exchange =
+        RecordedExchange::new("http://localhost/v1/chat/completions", &test_request("hi")).unwrap();
+    exchange.status = 200;
+    let body = "data: {\"choices\":[{\"delta\":{\"content\":\"né\"}}]}\ndata: [DONE]\n".as_bytes();
+    let split = body.iter().position(|&byte| byte >= 0x80).unwrap() + 1;
+    for bytes in [&body[..split], &body[split..]] {
+        exchange.chunks.push(RecordedChunk::new(0, bytes));
+    }
+    // Chunks that are not UTF-8 on their own are kept byte for byte
+    assert!(exchange.chunks[0].data_base64.is_some());
+    assert_eq!(exchange.chunks[0].bytes().unwrap(), &body[..split]);
+    recorder.save(&exchange).unwrap();
+
+    let exchange_log = ExchangeLog::Replay(Replayer::new(dir.path()).unwrap());
+    let output = stream_response_collect(
+        &Client::new(),
+        "http://localhost/v1",
+        None,
+        test_request("hi"),
+        5,
+        false,
+        false,
+        None,
+        None,
+        Some(&exchange_log),
+    )
+    .await
+    .unwrap();
+    assert_eq!(output, "né");
+}
+
+#[test]
+fn test_mock_server_subcommand_parsing() {
+    let args = Args::try_parse_from([
+        "ai-cli",
+        "mock-server",
+        "--port",
+        "0",
+        "--response",
+        "one",
+        "--response",
+        "two",
+        "--rate-limit-every",
+        "3",
+    ])
+    .unwrap();
+    let Some(Command::MockServer(settings)) = args.command else {
+        panic!("expected mock-server subcommand");
+    };
+    assert_eq!(settings.port, 0);
+    assert_eq!(settings.responses, vec!["one", "two"]);
+    assert_eq!(settings.rate_limit_every, 3);
+    assert_eq!(settings.model_ids, vec!["mock-model"]);
+}
+
+#[test]
+fn test_mock_server_split_chars() {
+    assert_eq!(
+        mock_server::split_chars("abcdefg", 3),
+        vec!["abc", "def", "g"]
+    );
+    assert_eq!(mock_server::split_chars("", 3), vec![""]);
+}
+
+/// Start a mock server on a free port and return its base URL.
+async fn start_mock_server(settings: MockServerArgs) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(mock_server::serve(listener, settings));
+    format!("http://{addr}/v1")
+}
+
+#[tokio::test]
+async fn test_mock_server_echo_stream() {
+    let base_url = start_mock_server(MockServerArgs {
+        chunk_chars: 2,
+        ..MockServerArgs::default()
+    })
+    .await;
+    let client = Client::new();
+    let output = stream_response_collect(
+        &client,
+        &base_url,
+        None,
+        test_request("echo me please"),
+        5,
+        false,
+        false,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(output, "echo me please");
+}
+
+#[tokio::test]
+async fn test_mock_server_failure_injection() {
+    let base_url = start_mock_server(MockServerArgs {
+        responses: vec!["scripted".to_string()],
+        rate_limit_every: 2,
+        malformed_every: 1,
+        chunk_chars: 3,
+        ..MockServerArgs::default()
+    })
+    .await;
+    let client = Client::new();
+
+    // Malformed SSE events are skipped by the parser.
+    let output = stream_response_collect(
+        &client,
+        &base_url,
+        None,
+        test_request("hi"),
+        5,
+        false,
+        false,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(output, "scripted");
+
+    let err = stream_response_collect(
+        &client,
+        &base_url,
+        None,
+        test_request("hi"),
+        5,
+        false,
+        false,
+        None,
+        None,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(err.to_string().contains("status 429"));
+}
+
+#[tokio::test]
+async fn test_mock_server_models() {
+    let base_url = start_mock_server(MockServerArgs {
+        model_ids: vec!["a".to_string(), "b".to_string()],
+        ..MockServerArgs::default()
+    })
+    .await;
+    let body: serde_json::Value = Client::new()
+        .get(format!("{base_url}/models"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(body["data"][0]["id"], "a");
+    assert_eq!(body["data"][1]["id"], "b");
+}
+
+#[test]
+fn test_serve_subcommand_parsing() {
+    let args = Args::try_parse_from(["ai-cli", "serve", "--port", "9000"]).unwrap();
+    let Some(Command::Serve(settings)) = args.command else {
+        panic!("expected serve subcommand");
+    };
+    assert_eq!(settings.port, 9000);
+    assert_eq!(settings.host, "127.0.0.1");
+}
+
+#[test]
+fn test_proxy_applies_config_defaults() {
+    let config = AppConfig {
+        model: "configured-model".to_string(),
+        temperature: Some(0.2),
+        default_prompt: Some("Summarize this".to_string()),
+        system_prompt: Some("Be brief.".to_string()),
+        ..AppConfig::default()
+    };
+
+    let mut body = serde_json::json!({
+        "messages": [{"role": "user", "content": "hi"}],
+        "stream": true
+    });
+    proxy_server::apply_config_defaults(&mut body, &config);
+    assert_eq!(body["model"], "configured-model");
+    assert!((body["temperature"].as_f64().unwrap() - 0.2).abs() < 1e-6);
+    assert_eq!(body["messages"][0]["role"], "system");
+    assert_eq!(body["messages"][0]["content"], "Be brief.");
+    assert_eq!(body["messages"][1]["content"], "hi");
+    // default_prompt is the CLI's user prompt, not a system message.
+    assert_eq!(body["messages"].as_array().unwrap().len(), 2);
+
+    // Values sent by the client are left alone.
+    let mut body = serde_json::json!({
+        "model": "client-model",
+        "temperature": 1.5,
+        "messages": [{"role": "system", "content": "Custom"}, {"role": "user", "content": "hi"}]
+    });
+    proxy_server::apply_config_defaults(&mut body, &config);
+    assert_eq!(body["model"], "client-model");
+    assert_eq!(body["temperature"], 1.5);
+    assert_eq!(body["messages"].as_array().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_proxy_forwards_to_backend() {
+    let backend_url = start_mock_server(MockServerArgs {
+        model_ids: vec!["backend-model".to_string()],
+        ..MockServerArgs::default()
+    })
+    .await;
+
+    let cache_dir = tempfile::tempdir().unwrap();
+    let state = proxy_server::ProxyState {
+        config: AppConfig {
+            model: "configured-model".to_string(),
+            base_url: backend_url,
+            ..AppConfig::default()
+        },
+        client: Client::new(),
+        cache: Some(ResponseCache::new(
+            cache_dir.path().to_path_buf(),
+            std::time::Duration::from_secs(60),
+            1024 * 1024,
+            false,
+        )),
+        token: None,
+        redactions: Vec::new(),
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_url = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(proxy_server::serve(listener, std::sync::Arc::new(state)));
+
+    let client = Client::new();
+    let request = serde_json::json!({
+        "messages": [{"role": "user", "content": "through the proxy"}],
+        "stream": true
+    });
+    for _ in 0..2 {
+        // The second request is answered from the proxy's cache.
+        let body = client
+            .post(format!("{proxy_url}/chat/completions"))
+            .json(&request)
+            .send()
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(body.contains("\"model\":\"configured-model\""));
+        let mut lines = sse::SseLines::default();
+        lines.push(body.as_bytes());
+        let mut output = String::new();
+        process_sse_lines(&mut lines, &mut output, false).unwrap();
+        assert_eq!(output, "through the proxy");
+    }
+    assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 1);
+
+    let models: serde_json::Value = client
+        .get(format!("{proxy_url}/models"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(models["data"][0]["id"], "backend-model");
+}
+
+#[test]
+fn test_proxy_refuses_remote_bind_without_token() {
+    let loopback: std::net::IpAddr = "127.0.0.1".parse().unwrap();
+    let any: std::net::IpAddr = "0.0.0.0".parse().unwrap();
+    assert!(proxy_server::check_exposure(loopback, false, false).is_ok());
+    assert!(proxy_server::check_exposure("::1".parse().unwrap(), false, false).is_ok());
+    assert!(proxy_server::check_exposure(any, false, true).is_err());
+    assert!(proxy_server::check_exposure(any, true, false).is_err());
+    assert!(proxy_server::check_exposure(any, true, true).is_ok());
+
+    let args = Args::try_parse_from([
+        "ai-cli",
+        "serve",
+        "--host",
+        "0.0.0.0",
+        "--allow-remote",
+        "--token",
+        "secret",
+    ])
+    .unwrap();
+    let Some(Command::Serve(settings)) = args.command else {
+        panic!("expected serve subcommand");
+    };
+    assert!(settings.allow_remote);
+    assert_eq!(settings.token.as_deref(), Some("secret"));
+}
+
+#[tokio::test]
+async fn test_proxy_requires_token() {
+    let backend_url = start_mock_server(MockServerArgs::default()).await;
+    let state = proxy_server::ProxyState {
+        config: AppConfig {
+            base_url: backend_url,
+            ..AppConfig::default()
+        },
+        client: Client::new(),
+        cache: None,
+        token: Some("secret".to_string()),
+        redactions: Vec::new(),
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_url = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(proxy_server::serve(listener, std::sync::Arc::new(state)));
+
+    let client = Client::new();
+    for token in [None, Some("wrong"), Some("secre")] {
+        let mut request = client.get(format!("{proxy_url}/models"));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        assert_eq!(request.send().await.unwrap().status().as_u16(), 401);
+    }
+    let response = client
+        .get(format!("{proxy_url}/models"))
+        .bearer_auth("secret")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[test]
+fn test_proxy_redacts_messages() {
+    let redactions = vec![
+        regex::Regex::new("sk-[A-Za-z0-9]+").unwrap(),
+        regex::Regex::new(r"\b\d{3}-\d{2}-\d{4}\b").unwrap(),
+    ];
+    let mut body = serde_json::json!({
+        "messages": [
+            {"role": "user", "content": "key sk-abc123 and sk-def456"},
+            {"role": "user", "content": [{"type": "text", "text": "ssn 123-45-6789"}]},
+            {"role": "assistant", "content": "nothing to hide"}
+        ]
+    });
+    proxy_server::redact_messages(&mut body, &redactions);
+    assert_eq!(
+        body["messages"][0]["content"],
+        "key [REDACTED] and [REDACTED]"
+    );
+    assert_eq!(body["messages"][1]["content"][0]["text"], "ssn [REDACTED]");
+    assert_eq!(body["messages"][2]["content"], "nothing to hide");
+
+    let config = AppConfig {
+        redact_patterns: vec!["(unclosed".to_string()],
+        ..AppConfig::default()
+    };
+    assert!(validate_chunk_settings(&config).is_err());
+}
+
+#[test]
+fn test_proxy_candidate_models() {
+    let config = AppConfig {
+        fallback_models: vec![
+            "fast".to_string(),
+            "gpt-4o".to_string(),
+            "llama3".to_string(),
+        ],
+        aliases: [("fast".to_string(), "llama3.2:3b".to_string())].into(),
+        ..AppConfig::default()
+    };
+    assert_eq!(
+        proxy_server::candidate_models("gpt-4o", &config),
+        vec!["gpt-4o", "llama3.2:3b", "llama3"]
+    );
+
+    let mut config = AppConfig::default();
+    let env = env_from(&[("AI_CLI_FALLBACK_MODELS", "a, b,,c")]);
+    apply_env_overrides(&mut config, &env).unwrap();
+    assert_eq!(config.fallback_models, vec!["a", "b", "c"]);
+
+    // Patterns with commas in them are given as a TOML array
+    let env = env_from(&[
+        ("AI_CLI_REDACT_PATTERNS", r#"["sk-[a-z]{20,}", "ghp_\\w+"]"#),
+        ("AI_CLI_FALLBACK_MODELS", r#"["x"]"#),
+    ]);
+    apply_env_overrides(&mut config, &env).unwrap();
+    assert_eq!(config.redact_patterns, vec!["sk-[a-z]{20,}", r"ghp_\w+"]);
+    assert_eq!(config.fallback_models, vec!["x"]);
+    let env = env_from(&[("AI_CLI_REDACT_PATTERNS", "[1, 2]")]);
+    assert!(apply_env_overrides(&mut config, &env).is_err());
+}
+
+#[tokio::test]
+async fn test_proxy_falls_back_to_next_model() {
+    let backend_url = start_mock_server(MockServerArgs {
+        model_ids: vec!["backup-model".to_string()],
+        reject_unknown_models: true,
+        ..MockServerArgs::default()
+    })
+    .await;
+    let cache_dir = tempfile::tempdir().unwrap();
+    let state = proxy_server::ProxyState {
+        config: AppConfig {
+            model: "missing-model".to_string(),
+            base_url: backend_url,
+            fallback_models: vec!["backup-model".to_string()],
+            ..AppConfig::default()
+        },
+        client: Client::new(),
+        cache: Some(ResponseCache::new(
+            cache_dir.path().to_path_buf(),
+            std::time::Duration::from_secs(60),
+            1024 * 1024,
+            false,
+        )),
+        token: None,
+        redactions: vec![regex::Regex::new("secret-[0-9]+").unwrap()],
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_url = format!("http://{}/v1", listener.local_addr().unwrap());
+    tokio::spawn(proxy_server::serve(listener, std::sync::Arc::new(state)));
+
+    let response: serde_json::Value = Client::new()
+        .post(format!("{proxy_url}/chat/completions"))
+        .json(&serde_json::json!({
+            "messages": [{"role": "user", "content": "my token is secret-42"}]
+        }))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(response["model"], "backup-model");
+    // The mock echoes the message, so this is what the backend received.
+    assert_eq!(
+        response["choices"][0]["message"]["content"],
+        "my token is [REDACTED]"
+    );
+    // A fallback answer is not cached as the requested model's answer.
+    assert_eq!(std::fs::read_dir(cache_dir.path()).unwrap().count(), 0);
+}
+
+#[test]
+fn test_profile_flag_parsing() {
+    let args = Args::try_parse_from(["ai-cli", "--profile", "staging"]).unwrap();
+    assert_eq!(args.profile.as_deref(), Some("staging"));
+}
+
+/// Parse config file contents the way `load_config` does for a single file.
+fn parse_config(contents: &str, profile: Option<&str>) -> Result<AppConfig> {
+    config_from_layers(vec![toml::from_str(contents)?], profile)
+}
+
+const PROFILE_CONFIG: &str = r#"
+model = "llama3"
+base_url = "http://localhost:11434/v1"
+timeout_secs = 300
+default_profile = "local"
+
+[profiles.local]
+model = "llama3.2:3b"
+
+[profiles.hosted]
+model = "gpt-4o"
+base_url = "https://api.openai.com/v1"
+api_key = "sk-test"
+"#;
+
+#[test]
+fn test_profile_overrides_base_fields() {
+    let config = parse_config(PROFILE_CONFIG, Some("hosted")).unwrap();
+    assert_eq!(config.active_profile.as_deref(), Some("hosted"));
+    assert_eq!(config.model, "gpt-4o");
+    assert_eq!(config.base_url, "https://api.openai.com/v1");
+    assert_eq!(config.api_key.as_deref(), Some("sk-test"));
+    // Fields the profile does not set keep their base values.
+    assert_eq!(config.timeout_secs, 300);
+}
+
+#[test]
+fn test_default_profile_applied() {
+    let config = parse_config(PROFILE_CONFIG, None).unwrap();
+    assert_eq!(config.active_profile.as_deref(), Some("local"));
+    assert_eq!(config.model, "llama3.2:3b");
+    assert_eq!(config.base_url, "http://localhost:11434/v1");
+}
+
+#[test]
+fn test_no_profile_uses_base_config() {
+    let contents = PROFILE_CONFIG.replace("default_profile = \"local\"\n", "");
+    let config = parse_config(&contents, None).unwrap();
+    assert!(config.active_profile.is_none());
+    assert_eq!(config.model, "llama3");
+}
+
+#[test]
+fn test_unknown_profile_errors() {
+    let err = parse_config(PROFILE_CONFIG, Some("missing")).unwrap_err();
+    let message = err.to_string();
+    assert!(message.contains("Profile 'missing' not found"));
+    assert!(message.contains("hosted, local"));
+}
+
+fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: std::collections::HashMap<String, String> = vars
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn test_env_overrides_config_fields() {
+    let mut config = AppConfig::default();
+    let env = env_from(&[
+        ("AI_CLI_MODEL", "gpt-4o"),
+        ("AI_CLI_BASE_URL", "https://api.openai.com/v1"),
+        ("AI_CLI_API_KEY", "sk-env"),
+        ("AI_CLI_TEMPERATURE", "0.5"),
+        ("AI_CLI_TIMEOUT_SECS", "30"),
+        ("AI_CLI_INPUT_MODE", "chunked"),
+        ("AI_CLI_CHUNK_SIZE_CHARS", "8000"),
+        ("AI_CLI_AGGREGATE_CHUNKS", "false"),
+        ("AI_CLI_NO_PROGRESS", "1"),
+        ("AI_CLI_CACHE_DIR", "/tmp/ai-cli-cache"),
+        ("AI_CLI_REDACT_PATTERNS", "secret, token"),
+    ]);
+    apply_env_overrides(&mut config, &env).unwrap();
+
+    assert_eq!(config.model, "gpt-4o");
+    assert_eq!(config.base_url, "https://api.openai.com/v1");
+    assert_eq!(config.api_key.as_deref(), Some("sk-env"));
+    assert_eq!(config.temperature, Some(0.5));
+    assert_eq!(config.timeout_secs, 30);
+    assert_eq!(config.input_mode, InputMode::Chunked);
+    assert_eq!(config.chunk_size_chars, 8000);
+    assert!(!config.aggregate_chunks);
+    assert!(config.no_progress);
+    assert_eq!(config.cache_dir, Some(PathBuf::from("/tmp/ai-cli-cache")));
+    assert_eq!(config.redact_patterns, vec!["secret", "token"]);
+}
+
+#[test]
+fn test_env_openai_variables_lower_precedence() {
+    let mut config = AppConfig::default();
+    let env = env_from(&[
+        ("OPENAI_API_KEY", "sk-openai"),
+        ("OPENAI_BASE_URL", "https://api.openai.com/v1"),
+    ]);
+    apply_env_overrides(&mut config, &env).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-openai"));
+    assert_eq!(config.base_url, "https://api.openai.com/v1");
+
+    let mut config = AppConfig::default();
+    let env = env_from(&[
+        ("OPENAI_API_KEY", "sk-openai"),
+        ("AI_CLI_API_KEY", "sk-ai-cli"),
+    ]);
+    apply_env_overrides(&mut config, &env).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-ai-cli"));
+}
+
+#[test]
+fn test_env_empty_and_invalid_values() {
+    let mut config = AppConfig::default();
+    // Empty variables are ignored.
+    apply_env_overrides(&mut config, &env_from(&[("AI_CLI_MODEL", "")])).unwrap();
+    assert_eq!(config.model, "llama3");
+
+    let err = apply_env_overrides(&mut config, &env_from(&[("AI_CLI_TIMEOUT_SECS", "soon")]))
+        .unwrap_err();
+    assert!(err.to_string().contains("AI_CLI_TIMEOUT_SECS"));
+    assert!(
+        apply_env_overrides(&mut config, &env_from(&[("AI_CLI_NO_PROGRESS", "maybe")])).is_err()
+    );
+    assert!(apply_env_overrides(
+        &mut config,
+        &env_from(&[("AI_CLI_INPUT_MODE", "sometimes")])
+    )
+    .is_err());
+}
+
+#[test]
+fn test_config_location_flags() {
+    let args = Args::try_parse_from(["ai-cli", "--config", "/etc/ai-cli.toml"]).unwrap();
+    assert_eq!(args.config, Some(PathBuf::from("/etc/ai-cli.toml")));
+    assert_eq!(
+        resolve_config_path(&args).unwrap(),
+        PathBuf::from("/etc/ai-cli.toml")
+    );
+
+    // Global flags may follow a subcommand.
+    let args =
+        Args::try_parse_from(["ai-cli", "config", "init", "--config", "/tmp/c.toml"]).unwrap();
+    assert_eq!(args.config, Some(PathBuf::from("/tmp/c.toml")));
+
+    assert!(Args::try_parse_from(["ai-cli", "--config", "a.toml", "--no-config"]).is_err());
+}
+
+#[test]
+fn test_config_dir_respects_xdg_config_home() {
+    assert_eq!(
+        config_dir_from(Some(PathBuf::from("/xdg/config"))).unwrap(),
+        PathBuf::from("/xdg/config/ai-cli")
+    );
+    // Relative values are ignored in favour of ~/.config.
+    let fallback = config_dir_from(Some(PathBuf::from("relative"))).unwrap();
+    assert!(fallback.ends_with(".config/ai-cli"));
+    assert_eq!(config_dir_from(None).unwrap(), fallback);
+}
+
+#[test]
+fn test_load_config_missing_file_is_read_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("ai-cli").join("config.toml");
+
+    let config = load_config(&config_path, false, None, None).unwrap();
+    assert_eq!(config.model, "llama3");
+    assert!(!config_path.exists());
+    assert!(!config_path.parent().unwrap().exists());
+
+    // An explicitly requested file must exist.
+    assert!(load_config(&config_path, true, None, None).is_err());
+    // So must a requested profile.
+    assert!(load_config(&config_path, false, None, Some("work")).is_err());
+}
+
+#[test]
+fn test_create_default_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let config_path = dir.path().join("nested").join("config.toml");
+
+    create_default_config(&config_path, false).unwrap();
+    let config = load_config(&config_path, true, None, None).unwrap();
+    assert_eq!(config.model, "llama3");
+
+    // Existing files are only replaced with --force.
+    assert!(create_default_config(&config_path, false).is_err());
+    create_default_config(&config_path, true).unwrap();
+}
+
+#[test]
+fn test_find_project_config_walks_up() {
+    let root = tempfile::tempdir().unwrap();
+    let nested = root.path().join("team").join("service").join("src");
+    std::fs::create_dir_all(&nested).unwrap();
+    assert!(find_project_config(&nested).is_none_or(|path| !path.starts_with(root.path())));
+
+    let project_config = root.path().join("team").join(".ai-cli.toml");
+    std::fs::write(&project_config, "model = \"team-model\"\n").unwrap();
+    assert_eq!(find_project_config(&nested), Some(project_config.clone()));
+
+    // The nearest file wins.
+    let service_config = root
+        .path()
+        .join("team")
+        .join("service")
+        .join(".ai-cli.toml");
+    std::fs::write(&service_config, "model = \"service-model\"\n").unwrap();
+    assert_eq!(find_project_config(&nested), Some(service_config));
+}
+
+#[test]
+fn test_project_config_merges_over_user_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let user_config = dir.path().join("config.toml");
+    std::fs::write(
+        &user_config,
+        "model = \"llama3\"\nbase_url = \"http://localhost:11434/v1\"\ntimeout_secs = 120\napi_key = \"sk-user\"\n\n[profiles.hosted]\nmodel = \"gpt-4o\"\n",
+    )
+    .unwrap();
+
+    let project_dir = dir.path().join("repo");
+    std::fs::create_dir_all(&project_dir).unwrap();
+    let project_config = project_dir.join(".ai-cli.toml");
+    std::fs::write(
+        &project_config,
+        "model = \"codellama\"\nchunk_size_chars = 4000\nchunk_overlap_chars = 200\nchunk_prompt_file = \"prompts/chunk.txt\"\n\n[profiles.hosted]\ntimeout_secs = 30\n",
+    )
+    .unwrap();
+
+    let config = load_config(&user_config, true, Some(&project_config), None).unwrap();
+    assert_eq!(config.model, "codellama");
+    assert_eq!(config.timeout_secs, 120);
+    assert_eq!(config.api_key.as_deref(), Some("sk-user"));
+    assert_eq!(config.chunk_size_chars, 4000);
+    assert_eq!(
+        config.chunk_prompt_file,
+        Some(project_dir.join("prompts/chunk.txt"))
+    );
+
+    // Profiles from both files are merged key by key.
+    let config = load_config(&user_config, true, Some(&project_config), Some("hosted")).unwrap();
+    assert_eq!(config.model, "gpt-4o");
+    assert_eq!(config.timeout_secs, 30);
+}
+
+#[test]
+fn test_project_config_cannot_set_keys_or_endpoint() {
+    let dir = tempfile::tempdir().unwrap();
+    let user_config = dir.path().join("config.toml");
+    std::fs::write(
+        &user_config,
+        "base_url = \"http://localhost:11434/v1\"\napi_key_env = \"MY_KEY\"\n",
+    )
+    .unwrap();
+    let project_config = dir.path().join(".ai-cli.toml");
+    std::fs::write(
+        &project_config,
+        r#"
+model = "codellama"
+base_url = "https://attacker.example/v1"
+api_key_command = "touch pwned"
+api_key_env = "HOME"
+
+[profiles.hosted]
+api_key_file = "/etc/passwd"
+api_key = "sk-project"
+
+[profiles.hosted.models.gpt-4o]
+base_url = "https://attacker.example/v1"
+
+[models.gpt-4o]
+api_key_env = "HOME"
+temperature = 0.1
+"#,
+    )
+    .unwrap();
+
+    let layers = load_config_layers(&user_config, true, Some(&project_config)).unwrap();
+    assert!(layers[0].ignored_keys.is_empty());
+    let mut ignored = layers[1].ignored_keys.clone();
+    ignored.sort();
+    assert_eq!(
+        ignored,
+        vec![
+            "api_key_command",
+            "api_key_env",
+            "base_url",
+            "models.gpt-4o.api_key_env",
+            "profiles.hosted.api_key",
+            "profiles.hosted.api_key_file",
+            "profiles.hosted.models.gpt-4o.base_url",
+        ]
+    );
+
+    let config = load_config(&user_config, true, Some(&project_config), Some("hosted")).unwrap();
+    assert_eq!(config.model, "codellama");
+    assert_eq!(config.base_url, "http://localhost:11434/v1");
+    assert_eq!(config.api_key_env.as_deref(), Some("MY_KEY"));
+    assert_eq!(config.api_key_command, None);
+    assert_eq!(config.api_key_file, None);
+    assert_eq!(config.api_key, None);
+    // Other model settings from the project file still apply.
+    assert_eq!(config.models["gpt-4o"].temperature, Some(0.1));
+    assert_eq!(config.models["gpt-4o"].base_url, None);
+}
+
+#[test]
+fn test_project_config_paths_stay_in_project() {
+    let dir = tempfile::tempdir().unwrap();
+    let project_config = dir.path().join(".ai-cli.toml");
+    std::fs::write(
+        &project_config,
+        r#"
+cache_dir = "/home/user/.ssh"
+chunk_prompt_file = "../../secrets.txt"
+aggregate_prompt_file = "prompts/aggregate.txt"
+
+[profiles.hosted]
+tokenizer_file = "/etc/passwd"
+chunk_prompt_file = "./prompts/chunk.txt"
+"#,
+    )
+    .unwrap();
+
+    let missing = dir.path().join("missing.toml");
+    let layers = load_config_layers(&missing, false, Some(&project_config)).unwrap();
+    let mut ignored = layers[0].ignored_keys.clone();
+    ignored.sort();
+    assert_eq!(
+        ignored,
+        vec![
+            "cache_dir",
+            "chunk_prompt_file",
+            "profiles.hosted.tokenizer_file"
+        ]
+    );
+
+    // Paths inside the project still work, relative to the project file
+    let config = load_config(&missing, false, Some(&project_config), Some("hosted")).unwrap();
+    assert_eq!(config.cache_dir, None);
+    assert_eq!(config.tokenizer_file, None);
+    assert_eq!(
+        config.aggregate_prompt_file,
+        Some(dir.path().join("prompts/aggregate.txt"))
+    );
+    assert_eq!(
+        config.chunk_prompt_file,
+        Some(dir.path().join("./prompts/chunk.txt"))
+    );
+
+    // The user config file may point anywhere
+    let user_config = dir.path().join("config.toml");
+    std::fs::write(&user_config, "cache_dir = \"/var/cache/ai-cli\"\n").unwrap();
+    let config = load_config(&user_config, true, None, None).unwrap();
+    assert_eq!(config.cache_dir, Some(PathBuf::from("/var/cache/ai-cli")));
+}
+
+#[test]
+fn test_project_config_without_user_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let project_config = dir.path().join(".ai-cli.toml");
+    std::fs::write(&project_config, "model = \"team-model\"\n").unwrap();
+
+    // A partial project file is layered over the built-in defaults.
+    let config = load_config(
+        &dir.path().join("missing.toml"),
+        false,
+        Some(&project_config),
+        None,
+    )
+    .unwrap();
+    assert_eq!(config.model, "team-model");
+    assert_eq!(config.base_url, "http://localhost:11434/v1");
+    assert_eq!(config.timeout_secs, 300);
+}
+
+#[test]
+fn test_config_keys_match_app_config() {
+    let config = AppConfig {
+        temperature: Some(0.5),
+        chunk_prompt_file: Some(PathBuf::from("prompt.txt")),
+        aggregate_prompt_file: Some(PathBuf::from("aggregate.txt")),
+        cache_dir: Some(PathBuf::from("cache")),
+        default_profile: Some("work".to_string()),
+        api_key: Some("sk-test".to_string()),
+        api_key_command: Some("pass show openai".to_string()),
+        api_key_file: Some(PathBuf::from("key.txt")),
+        api_key_env: Some("MY_KEY".to_string()),
+        default_prompt: Some("Be brief".to_string()),
+        system_prompt: Some("Answer concisely".to_string()),
+        fallback_models: vec!["gpt-4o-mini".to_string()],
+        redact_patterns: vec!["sk-[a-z]+".to_string()],
+        chunk_size_tokens: Some(8000),
+        chunk_overlap_tokens: Some(200),
+        tokenizer_file: Some(PathBuf::from("cl100k_base.tiktoken")),
+        aliases: [("fast".to_string(), "llama3.2:3b".to_string())].into(),
+        models: [(
+            "gpt-4o".to_string(),
+            model_settings::ModelSettings::default(),
+        )]
+        .into(),
+        ..AppConfig::default()
+    };
+    let table = toml::Table::try_from(&config).unwrap();
+    let mut keys: Vec<&str> = table.keys().map(String::as_str).collect();
+    let mut expected = CONFIG_KEYS.to_vec();
+    keys.sort_unstable();
+    expected.sort_unstable();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn test_unknown_config_keys() {
+    let table: toml::Table = toml::from_str(
+        "model = \"m\"\nmodle = \"typo\"\n\n[profiles.work]\ntemperature = 0.2\ntemprature = 0.3\ndefault_profile = \"x\"\n",
+    )
+    .unwrap();
+    assert_eq!(
+        unknown_config_keys(&table),
+        vec![
+            "modle",
+            "profiles.work.default_profile",
+            "profiles.work.temprature"
+        ]
+    );
+}
+
+#[test]
+fn test_config_set_value_parsing() {
+    use config_command::parse_config_value;
+
+    // String keys never need quotes, even if the text looks like another type.
+    assert_eq!(
+        parse_config_value(&["model"], "true").unwrap().as_str(),
+        Some("true")
+    );
+    assert_eq!(
+        parse_config_value(&["temperature"], "1")
+            .unwrap()
+            .as_float(),
+        Some(1.0)
+    );
+    assert_eq!(
+        parse_config_value(&["timeout_secs"], "60")
+            .unwrap()
+            .as_integer(),
+        Some(60)
+    );
+    assert_eq!(
+        parse_config_value(&["aggregate_chunks"], "false")
+            .unwrap()
+            .as_bool(),
+        Some(false)
+    );
+    assert!(parse_config_value(&["temperature"], "warm").is_err());
+}
+
+#[test]
+fn test_config_set_and_unset_keep_comments() {
+    use config_command::{parse_config_value, remove_document_value, set_document_value};
+
+    let mut document: toml_edit::DocumentMut = "# My settings\nmodel = \"llama3\" # local\n"
+        .parse()
+        .unwrap();
+    set_document_value(
+        &mut document,
+        &["model"],
+        parse_config_value(&["model"], "gpt-4o").unwrap(),
+    )
+    .unwrap();
+    set_document_value(
+        &mut document,
+        &["profiles", "work", "temperature"],
+        parse_config_value(&["temperature"], "0.2").unwrap(),
+    )
+    .unwrap();
+    let contents = document.to_string();
+    assert!(contents.starts_with("# My settings\n"));
+    assert!(contents.contains("model = \"gpt-4o\" # local\n"));
+    assert!(contents.contains("[profiles.work]\ntemperature = 0.2"));
+
+    assert!(remove_document_value(
+        &mut document,
+        &["profiles", "work", "temperature"]
+    ));
+    assert!(!remove_document_value(&mut document, &["api_key"]));
+    assert!(!remove_document_value(
+        &mut document,
+        &["profiles", "missing", "model"]
+    ));
+    assert!(!document.to_string().contains("temperature"));
+}
+
+#[test]
+fn test_mask_secret() {
+    assert_eq!(config_command::mask_secret("sk-abcdefghijkl"), "***ijkl");
+    assert_eq!(config_command::mask_secret("short"), "***");
+}
+
+#[test]
+fn test_config_get_masks_secrets() {
+    let key = toml::Value::String("sk-abcdefghijkl".to_string());
+    assert_eq!(
+        config_command::format_value(&["api_key"], &key, false),
+        "***ijkl"
+    );
+    assert_eq!(
+        config_command::format_value(&["profiles", "work", "api_key"], &key, false),
+        "***ijkl"
+    );
+    assert_eq!(
+        config_command::format_value(&["api_key"], &key, true),
+        "sk-abcdefghijkl"
+    );
+    assert_eq!(
+        config_command::format_value(&["model"], &toml::Value::String("gpt-4o".into()), false),
+        "gpt-4o"
+    );
+    assert_eq!(
+        config_command::format_value(&["timeout_secs"], &toml::Value::Integer(30), false),
+        "30"
+    );
+
+    let args = Args::try_parse_from(["ai-cli", "config", "get", "api_key", "--reveal"]).unwrap();
+    let Some(Command::Config(config_args)) = args.command else {
+        panic!("expected config subcommand");
+    };
+    assert!(matches!(
+        config_args.command,
+        config_command::ConfigCommand::Get { reveal: true, .. }
+    ));
+}
+
+#[test]
+fn test_config_show_sources() {
+    let user: toml::Table = toml::from_str(
+        "model = \"gpt-4o\"\napi_key = \"sk-user-secret\"\n\n[profiles.work]\ntemperature = 0.2\n",
+    )
+    .unwrap();
+    let project: toml::Table =
+        toml::from_str("timeout_secs = 60\n\n[models.gpt-4o]\ncontext_window = 128000\n").unwrap();
+    let layers = [
+        (Path::new("/home/me/config.toml"), &user),
+        (Path::new("/work/.ai-cli.toml"), &project),
+    ];
+    let args = Args {
+        timeout: Some(30),
+        no_cache: true,
+        ..default_test_args()
+    };
+    let env = env_from(&[("OPENAI_BASE_URL", "https://example.com/v1")]);
+
+    let config = AppConfig {
+        model: "gpt-4o".to_string(),
+        api_key: Some("sk-user-secret".to_string()),
+        models: [(
+            "gpt-4o".to_string(),
+            model_settings::ModelSettings {
+                context_window: Some(128_000),
+                ..Default::default()
+            },
+        )]
+        .into(),
+        active_profile: Some("work".to_string()),
+        ..AppConfig::default()
+    };
+    let sources = config_command::config_sources(&args, &layers, &config, &env);
+    assert_eq!(sources["model"], "/home/me/config.toml");
+    assert_eq!(
+        sources["temperature"],
+        "profile 'work' in /home/me/config.toml"
+    );
+    assert_eq!(sources["base_url"], "env OPENAI_BASE_URL");
+    assert_eq!(sources["timeout_secs"], "flag --timeout");
+    assert_eq!(sources["cache_enabled"], "flag --no-cache");
+    assert_eq!(sources["max_chunks"], "default");
+    assert_eq!(
+        sources["chunk_size_chars"],
+        "model 'gpt-4o' in /work/.ai-cli.toml"
+    );
+
+    let output = config_command::format_config(&config, &sources).unwrap();
+    assert!(output.starts_with("# profile: work\n"));
+    assert!(output.contains("model = \"gpt-4o\"  # /home/me/config.toml\n"));
+    assert!(output.contains("api_key = \"***cret\"  # /home/me/config.toml\n"));
+    assert!(!output.contains("sk-user-secret"));
+    assert!(output.contains("# temperature is not set\n"));
+}
+
+#[test]
+fn test_api_key_from_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let key_file = dir.path().join("openai.key");
+    std::fs::write(&key_file, "sk-from-file\n").unwrap();
+
+    let mut config = AppConfig {
+        api_key_file: Some(key_file.clone()),
+        ..AppConfig::default()
+    };
+    secrets::resolve_api_key(&mut config).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-from-file"));
+
+    // A key given directly takes precedence over the indirect sources.
+    let mut config = AppConfig {
+        api_key: Some("sk-direct".to_string()),
+        api_key_file: Some(key_file),
+        ..AppConfig::default()
+    };
+    secrets::resolve_api_key(&mut config).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-direct"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_api_key_command() {
+    let mut config = AppConfig {
+        api_key_command: Some("printf 'sk-from-command\\n'".to_string()),
+        ..AppConfig::default()
+    };
+    secrets::resolve_api_key(&mut config).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-from-command"));
+
+    // Failures name the command but never include its output.
+    let mut config = AppConfig {
+        api_key_command: Some("echo sk-$((40 + 2)); exit 3".to_string()),
+        ..AppConfig::default()
+    };
+    let error = format!("{:#}", secrets::resolve_api_key(&mut config).unwrap_err());
+    assert!(error.contains("api_key_command"));
+    assert!(!error.contains("sk-42"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_project_config_cannot_supply_api_key_command_or_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("pwned");
+    let key_file = dir.path().join("project.key");
+    std::fs::write(&key_file, "sk-from-project-file\n").unwrap();
+    let user_config = dir.path().join("config.toml");
+    std::fs::write(&user_config, "model = \"llama3\"\n").unwrap();
+    let project_config = dir.path().join(".ai-cli.toml");
+    std::fs::write(
+        &project_config,
+        format!(
+            "api_key_command = \"touch {}\"\n\n[profiles.hosted]\napi_key_file = \"{}\"\n",
+            marker.display(),
+            key_file.display()
+        ),
+    )
+    .unwrap();
+
+    for profile in [None, Some("hosted")] {
+        let mut config = load_config(&user_config, true, Some(&project_config), profile).unwrap();
+        secrets::resolve_api_key(&mut config).unwrap();
+        assert_eq!(config.api_key, None);
+    }
+    assert!(!marker.exists());
+
+    // The same settings are honoured from the user config file.
+    std::fs::write(
+        &user_config,
+        format!("api_key_file = \"{}\"\n", key_file.display()),
+    )
+    .unwrap();
+    let mut config = load_config(&user_config, true, Some(&project_config), None).unwrap();
+    secrets::resolve_api_key(&mut config).unwrap();
+    assert_eq!(config.api_key.as_deref(), Some("sk-from-project-file"));
+    assert!(!marker.exists());
+}
+
+#[test]
+fn test_api_key_env_missing() {
+    let mut config = AppConfig {
+        api_key_env: Some("AI_CLI_TEST_KEY_THAT_IS_NOT_SET".to_string()),
+        ..AppConfig::default()
+    };
+    let error = secrets::resolve_api_key(&mut config).unwrap_err();
+    assert!(error
+        .to_string()
+        .contains("AI_CLI_TEST_KEY_THAT_IS_NOT_SET"));
+}
+
+#[test]
+fn test_table_has_api_key() {
+    let plain: toml::Table = toml::from_str("api_key_command = \"pass show openai\"\n").unwrap();
+    assert!(!secrets::table_has_api_key(&plain));
+    let profile: toml::Table =
+        toml::from_str("[profiles.hosted]\napi_key = \"sk-secret\"\n").unwrap();
+    assert!(secrets::table_has_api_key(&profile));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_world_readable_detection() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(&path, "api_key = \"sk-secret\"\n").unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+    assert!(secrets::is_world_readable(&path));
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+    assert!(!secrets::is_world_readable(&path));
+}
+
+#[test]
+fn test_partial_config_deserializes_with_defaults() {
+    let config: AppConfig = toml::from_str("temperature = 0.3\n").unwrap();
+    assert_eq!(config.model, "llama3");
+    assert_eq!(config.base_url, "http://localhost:11434/v1");
+    assert_eq!(config.timeout_secs, 300);
+    assert_eq!(config.temperature, Some(0.3));
+}
+
+#[test]
+fn test_config_type_errors_have_line_and_column() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    std::fs::write(
+        &path,
+        "model = \"m\"\n\n[profiles.fast]\ntimeout_secs = \"soon\"\n",
+    )
+    .unwrap();
+
+    let error = format!("{:#}", read_config_table(&path).unwrap_err());
+    assert!(error.contains("line 4, column 16"), "{error}");
+    assert!(error.contains("timeout_secs"), "{error}");
+}
+
+#[test]
+fn test_unknown_key_suggestions() {
+    assert_eq!(suggest_config_key("chunk_size"), Some("chunk_size_chars"));
+    assert_eq!(suggest_config_key("temprature"), Some("temperature"));
+    assert_eq!(suggest_config_key("profiles.work.modle"), Some("model"));
+    assert_eq!(suggest_config_key("colour_scheme"), None);
+    assert_eq!(
+        unknown_key_message(Path::new("config.toml"), "chunk_size"),
+        "config.toml: unknown key 'chunk_size' (did you mean 'chunk_size_chars'?)"
+    );
+
+    let table: toml::Table = toml::from_str("[profiles.work]\nconfig_version = 1\n").unwrap();
+    assert_eq!(
+        unknown_config_keys(&table),
+        vec!["profiles.work.config_version"]
+    );
+}
+
+#[test]
+fn test_config_migration_renames_keys() {
+    use config_migration::{migrate_document_with, migrate_table_with, Migration, CONFIG_VERSION};
+
+    let migrations = [Migration {
+        version: 1,
+        renamed_keys: &[("chunk_size", "chunk_size_chars")],
+    }];
+    let path = Path::new("config.toml");
+
+    let mut table: toml::Table =
+        toml::from_str("chunk_size = 4000\n\n[profiles.big]\nchunk_size = 32000\n").unwrap();
+    migrate_table_with(&mut table, path, &migrations);
+    assert_eq!(table["chunk_size_chars"].as_integer(), Some(4000));
+    assert_eq!(
+        table["profiles"]["big"]["chunk_size_chars"].as_integer(),
+        Some(32000)
+    );
+    assert!(!table.contains_key("chunk_size"));
+    assert_eq!(
+        table["config_version"].as_integer(),
+        Some(i64::from(CONFIG_VERSION))
+    );
+
+    // Files already at the new version are left alone.
+    let mut table: toml::Table = toml::from_str("config_version = 1\nchunk_size = 4000\n").unwrap();
+    migrate_table_with(&mut table, path, &migrations);
+    assert!(table.contains_key("chunk_size"));
+
+    let mut document: toml_edit::DocumentMut =
+        "# Tuned for our docs\nchunk_size = 4000\n".parse().unwrap();
+    migrate_document_with(&mut document, path, &migrations);
+    let contents = document.to_string();
+    assert!(contents.contains("# Tuned for our docs"));
+    assert!(contents.contains("chunk_size_chars = 4000"));
+    assert!(contents.contains("config_version = 1"));
+}
+
+const MODELS_CONFIG: &str = r#"
+model = "fast"
+temperature = 0.7
+chunk_overlap_chars = 500
+api_key = "sk-local"
+
+[aliases]
+fast = "llama3.2:3b"
+smart = "gpt-4o"
+
+[models."llama3.2:3b"]
+context_window = 8192
+
+[models.gpt-4o]
+temperature = 0.2
+chunk_size_chars = 200000
+base_url = "https://api.openai.com/v1"
+api_key_env = "OPENAI_KEY_FOR_TESTS"
+
+[profiles.hosted.aliases]
+fast = "gpt-4o-mini"
+"#;
+
+fn models_config_layers() -> Vec<ConfigLayer> {
+    vec![ConfigLayer {
+        path: PathBuf::from("config.toml"),
+        table: toml::from_str(MODELS_CONFIG).unwrap(),
+        ignored_keys: Vec::new(),
+    }]
+}
+
+#[test]
+fn test_config_get_profile_value() {
+    let layer = |path: &str, contents: &str| ConfigLayer {
+        path: PathBuf::from(path),
+        table: toml::from_str(contents).unwrap(),
+        ignored_keys: Vec::new(),
+    };
+    let layers = vec![
+        layer(
+            "config.toml",
+            "model = \"gpt-4o\"\n\n[profiles.work]\nmodel = \"user-model\"\n\n[profiles.work.aliases]\nfast = \"gpt-4o-mini\"\n",
+        ),
+        layer(".ai-cli.toml", "[profiles.work]\nmodel = \"project-model\"\n"),
+    ];
+    let get = |key| {
+        let path = config_command::check_key(key).unwrap();
+        config_command::profile_value(&layers, &path).cloned()
+    };
+
+    // Profile keys are read from the files, the last one that sets them winning
+    assert_eq!(get("profiles.work.model"), Some("project-model".into()));
+    assert_eq!(
+        get("profiles.work.aliases.fast"),
+        Some("gpt-4o-mini".into())
+    );
+    assert_eq!(get("profiles.work.temperature"), None);
+    assert_eq!(get("profiles.missing.model"), None);
+}
+
+#[test]
+fn test_model_alias_and_context_window() {
+    let config = resolve_config(&default_test_args(), &models_config_layers()).unwrap();
+    assert_eq!(config.model, "llama3.2:3b");
+    assert_eq!(config.chunk_size_chars, 16384);
+    assert_eq!(config.temperature, Some(0.7));
+    assert_eq!(config.api_key.as_deref(), Some("sk-local"));
+}
+
+#[test]
+fn test_context_window_keeps_configured_chunk_size() {
+    // A chunk size set in a config file wins over one derived from the
+    // model's context window.
+    let mut table: toml::Table = toml::from_str(MODELS_CONFIG).unwrap();
+    table.insert("chunk_size_chars".to_string(), 8000.into());
+    let layers = vec![ConfigLayer {
+        path: PathBuf::from("config.toml"),
+        table,
+        ignored_keys: Vec::new(),
+    }];
+    let config = resolve_config(&default_test_args(), &layers).unwrap();
+    assert_eq!(config.model, "llama3.2:3b");
+    assert_eq!(config.chunk_size_chars, 8000);
+
+    // A small window also shrinks the default overlap, so the derived
+    // settings are valid.
+    let table: toml::Table =
+        toml::from_str("model = \"tiny\"\n\n[models.tiny]\ncontext_window = 400\n").unwrap();
+    let layers = vec![ConfigLayer {
+        path: PathBuf::from("config.toml"),
+        table,
+        ignored_keys: Vec::new(),
+    }];
+    let config = resolve_config(&default_test_args(), &layers).unwrap();
+    assert_eq!(config.chunk_size_chars, 800);
+    assert_eq!(config.chunk_overlap_chars, 50);
+    validate_chunk_settings(&config).unwrap();
+}
+
+#[test]
+fn test_model_settings_from_command_line_alias() {
+    let args = Args {
+        model: Some("smart".to_string()),
+        ..default_test_args()
+    };
+    let config = resolve_config(&args, &models_config_layers()).unwrap();
+    assert_eq!(config.model, "gpt-4o");
+    assert_eq!(config.temperature, Some(0.2));
+    assert_eq!(config.chunk_size_chars, 200000);
+    assert_eq!(config.base_url, "https://api.openai.com/v1");
+    // The provider's key source replaces the key from the file.
+    assert_eq!(config.api_key, None);
+    assert_eq!(config.api_key_env.as_deref(), Some("OPENAI_KEY_FOR_TESTS"));
+
+    // Command line values still win over per-model defaults.
+    let args = Args {
+        model: Some("smart".to_string()),
+        temperature: Some(1.0),
+        ..default_test_args()
+    };
+    let config = resolve_config(&args, &models_config_layers()).unwrap();
+    assert_eq!(config.temperature, Some(1.0));
+
+    // Unknown names are passed through as model names.
+    let args = Args {
+        model: Some("mistral".to_string()),
+        ..default_test_args()
+    };
+    let config = resolve_config(&args, &models_config_layers()).unwrap();
+    assert_eq!(config.model, "mistral");
+    assert_eq!(config.chunk_size_chars, 16000);
+}
+
+#[test]
+fn test_profile_aliases_merge_with_top_level() {
+    let args = Args {
+        profile: Some("hosted".to_string()),
+        ..default_test_args()
+    };
+    let config = resolve_config(&args, &models_config_layers()).unwrap();
+    assert_eq!(config.model, "gpt-4o-mini");
+    assert_eq!(config.aliases["smart"], "gpt-4o");
+}
+
+#[test]
+fn test_chunk_size_for_context() {
+    assert_eq!(model_settings::chunk_size_for_context(8192), 16384);
+    assert_eq!(model_settings::chunk_size_for_context(128_000), 256_000);
+}
+
+#[test]
+fn test_model_config_keys() {
+    use config_command::check_key;
+
+    assert_eq!(check_key("aliases.fast").unwrap(), vec!["aliases", "fast"]);
+    assert_eq!(
+        check_key("models.llama3.2:3b.context_window").unwrap(),
+        vec!["models", "llama3.2:3b", "context_window"]
+    );
+    assert_eq!(
+        check_key("profiles.work.models.gpt-4o.temperature").unwrap(),
+        vec!["profiles", "work", "models", "gpt-4o", "temperature"]
+    );
+    assert!(check_key("models.gpt-4o.max_tokens").is_err());
+    assert!(check_key("models.temperature").is_err());
+    assert!(check_key("aliases.").is_err());
+
+    // Alias targets are always strings.
+    assert_eq!(
+        config_command::parse_config_value(&["aliases", "v3"], "3")
+            .unwrap()
+            .as_str(),
+        Some("3")
+    );
+
+    let table: toml::Table = toml::from_str("[models.gpt-4o]\ncontext_windw = 128000\n").unwrap();
+    assert_eq!(
+        unknown_config_keys(&table),
+        vec!["models.gpt-4o.context_windw"]
+    );
+    assert_eq!(
+        suggest_config_key("models.gpt-4o.context_windw"),
+        Some("context_window")
+    );
+}
+
+#[test]
+fn test_proxy_resolves_model_aliases() {
+    let config = AppConfig {
+        aliases: [("smart".to_string(), "gpt-4o".to_string())].into(),
+        ..AppConfig::default()
+    };
+    let mut body = serde_json::json!({"model": "smart", "messages": []});
+    proxy_server::apply_config_defaults(&mut body, &config);
+    assert_eq!(body["model"], "gpt-4o");
+}
+
+#[tokio::test]
+async fn test_models_command_lists_and_checks() {
+    let base_url = start_mock_server(MockServerArgs {
+        model_ids: vec!["llama3.2:3b".to_string(), "gpt-4o".to_string()],
+        ..MockServerArgs::default()
+    })
+    .await;
+    let config = AppConfig {
+        base_url,
+        model: "gpt-4o".to_string(),
+        aliases: [("smart".to_string(), "gpt-4o".to_string())].into(),
+        ..AppConfig::default()
+    };
+
+    let models = models_command::fetch_models(&Client::new(), &config)
+        .await
+        .unwrap();
+    let ids: Vec<&str> = models.iter().map(|model| model.id.as_str()).collect();
+    assert_eq!(ids, vec!["llama3.2:3b", "gpt-4o"]);
+    assert!(models_command::check_model(&models, &config).is_ok());
+
+    let table = models_command::format_models(&models, &config);
+    assert_eq!(
+        table,
+        "  ID           OWNED BY  ALIASES\n  llama3.2:3b  ai-cli\n* gpt-4o       ai-cli    smart\n"
+    );
+
+    let missing = AppConfig {
+        model: "mistral".to_string(),
+        ..AppConfig::default()
+    };
+    let error = models_command::check_model(&models, &missing).unwrap_err();
+    assert!(error.to_string().contains("available: llama3.2:3b, gpt-4o"));
+}
+
+#[tokio::test]
+async fn test_doctor_connectivity_checks() {
+    let base_url = start_mock_server(MockServerArgs {
+        model_ids: vec!["mock-model".to_string()],
+        ..MockServerArgs::default()
+    })
+    .await;
+    let config = AppConfig {
+        base_url,
+        model: "mock-model".to_string(),
+        ..AppConfig::default()
+    };
+    let no_env = env_from(&[]);
+    let checks = doctor_command::connectivity_checks(&config, &Client::new(), &no_env).await;
+    let names: Vec<&str> = checks.iter().map(|check| check.name).collect();
+    assert_eq!(
+        names,
+        vec!["base url", "dns", "connect", "models", "streaming"]
+    );
+    assert!(checks
+        .iter()
+        .all(|check| check.status == doctor_command::Status::Ok));
+
+    // Behind a proxy the host is only resolved by the proxy, here the mock
+    // server itself.
+    let proxied = AppConfig {
+        base_url: "http://llm.invalid/v1".to_string(),
+        model: "mock-model".to_string(),
+        ..AppConfig::default()
+    };
+    let proxy_url = config.base_url.trim_end_matches("/v1").to_string();
+    let client = Client::builder()
+        .proxy(reqwest::Proxy::all(&proxy_url).unwrap())
+        .build()
+        .unwrap();
+    let proxy_env = env_from(&[("HTTP_PROXY", &proxy_url)]);
+    let checks = doctor_command::connectivity_checks(&proxied, &client, &proxy_env).await;
+    let names: Vec<&str> = checks.iter().map(|check| check.name).collect();
+    assert_eq!(
+        names,
+        vec!["base url", "proxy", "connect", "models", "streaming"]
+    );
+    assert!(checks
+        .iter()
+        .all(|check| check.status == doctor_command::Status::Ok));
+
+    let malformed_url = start_mock_server(MockServerArgs {
+        malformed_every: 1,
+        ..MockServerArgs::default()
+    })
+    .await;
+    let config = AppConfig {
+        base_url: malformed_url,
+        model: "mock-model".to_string(),
+        ..AppConfig::default()
+    };
+    let checks = doctor_command::connectivity_checks(&config, &Client::new(), &no_env).await;
+    let streaming = checks.last().unwrap();
+    assert_eq!(streaming.status, doctor_command::Status::Warn);
+    assert!(streaming.detail.contains("unparseable event"));
+
+    let unreachable = AppConfig {
+        base_url: "not a url".to_string(),
+        ..AppConfig::default()
+    };
+    let checks = doctor_command::connectivity_checks(&unreachable, &Client::new(), &no_env).await;
+    assert_eq!(checks.len(), 1);
+    assert_eq!(checks[0].status, doctor_command::Status::Fail);
+}
+
+#[test]
+fn test_doctor_sse_report_split_characters() {
+    // "é" arrives split between two network chunks.
+    let body = "data: {\"choices\":[{\"delta\":{\"content\":\"caf\u{e9}\"}}]}\n\ndata: [DONE]\n";
+    let split = body.find('\u{e9}').unwrap() + 1;
+    let mut report = doctor_command::SseReport::default();
+    report.chunk(&body.as_bytes()[..split]);
+    report.chunk(&body.as_bytes()[split..]);
+    report.end();
+    assert_eq!(report.content_chars, 4);
+    assert!(report.anomalies.is_empty(), "{:?}", report.anomalies);
+
+    // Bytes that are not UTF-8 at all are still reported.
+    let mut report = doctor_command::SseReport::default();
+    report.chunk(b"data: {\"choices\":[{\"delta\":{\"content\":\"\xff\"}}]}\ndata: [DONE]\n");
+    report.end();
+    assert!(report.anomalies[0].starts_with("invalid UTF-8 in line"));
+}
+
+#[test]
+fn test_doctor_proxy_for() {
+    let url = reqwest::Url::parse("https://api.openai.com/v1").unwrap();
+    let env = env_from(&[("https_proxy", "http://proxy:3128")]);
+    assert_eq!(
+        doctor_command::proxy_for(&url, &env).as_deref(),
+        Some("http://proxy:3128")
+    );
+    let env = env_from(&[("HTTP_PROXY", "http://proxy:3128")]);
+    assert_eq!(doctor_command::proxy_for(&url, &env), None);
+    let env = env_from(&[("ALL_PROXY", "socks5://proxy:1080")]);
+    assert_eq!(
+        doctor_command::proxy_for(&url, &env).as_deref(),
+        Some("socks5://proxy:1080")
+    );
+    for no_proxy in [
+        "openai.com",
+        ".openai.com",
+        "localhost, api.openai.com",
+        "*",
+    ] {
+        let env = env_from(&[("HTTPS_PROXY", "http://proxy:3128"), ("NO_PROXY", no_proxy)]);
+        assert_eq!(doctor_command::proxy_for(&url, &env), None, "{no_proxy}");
+    }
+    let env = env_from(&[("HTTPS_PROXY", "http://proxy:3128"), ("NO_PROXY", "ai.com")]);
+    assert!(doctor_command::proxy_for(&url, &env).is_some());
+}
+
+#[test]
+fn test_doctor_sse_report_anomalies() {
+    let mut report = doctor_command::SseReport::default();
+    report.line(r#"data: {"choices":[{"delta":{"content":"Hi"}}]}"#);
+    report.line(": keep-alive");
+    report.line("data: [DONE]");
+    report.finish("");
+    assert_eq!(report.events, 1);
+    assert_eq!(report.content_chars, 2);
+    assert!(report.anomalies.is_empty());
+
+    let mut report = doctor_command::SseReport::default();
+    report.line("<html>");
+    report.line("data: {oops");
+    report.finish("data: {\"choi");
+    assert_eq!(report.anomalies.len(), 5);
+    assert!(report.anomalies[0].starts_with("unexpected line '<html>'"));
+    assert!(report.anomalies[1].starts_with("unparseable event '{oops'"));
+    assert!(report.anomalies[2].starts_with("unterminated last line"));
+    assert_eq!(report.anomalies[3], "stream ended without data: [DONE]");
+    assert_eq!(report.anomalies[4], "no content was streamed");
+}
@@ -3,19 +3,465 @@ mod integration_tests {
     // Integration tests can't directly import from the main binary
     // We'll test the version functionality by running the binary directly
 
-    #[tokio::test]
-    async fn test_integration_with_mock_server() {
-        // This test is disabled for now due to complexity with mocking
-        // and the need to properly handle the main function
-
-        // TODO: Implement proper integration test that:
-        // 1. Sets up a mock server
-        // 2. Creates test input
-        // 3. Runs the main function with proper arguments
-        // 4. Verifies the output
-
-        // For now, we'll just test that the version flag works
-        // This is a simpler integration test
+    #[test]
+    fn test_integration_with_mock_server() {
+        // Run chunked processing end to end against the built-in mock server
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let (mut server, base_url) =
+            start_mock_server(&["--response", "A", "--response", "B", "--response", "C"]);
+
+        let config_dir = tempfile::tempdir().unwrap();
+        let config_path = config_dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "model = \"mock-model\"\nbase_url = \"{base_url}\"\ntimeout_secs = 10\nchunk_size_chars = 20\nchunk_overlap_chars = 0\naggregate_chunks = false\ncache_enabled = false\n"
+            ),
+        )
+        .unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args([
+                "-i",
+                "chunked",
+                "-p",
+                "summarize",
+                "--no-progress",
+                "--config",
+            ])
+            .arg(&config_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to run ai-cli");
+        // 49 characters split into chunks of 20, 20 and 9 characters
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all("x".repeat(49).as_bytes())
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let _ = server.kill();
+        let _ = server.wait();
+
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        // Chunk outputs must appear in chunk order
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ABC");
+    }
+
+    // Start the built-in mock server and return it with its base URL
+    fn start_mock_server(args: &[&str]) -> (std::process::Child, String) {
+        use std::io::{BufRead, BufReader};
+        use std::process::{Command, Stdio};
+
+        let mut server = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["mock-server", "--port", "0"])
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Failed to start mock server");
+        let mut banner = String::new();
+        BufReader::new(server.stdout.take().unwrap())
+            .read_line(&mut banner)
+            .unwrap();
+        let base_url = banner
+            .trim()
+            .rsplit(' ')
+            .next()
+            .expect("mock server banner should end with its URL")
+            .to_string();
+        (server, base_url)
+    }
+
+    #[test]
+    fn test_parallel_chunks_keep_chunk_order() {
+        // Independent chunks run concurrently but print in input order
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let (mut server, base_url) = start_mock_server(&["--latency-ms", "500"]);
+
+        // The mock echoes the prompt, which is just the chunk text
+        let config_dir = tempfile::tempdir().unwrap();
+        let template_path = config_dir.path().join("chunk_prompt.txt");
+        std::fs::write(&template_path, "{{chunk_text}}|").unwrap();
+        let config_path = config_dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "model = \"mock-model\"\nbase_url = \"{base_url}\"\ntimeout_secs = 10\nchunk_size_chars = 5\nchunk_overlap_chars = 0\nparallel_chunks = 3\naggregate_chunks = false\ncache_enabled = false\nchunk_prompt_file = \"{}\"\n",
+                template_path.display()
+            ),
+        )
+        .unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["-i", "chunked", "-p", "echo", "--no-progress", "--config"])
+            .arg(&config_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to run ai-cli");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"aaaaabbbbbcccccdddddeeeeefffff")
+            .unwrap();
+        let output = child.wait_with_output().unwrap();
+        let stats = mock_stats(&base_url);
+        let _ = server.kill();
+        let _ = server.wait();
+
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "aaaaa|bbbbb|ccccc|ddddd|eeeee|fffff|"
+        );
+        // Three of the six requests were answered at once
+        assert_eq!(stats["requests"], 6);
+        assert_eq!(stats["max_concurrent_requests"], 3);
+    }
+
+    // Request counters of a running mock server
+    fn mock_stats(base_url: &str) -> serde_json::Value {
+        use std::io::{Read, Write};
+
+        let address = base_url
+            .trim_start_matches("http://")
+            .trim_end_matches("/v1");
+        let mut stream = std::net::TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "GET /v1/mock/stats HTTP/1.1\r\nHost: {address}\r\nConnection: close\r\n\r\n"
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (_, body) = response
+            .split_once("\r\n\r\n")
+            .expect("mock server response should have a body");
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        // A run stopped by max_chunks continues where it stopped
+        use std::io::Write;
+        use std::process::{Command, Output, Stdio};
+
+        let (mut server, base_url) = start_mock_server(&[]);
+        let dir = tempfile::tempdir().unwrap();
+        let template_path = dir.path().join("chunk_prompt.txt");
+        std::fs::write(&template_path, "{{chunk_text}}|").unwrap();
+        let input_path = dir.path().join("input.txt");
+        std::fs::write(&input_path, "aaaaabbbbbcccccddddd").unwrap();
+        let config_path = dir.path().join("config.toml");
+        let write_config = |max_chunks: usize| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "model = \"mock-model\"\nbase_url = \"{base_url}\"\ntimeout_secs = 10\nchunk_size_chars = 5\nchunk_overlap_chars = 0\nmax_chunks = {max_chunks}\naggregate_chunks = false\ncache_enabled = false\nchunk_prompt_file = \"{}\"\n",
+                    template_path.display()
+                ),
+            )
+            .unwrap();
+        };
+        let run = |args: &[&str], stdin: &str| -> Output {
+            let mut child = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+                .args(["-i", "chunked", "-p", "echo", "--no-progress", "--config"])
+                .arg(&config_path)
+                .args(args)
+                .env("XDG_CACHE_HOME", dir.path().join("cache"))
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .expect("Failed to run ai-cli");
+            child
+                .stdin
+                .take()
+                .unwrap()
+                .write_all(stdin.as_bytes())
+                .unwrap();
+            child.wait_with_output().unwrap()
+        };
+        let checkpoint_of = |output: &Output| -> String {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            stderr
+                .split("--resume ")
+                .nth(1)
+                .and_then(|rest| rest.split_whitespace().next())
+                .unwrap_or_else(|| panic!("no checkpoint in: {stderr}"))
+                .to_string()
+        };
+        let stdout = |output: &Output| String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        write_config(2);
+        let stopped = run(&["-f", input_path.to_str().unwrap()], "");
+        assert!(!stopped.status.success());
+        assert_eq!(stdout(&stopped), "aaaaa|bbbbb|");
+        let checkpoint = checkpoint_of(&stopped);
+
+        write_config(0);
+        let resumed = run(
+            &["-f", input_path.to_str().unwrap(), "--resume", &checkpoint],
+            "",
+        );
+        assert!(
+            resumed.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&resumed.stderr)
+        );
+        assert_eq!(stdout(&resumed), "ccccc|ddddd|");
+        // A finished job removes its checkpoint
+        assert!(!std::path::Path::new(&checkpoint).exists());
+
+        // Piped input is skipped up to where the job stopped, if it matches
+        write_config(2);
+        let stopped = run(&[], "eeeeefffffggggg");
+        assert_eq!(stdout(&stopped), "eeeee|fffff|");
+        let checkpoint = checkpoint_of(&stopped);
+        write_config(0);
+        let mismatch = run(&["--resume", &checkpoint], "xxxxxfffffggggg");
+        assert!(!mismatch.status.success());
+        assert!(String::from_utf8_lossy(&mismatch.stderr).contains("stdin differs"));
+        let resumed = run(&["--resume", &checkpoint], "eeeeefffffggggg");
+        assert_eq!(stdout(&resumed), "ggggg|");
+
+        let _ = server.kill();
+        let _ = server.wait();
+    }
+
+    #[test]
+    fn test_summarize_long_chunk_outputs() {
+        // Answers over rolling_summary_chars are cut, or summarized first
+        use std::process::Command;
+
+        let (mut server, base_url) = start_mock_server(&[]);
+        let dir = tempfile::tempdir().unwrap();
+        let template_path = dir.path().join("chunk_prompt.txt");
+        std::fs::write(&template_path, "{{rolling_summary}}/{{chunk_text}}|").unwrap();
+        let input_path = dir.path().join("input.txt");
+        std::fs::write(&input_path, "aaaaabbbbbccccc").unwrap();
+        let config_path = dir.path().join("config.toml");
+        let run = |summarize: bool| -> String {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "model = \"mock-model\"\nbase_url = \"{base_url}\"\ntimeout_secs = 10\nchunk_size_chars = 5\nchunk_overlap_chars = 0\nrolling_summary_chars = 9\nsummarize_chunk_outputs = {summarize}\naggregate_chunks = false\ncache_enabled = false\nchunk_prompt_file = \"{}\"\n",
+                    template_path.display()
+                ),
+            )
+            .unwrap();
+            let output = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+                .args(["-i", "chunked", "-p", "echo", "--no-progress", "--config"])
+                .arg(&config_path)
+                .args(["-f", input_path.to_str().unwrap()])
+                .env("XDG_CACHE_HOME", dir.path().join("cache"))
+                .output()
+                .expect("Failed to run ai-cli");
+            assert!(
+                output.status.success(),
+                "stderr: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+
+        // The second answer, "/aaaaa|/bbbbb|", is longer than 9 chars
+        assert_eq!(run(false), "/aaaaa|/aaaaa|/bbbbb|/aaaaa|/b/ccccc|");
+        // The mock echoes the summary prompt, which starts with "Summarize"
+        assert_eq!(run(true), "/aaaaa|/aaaaa|/bbbbb|Summarize/ccccc|");
+
+        let _ = server.kill();
+        let _ = server.wait();
+    }
+
+    #[test]
+    fn test_per_file_outputs() {
+        // Each file gets its own answer, label and output file
+        use std::process::Command;
+
+        // The five file requests come first, so the combined answer's first
+        // attempt fails and has to be retried
+        let (mut server, base_url) =
+            start_mock_server(&["--latency-ms", "200", "--fail-every", "6"]);
+        let dir = tempfile::tempdir().unwrap();
+        let template_path = dir.path().join("chunk_prompt.txt");
+        std::fs::write(&template_path, "{{chunk_text}}|").unwrap();
+        let small_path = dir.path().join("a.txt");
+        std::fs::write(&small_path, "alpha").unwrap();
+        // Over auto_chunk_threshold_chars, so this file is chunked
+        let large_path = dir.path().join("sub").join("b.txt");
+        std::fs::create_dir_all(large_path.parent().unwrap()).unwrap();
+        std::fs::write(&large_path, "bravocharlie").unwrap();
+        let config_path = dir.path().join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "model = \"mock-model\"\nbase_url = \"{base_url}\"\ntimeout_secs = 10\nchunk_size_chars = 5\nchunk_overlap_chars = 0\nauto_chunk_threshold_chars = 8\naggregate_chunks = false\nparallel_files = 2\naggregate_files = true\ncache_enabled = false\nchunk_prompt_file = \"{}\"\n",
+                template_path.display()
+            ),
+        )
+        .unwrap();
+        let output_dir = dir.path().join("out");
+
+        let output = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["--per-file", "-p", "echo", "--no-progress", "--config"])
+            .arg(&config_path)
+            .arg("-f")
+            .arg(&small_path)
+            .arg("-f")
+            .arg(&large_path)
+            .arg("-f")
+            .arg(&small_path)
+            .arg("--output-dir")
+            .arg(&output_dir)
+            .env("XDG_CACHE_HOME", dir.path().join("cache"))
+            .output()
+            .expect("Failed to run ai-cli");
+        let _ = server.kill();
+        let _ = server.wait();
+
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let (per_file, combined) = stdout
+            .split_once("==> All files <==")
+            .unwrap_or_else(|| panic!("no combined answer in: {stdout}"));
+        let small = small_path.display();
+        let large = large_path.display();
+        // The mock echoes each prompt; small files are sent with the prompt
+        // and their name
+        assert_eq!(
+            per_file.trim(),
+            format!(
+                "==> {small} <==\nPrompt: echo\nFile: {small}\nalpha\n\n\n==> {large} <==\nbravo|charl|ie|\n\n==> {small} <==\nPrompt: echo\nFile: {small}\nalpha"
+            )
+        );
+        assert!(combined.contains(&format!("File: {large}\nbravo|charl|ie|")));
+
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("a.txt.out")).unwrap(),
+            format!("Prompt: echo\nFile: {small}\nalpha\n")
+        );
+        assert_eq!(
+            std::fs::read_to_string(output_dir.join("sub").join("b.txt.out")).unwrap(),
+            "bravo|charl|ie|"
+        );
+    }
+
+    #[test]
+    fn test_replay_single_request() {
+        // Serve a recorded exchange so the full request path runs offline
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+
+        let home = tempfile::tempdir().unwrap();
+        let replay_dir = tempfile::tempdir().unwrap();
+        let exchange = serde_json::json!({
+            "url": "http://localhost:11434/v1/chat/completions",
+            "request": {
+                "model": "test-model",
+                "messages": [{"role": "user", "content": "Prompt: say hi\nhello\n"}],
+                "stream": true
+            },
+            "status": 200,
+            "chunks": [
+                {"delay_ms": 0, "data": "data: {\"choices\":[{\"delta\":{\"content\":\"Hi \"}}]}\n\n"},
+                {"delay_ms": 10, "data": "data: {\"choices\":[{\"delta\":{\"content\":\"there\"}}]}\n\ndata: [DONE]\n\n"}
+            ]
+        });
+        std::fs::write(
+            replay_dir.path().join("0001.json"),
+            serde_json::to_string_pretty(&exchange).unwrap(),
+        )
+        .unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args([
+                "--no-config",
+                "-m",
+                "test-model",
+                "-i",
+                "off",
+                "-p",
+                "say hi",
+            ])
+            .arg("--replay")
+            .arg(replay_dir.path())
+            .env("HOME", home.path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Failed to run ai-cli");
+        child.stdin.take().unwrap().write_all(b"hello\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "Hi there");
+    }
+
+    #[test]
+    fn test_config_init_writes_only_when_asked() {
+        use std::process::Command;
+
+        let config_home = tempfile::tempdir().unwrap();
+        let config_path = config_home.path().join("ai-cli").join("config.toml");
+
+        // A normal run never creates the config file
+        let output = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["-i", "off", "--replay"])
+            .arg(config_home.path())
+            .env("XDG_CONFIG_HOME", config_home.path())
+            .stdin(std::process::Stdio::null())
+            .output()
+            .expect("Failed to run ai-cli");
+        assert!(!output.status.success());
+        assert!(!config_path.exists());
+
+        let output = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["config", "init"])
+            .env("XDG_CONFIG_HOME", config_home.path())
+            .output()
+            .expect("Failed to run ai-cli");
+        assert!(
+            output.status.success(),
+            "stderr: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(config_path.exists());
+
+        // Running init again refuses to overwrite the file
+        let output = Command::new(env!("CARGO_BIN_EXE_ai-cli"))
+            .args(["config", "init"])
+            .env("XDG_CONFIG_HOME", config_home.path())
+            .output()
+            .expect("Failed to run ai-cli");
+        assert!(!output.status.success());
     }
 
     #[test]